@@ -0,0 +1,98 @@
+//! Interpreter throughput on integer arithmetic, the ops that move the most values through the
+//! stack. Run with `cargo bench -p paravita`.
+
+#![feature(test)]
+
+extern crate test;
+
+use std::net::Ipv6Addr;
+
+use paravita::vm::{Operation, PrimOpKind, Process};
+use test::Bencher;
+
+const ROUNDS: usize = 1000;
+
+/// Builds `ROUNDS` repetitions of `body`, each leaving the stack as it found it.
+fn program(kind: PrimOpKind, body: &[Operation]) -> Vec<Operation> {
+    let mut code = vec![Operation::PushImm(kind, 3i64.into())];
+    for _ in 0..ROUNDS {
+        code.extend_from_slice(body);
+    }
+    code.push(Operation::Drop);
+    code
+}
+
+fn run(b: &mut Bencher, code: &[Operation]) {
+    let mut p = Process::new(Ipv6Addr::UNSPECIFIED).unwrap();
+    b.iter(|| {
+        p.run(code).unwrap();
+        test::black_box(p.depth())
+    });
+}
+
+fn binary(kind: PrimOpKind) -> Vec<Operation> {
+    program(
+        kind,
+        &[
+            Operation::Dup,
+            Operation::Add(kind),
+            Operation::Dup,
+            Operation::Mul(kind),
+            Operation::PushImm(kind, 7i64.into()),
+            Operation::Sub(kind),
+            Operation::PushImm(kind, 5i64.into()),
+            Operation::Div(kind),
+            Operation::Drop,
+        ],
+    )
+}
+
+fn immediate(kind: PrimOpKind) -> Vec<Operation> {
+    program(
+        kind,
+        &[
+            Operation::AddImm(kind, 1i64.into()),
+            Operation::MulImm(kind, 3i64.into()),
+            Operation::SubImm(kind, 2i64.into()),
+            Operation::DivImm(kind, 7i64.into()),
+            Operation::Drop,
+        ],
+    )
+}
+
+#[bench]
+fn binary_i32(b: &mut Bencher) {
+    run(b, &binary(PrimOpKind::I32));
+}
+
+#[bench]
+fn binary_u64(b: &mut Bencher) {
+    run(b, &binary(PrimOpKind::U64));
+}
+
+#[bench]
+fn immediate_i32(b: &mut Bencher) {
+    run(b, &immediate(PrimOpKind::I32));
+}
+
+#[bench]
+fn immediate_i64(b: &mut Bencher) {
+    run(b, &immediate(PrimOpKind::I64));
+}
+
+#[bench]
+fn stack_shuffle(b: &mut Bencher) {
+    run(
+        b,
+        &program(
+            PrimOpKind::U8,
+            &[
+                Operation::Dup,
+                Operation::Dup,
+                Operation::Swap,
+                Operation::Drop,
+                Operation::Drop,
+            ],
+        ),
+    );
+}
@@ -0,0 +1,65 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+
+fn main() {
+    let manifest_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+
+    let list_path = manifest_dir.join("atoms.txt");
+    println!("cargo:rerun-if-changed={}", list_path.display());
+
+    let list = std::fs::read_to_string(&list_path).unwrap();
+    let atoms: Vec<&str> = list
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+
+    for (i, a) in atoms.iter().enumerate() {
+        let valid = a.chars().next().is_some_and(|c| c.is_ascii_lowercase())
+            && a.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid {
+            panic!("atoms.txt: `{a}` is not a valid well-known atom name (lowercase identifiers only)");
+        }
+        if atoms[..i].contains(a) {
+            panic!("atoms.txt: `{a}` is listed twice");
+        }
+    }
+
+    let mut out = String::new();
+
+    // The table itself. Index + 1 is the handle.
+    writeln!(out, "/// Names of the atoms pre-interned at build time, in handle order.").unwrap();
+    writeln!(out, "pub const STATIC_ATOMS: [&str; {}] = [", atoms.len()).unwrap();
+    for a in &atoms {
+        writeln!(out, "    {a:?},").unwrap();
+    }
+    writeln!(out, "];").unwrap();
+
+    // One constant per atom.
+    for (i, a) in atoms.iter().enumerate() {
+        writeln!(out, "/// The `{a}` atom.").unwrap();
+        writeln!(out, "pub const {}: Atom = Atom::from_static({});", a.to_uppercase(), i + 1).unwrap();
+    }
+
+    // Lock-free lookup for runtime interning.
+    writeln!(out, "pub(super) fn lookup(s: &str) -> Option<Atom> {{").unwrap();
+    writeln!(out, "    match s {{").unwrap();
+    for a in &atoms {
+        writeln!(out, "        {a:?} => Some({}),", a.to_uppercase()).unwrap();
+    }
+    writeln!(out, "        _ => None,").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    // And the macro. Unknown names fall back to runtime interning.
+    writeln!(out, "#[macro_export]").unwrap();
+    writeln!(out, "macro_rules! atom {{").unwrap();
+    for a in &atoms {
+        writeln!(out, "    ({a:?}) => {{ $crate::vm::statics::{} }};", a.to_uppercase()).unwrap();
+    }
+    writeln!(out, "    ($name:literal) => {{ $crate::vm::Atom::from($name) }};").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    std::fs::write(out_dir.join("static_atoms.rs"), out).unwrap();
+}
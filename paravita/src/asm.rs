@@ -0,0 +1,384 @@
+//! A text form of [Operation], for writing and reading code by hand:
+//!
+//! ```text
+//! push_imm i32 40   # ( -- 40 )
+//! add_imm i32 2; debug_out
+//! ```
+//!
+//! Each op is its name in snake case followed by its operands: kinds by name, ints in decimal
+//! and atoms bare. Ops go one per line or are separated by `;`, and `#` starts a comment.
+//! [Operation]'s Display writes the same form, so the two round trip, for atoms that are
+//! single words.
+
+use core::fmt::Display;
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    forth::{Diagnostic, Span},
+    vm::{Atom, Combine, Endian, IntOpImmediate, Operation, PrimOpKind, Reduce},
+};
+
+/// Assembles source into code, collecting a diagnostic for each op that's wrong.
+pub fn assemble(source: &str) -> Result<Vec<Operation>, Vec<Diagnostic>> {
+    let mut code = Vec::new();
+    let mut diags = Vec::new();
+    for line in source.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        for text in line.split(';') {
+            let words: Vec<_> = text
+                .split_whitespace()
+                .map(|w| {
+                    let start = w.as_ptr() as usize - source.as_ptr() as usize;
+                    (w, Span { start, end: start + w.len() })
+                })
+                .collect();
+            let Some(&(name, span)) = words.first() else {
+                continue;
+            };
+            let mut operands = Operands {
+                words: &words[1..],
+                span,
+            };
+            match op(name, &mut operands).and_then(|op| operands.end().map(|_| op)) {
+                Ok(op) => code.push(op),
+                Err(e) => diags.push(e),
+            }
+        }
+    }
+    if diags.is_empty() {
+        Ok(code)
+    } else {
+        Err(diags)
+    }
+}
+
+fn op(name: &str, o: &mut Operands) -> Result<Operation, Diagnostic> {
+    Ok(match name {
+        "trap" => Operation::Trap,
+        "add" => Operation::Add(o.kind()?),
+        "add_imm" => {
+            let k = o.kind()?;
+            Operation::AddImm(k, o.imm(k)?)
+        }
+        "sub" => Operation::Sub(o.kind()?),
+        "sub_imm" => {
+            let k = o.kind()?;
+            Operation::SubImm(k, o.imm(k)?)
+        }
+        "mul" => Operation::Mul(o.kind()?),
+        "mul_imm" => {
+            let k = o.kind()?;
+            Operation::MulImm(k, o.imm(k)?)
+        }
+        "div" => Operation::Div(o.kind()?),
+        "div_imm" => {
+            let k = o.kind()?;
+            Operation::DivImm(k, o.imm(k)?)
+        }
+        "push_imm" => {
+            let k = o.kind()?;
+            Operation::PushImm(k, o.imm(k)?)
+        }
+        "push_atom" => Operation::PushAtom(Atom::from(o.next("an atom")?.0)),
+        "make_object" => Operation::MakeObject(o.int()?),
+        "make_array" => Operation::MakeArray,
+        "index_array" => Operation::IndexArray,
+        "set_array" => Operation::SetArray,
+        "drop" => Operation::Drop,
+        "dup" => Operation::Dup,
+        "swap" => Operation::Swap,
+        "debug_out" => Operation::DebugOut,
+        "try" => Operation::Try(o.int()?),
+        "end_try" => Operation::EndTry(o.int()?),
+        "throw" => Operation::Throw,
+        "syscall" => Operation::Syscall(o.int()?),
+        "call" => Operation::Call(o.int()?),
+        "call_import" => Operation::CallImport(o.int()?),
+        "return" => Operation::Return,
+        "push_const" => Operation::PushConst(o.int()?),
+        "load_local" => Operation::LoadLocal(o.int()?),
+        "store_local" => Operation::StoreLocal(o.int()?),
+        "load_global" => Operation::LoadGlobal(o.int()?),
+        "store_global" => Operation::StoreGlobal(o.int()?),
+        "make_closure" => Operation::MakeClosure(o.int()?, o.int()?),
+        "call_indirect" => Operation::CallIndirect(o.int()?),
+        "tail_call" => Operation::TailCall(o.int()?),
+        "tail_call_indirect" => Operation::TailCallIndirect(o.int()?),
+        "over" => Operation::Over,
+        "rot" => Operation::Rot,
+        "minus_rot" => Operation::MinusRot,
+        "nip" => Operation::Nip,
+        "tuck" => Operation::Tuck,
+        "pick" => Operation::Pick(o.int()?),
+        "roll" => Operation::Roll(o.int()?),
+        "two_dup" => Operation::TwoDup,
+        "two_drop" => Operation::TwoDrop,
+        "two_swap" => Operation::TwoSwap,
+        "depth" => Operation::Depth,
+        "jump" => Operation::Jump(o.int()?),
+        "jump_if_zero" => Operation::JumpIfZero(o.int()?),
+        "compare" => Operation::Compare,
+        "hash" => Operation::Hash,
+        "make_dict" => Operation::MakeDict,
+        "map_keys" => Operation::MapKeys,
+        "map_values" => Operation::MapValues,
+        "map_entries" => Operation::MapEntries,
+        "map_merge" => Operation::MapMerge,
+        "map_filter" => Operation::MapFilter,
+        "make_binary" => Operation::MakeBinary,
+        "length" => Operation::Length,
+        "binary_slice" => Operation::BinarySlice,
+        "binary_concat" => Operation::BinaryConcat,
+        "binary_read" => Operation::BinaryRead(o.kind()?, o.endian()?),
+        "binary_write" => Operation::BinaryWrite(o.kind()?, o.endian()?),
+        "make_packed" => Operation::MakePacked(o.kind()?),
+        "packed_fill" => Operation::PackedFill,
+        "packed_copy" => Operation::PackedCopy,
+        "packed_combine" => Operation::PackedCombine(o.named("a combination", Combine::from_name)?),
+        "packed_reduce" => Operation::PackedReduce(o.named("a reduction", Reduce::from_name)?),
+        _ => return Err(Diagnostic::new(o.span, format!("unknown op `{name}`"))),
+    })
+}
+
+/// The operands of one op.
+struct Operands<'w, 'a> {
+    words: &'w [(&'a str, Span)],
+    /// The span of the op's name, for missing operands.
+    span: Span,
+}
+
+impl<'a> Operands<'_, 'a> {
+    fn next(&mut self, what: &str) -> Result<(&'a str, Span), Diagnostic> {
+        let Some((&first, rest)) = self.words.split_first() else {
+            return Err(Diagnostic::new(self.span, format!("expected {what}")));
+        };
+        self.words = rest;
+        Ok(first)
+    }
+
+    fn kind(&mut self) -> Result<PrimOpKind, Diagnostic> {
+        let (w, span) = self.next("a kind")?;
+        PrimOpKind::from_name(w).ok_or_else(|| Diagnostic::new(span, format!("`{w}` isn't a kind")))
+    }
+
+    fn named<T>(&mut self, what: &str, f: fn(&str) -> Option<T>) -> Result<T, Diagnostic> {
+        let (w, span) = self.next(what)?;
+        f(w).ok_or_else(|| Diagnostic::new(span, format!("`{w}` isn't {what}")))
+    }
+
+    fn endian(&mut self) -> Result<Endian, Diagnostic> {
+        let (w, span) = self.next("a byte order")?;
+        Endian::from_name(w)
+            .ok_or_else(|| Diagnostic::new(span, format!("`{w}` isn't a byte order, le or be")))
+    }
+
+    fn imm(&mut self, kind: PrimOpKind) -> Result<IntOpImmediate, Diagnostic> {
+        let (w, span) = self.next("an int")?;
+        let (min, max) = kind.bounds();
+        match w.parse::<i128>() {
+            Ok(v) if (min..=max).contains(&v) => Ok(IntOpImmediate::from(v as u64)),
+            Ok(_) => Err(Diagnostic::new(span, format!("`{w}` doesn't fit in {}", kind.name()))),
+            Err(_) => Err(Diagnostic::new(span, format!("`{w}` isn't an int"))),
+        }
+    }
+
+    fn int<T: TryFrom<u64>>(&mut self) -> Result<T, Diagnostic> {
+        let (w, span) = self.next("an int")?;
+        w.parse::<u64>()
+            .ok()
+            .and_then(|v| T::try_from(v).ok())
+            .ok_or_else(|| Diagnostic::new(span, format!("`{w}` is out of range")))
+    }
+
+    fn end(&self) -> Result<(), Diagnostic> {
+        match self.words.first() {
+            Some(&(w, span)) => Err(Diagnostic::new(span, format!("unexpected `{w}`"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Operation {
+    /// The op's name in the text form, see [assemble].
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Operation::Trap => "trap",
+            Operation::Add(_) => "add",
+            Operation::AddImm(_, _) => "add_imm",
+            Operation::Sub(_) => "sub",
+            Operation::SubImm(_, _) => "sub_imm",
+            Operation::Mul(_) => "mul",
+            Operation::MulImm(_, _) => "mul_imm",
+            Operation::Div(_) => "div",
+            Operation::DivImm(_, _) => "div_imm",
+            Operation::PushImm(_, _) => "push_imm",
+            Operation::PushAtom(_) => "push_atom",
+            Operation::MakeObject(_) => "make_object",
+            Operation::MakeArray => "make_array",
+            Operation::IndexArray => "index_array",
+            Operation::SetArray => "set_array",
+            Operation::Drop => "drop",
+            Operation::Dup => "dup",
+            Operation::Swap => "swap",
+            Operation::DebugOut => "debug_out",
+            Operation::Try(_) => "try",
+            Operation::EndTry(_) => "end_try",
+            Operation::Throw => "throw",
+            Operation::Syscall(_) => "syscall",
+            Operation::Call(_) => "call",
+            Operation::CallImport(_) => "call_import",
+            Operation::Return => "return",
+            Operation::PushConst(_) => "push_const",
+            Operation::LoadLocal(_) => "load_local",
+            Operation::StoreLocal(_) => "store_local",
+            Operation::LoadGlobal(_) => "load_global",
+            Operation::StoreGlobal(_) => "store_global",
+            Operation::MakeClosure(_, _) => "make_closure",
+            Operation::CallIndirect(_) => "call_indirect",
+            Operation::TailCall(_) => "tail_call",
+            Operation::TailCallIndirect(_) => "tail_call_indirect",
+            Operation::Over => "over",
+            Operation::Rot => "rot",
+            Operation::MinusRot => "minus_rot",
+            Operation::Nip => "nip",
+            Operation::Tuck => "tuck",
+            Operation::Pick(_) => "pick",
+            Operation::Roll(_) => "roll",
+            Operation::TwoDup => "two_dup",
+            Operation::TwoDrop => "two_drop",
+            Operation::TwoSwap => "two_swap",
+            Operation::Depth => "depth",
+            Operation::Jump(_) => "jump",
+            Operation::JumpIfZero(_) => "jump_if_zero",
+            Operation::Compare => "compare",
+            Operation::Hash => "hash",
+            Operation::MakeDict => "make_dict",
+            Operation::MapKeys => "map_keys",
+            Operation::MapValues => "map_values",
+            Operation::MapEntries => "map_entries",
+            Operation::MapMerge => "map_merge",
+            Operation::MapFilter => "map_filter",
+            Operation::MakeBinary => "make_binary",
+            Operation::Length => "length",
+            Operation::BinarySlice => "binary_slice",
+            Operation::BinaryConcat => "binary_concat",
+            Operation::BinaryRead(_, _) => "binary_read",
+            Operation::BinaryWrite(_, _) => "binary_write",
+            Operation::MakePacked(_) => "make_packed",
+            Operation::PackedFill => "packed_fill",
+            Operation::PackedCopy => "packed_copy",
+            Operation::PackedCombine(_) => "packed_combine",
+            Operation::PackedReduce(_) => "packed_reduce",
+            Operation::__Final => "__final",
+        }
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.mnemonic())?;
+        match *self {
+            Operation::Add(k)
+            | Operation::Sub(k)
+            | Operation::Mul(k)
+            | Operation::Div(k)
+            | Operation::MakePacked(k) => write!(f, " {}", k.name()),
+            Operation::PackedCombine(c) => write!(f, " {}", c.name()),
+            Operation::PackedReduce(r) => write!(f, " {}", r.name()),
+            Operation::AddImm(k, imm)
+            | Operation::SubImm(k, imm)
+            | Operation::MulImm(k, imm)
+            | Operation::DivImm(k, imm)
+            | Operation::PushImm(k, imm) => write!(f, " {} {}", k.name(), imm.to_i128(k)),
+            Operation::PushAtom(a) => write!(f, " {}", <&str>::from(a)),
+            Operation::MakeObject(n)
+            | Operation::Try(n)
+            | Operation::EndTry(n)
+            | Operation::Call(n)
+            | Operation::CallImport(n)
+            | Operation::PushConst(n)
+            | Operation::TailCall(n)
+            | Operation::Jump(n)
+            | Operation::JumpIfZero(n) => write!(f, " {n}"),
+            Operation::Syscall(n)
+            | Operation::LoadLocal(n)
+            | Operation::StoreLocal(n)
+            | Operation::LoadGlobal(n)
+            | Operation::StoreGlobal(n)
+            | Operation::CallIndirect(n)
+            | Operation::TailCallIndirect(n)
+            | Operation::Pick(n)
+            | Operation::Roll(n) => write!(f, " {n}"),
+            Operation::MakeClosure(i, n) => write!(f, " {i} {n}"),
+            Operation::BinaryRead(k, e) | Operation::BinaryWrite(k, e) => {
+                write!(f, " {} {}", k.name(), e.name())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes code in the text form, one op per line, numbered by offset.
+pub fn disassemble(code: &[Operation]) -> String {
+    code.iter()
+        .enumerate()
+        .map(|(i, op)| format!("{i:>4}  {op}\n"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec::Vec};
+
+    use super::assemble;
+    use crate::vm::{Operation, PrimOpKind};
+
+    #[test]
+    pub fn round_trip() {
+        let source = "trap; add i16; add_imm i16 -2; sub i16; sub_imm i16 -2; mul i16
+            mul_imm i16 -2; div i16; div_imm i16 -2; push_imm i16 -2; push_atom ok
+            make_object 3; make_array; index_array; set_array; drop; dup; swap; debug_out
+            try 1; end_try 2; throw; syscall 9; call 0; call_import 0; return; push_const 0
+            load_local 0; store_local 0; load_global 0; store_global 0; make_closure 0 1
+            call_indirect 2; tail_call 0; tail_call_indirect 3; over; rot; minus_rot; nip
+            tuck; pick 4; roll 5; two_dup; two_drop; two_swap; depth # and a comment
+            jump 3; jump_if_zero 4; compare; hash
+            make_dict; map_keys; map_values; map_entries; map_merge; map_filter
+            make_binary; length; binary_slice; binary_concat; binary_read u32 be
+            binary_write i16 le; make_packed u32; packed_fill; packed_copy
+            packed_combine mul; packed_reduce max";
+        let code = assemble(source).unwrap();
+        // One of each, in opcode order.
+        assert_eq!(code.len(), Operation::__Final.discriminant() as usize);
+        for (i, op) in code.iter().enumerate() {
+            assert_eq!(op.discriminant() as usize, i);
+            assert_eq!(assemble(&op.to_string()).unwrap(), [*op]);
+        }
+        assert_eq!(
+            code[2],
+            Operation::AddImm(PrimOpKind::I16, (-2i16).into())
+        );
+    }
+
+    #[test]
+    pub fn diagnostics() {
+        let source = "push_imm u8 256; jump\nfrob 1; add i32 x; pick -1; add q32";
+        let found: Vec<_> = assemble(source)
+            .unwrap_err()
+            .iter()
+            .map(|d| (d.span.line_col(source), d.message.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ((1, 13), "`256` doesn't fit in u8".to_string()),
+                ((1, 18), "expected an int".to_string()),
+                ((2, 1), "unknown op `frob`".to_string()),
+                ((2, 17), "unexpected `x`".to_string()),
+                ((2, 25), "`-1` is out of range".to_string()),
+                ((2, 33), "`q32` isn't a kind".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! An interactive session with a live process. Type Forth, or assembler after `/asm`, a line
+//! at a time and see the stack after each. `/help` lists the commands.
+//!
+//! Forth definitions are kept for later lines: every line is compiled along with them into a
+//! module named `repl`, loaded over the last one, and its code outside of definitions runs.
+
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    net::Ipv6Addr,
+    path::Path,
+};
+
+use paravita::{
+    asm,
+    forth::{self, Diagnostic},
+    vm::{atoms_count, Atom, AtomStore, Vm, VmFault, MODULE_MAGIC},
+};
+
+const HELP: &str = "\
+/forth          read Forth (the default)
+/asm            read assembler, see paravita::asm
+/words          list the Forth definitions so far
+/atoms [prefix] count the atoms, and list those starting with prefix
+/dump [n]       show the value n down from the top of the stack in full, the top by default
+/reset          start over with a new process, keeping loaded modules
+/load <file>    load a module, either encoded or Forth source named after the file
+/quit           leave, as does end of input";
+
+enum Mode {
+    Forth,
+    Asm,
+}
+
+struct Repl {
+    vm: Vm,
+    pid: Ipv6Addr,
+    mode: Mode,
+    /// Forth definitions entered so far, by name.
+    defs: Vec<(String, String)>,
+}
+
+impl Repl {
+    fn new() -> Repl {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().expect("out of memory");
+        Repl {
+            vm,
+            pid,
+            mode: Mode::Forth,
+            defs: Vec::new(),
+        }
+    }
+
+    /// Handles a line, returning false to quit.
+    fn line(&mut self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let arg = words.next();
+        match command {
+            "/help" => println!("{HELP}"),
+            "/quit" => return false,
+            "/forth" => self.mode = Mode::Forth,
+            "/asm" => self.mode = Mode::Asm,
+            "/words" => {
+                for (_, text) in &self.defs {
+                    println!("{text}");
+                }
+            }
+            "/atoms" => self.atoms(arg),
+            "/dump" => self.dump(arg),
+            "/reset" => {
+                self.vm.kill(self.pid);
+                self.pid = self.vm.spawn().expect("out of memory");
+            }
+            "/load" => match arg {
+                Some(path) => self.load(path),
+                None => println!("usage: /load <file>"),
+            },
+            _ => {
+                match self.mode {
+                    Mode::Forth => self.forth(line),
+                    Mode::Asm => self.asm(line),
+                }
+                self.print_stack();
+            }
+        }
+        true
+    }
+
+    fn forth(&mut self, line: &str) {
+        let new = match forth::definitions(line) {
+            Ok(new) => new,
+            Err(diags) => return report(&diags, line),
+        };
+        // The line's definitions replace earlier ones of the same name. It goes first, so
+        // diagnostics point at the right line.
+        let kept: Vec<_> = self
+            .defs
+            .iter()
+            .filter(|(name, _)| !new.iter().any(|(n, _)| n == name))
+            .cloned()
+            .collect();
+        let mut source = String::from(line);
+        for (_, text) in &kept {
+            source.push('\n');
+            source.push_str(text);
+        }
+        let m = match forth::compile_module(Atom::from("repl"), &source) {
+            Ok(m) => m,
+            Err(diags) => return report(&diags, &source),
+        };
+        let has_main = m.find(Atom::from("main"), 0).is_some();
+        if let Err(e) = self.vm.load(m) {
+            return println!("error: {e}");
+        }
+        self.defs = kept;
+        self.defs
+            .extend(new.into_iter().map(|(n, t)| (n.to_string(), t.to_string())));
+        if has_main {
+            let run = self
+                .vm
+                .start(self.pid, Atom::from("repl"), Atom::from("main"), ())
+                .and_then(|_| self.vm.resume(self.pid, usize::MAX));
+            if let Err(fault) = run {
+                fail(&fault);
+            }
+        }
+    }
+
+    fn asm(&mut self, line: &str) {
+        match asm::assemble(line) {
+            Ok(code) => {
+                if let Err(fault) = self.vm.run(self.pid, &code) {
+                    fail(&fault);
+                }
+            }
+            Err(diags) => report(&diags, line),
+        }
+    }
+
+    fn atoms(&self, prefix: Option<&str>) {
+        println!("{} atoms", atoms_count());
+        if let Some(prefix) = prefix {
+            let table = AtomStore::snapshot();
+            for (i, name) in table.iter().enumerate() {
+                if name.starts_with(prefix) {
+                    println!("{i:>6}  {name}");
+                }
+            }
+        }
+    }
+
+    fn dump(&self, n: Option<&str>) {
+        let n = match n.map(str::parse::<usize>) {
+            None => 0,
+            Some(Ok(n)) => n,
+            Some(Err(_)) => return println!("usage: /dump [n]"),
+        };
+        let p = self.vm.process(self.pid).expect("the session's process");
+        match p.depth().checked_sub(n + 1).and_then(|i| p.stack().nth(i)) {
+            Some(v) => println!("{v:#}"),
+            None => println!("the stack holds {} values", p.depth()),
+        }
+    }
+
+    fn load(&mut self, path: &str) {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => return println!("{path}: {e}"),
+        };
+        let loaded = if bytes.starts_with(&MODULE_MAGIC) {
+            self.vm.load_bytes(&bytes)
+        } else {
+            let Ok(source) = String::from_utf8(bytes) else {
+                return println!("{path}: neither a module nor text");
+            };
+            let name = Path::new(path)
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("main");
+            match forth::compile_module(Atom::from(name), &source) {
+                Ok(m) => self.vm.load(m),
+                Err(diags) => return report(&diags, &source),
+            }
+        };
+        match loaded {
+            Ok(name) => println!("loaded {}", <&str>::from(name)),
+            Err(e) => println!("{path}: {e}"),
+        }
+    }
+
+    fn print_stack(&self) {
+        let p = self.vm.process(self.pid).expect("the session's process");
+        // Kept short, the whole of a value is a /dump away.
+        let values: Vec<_> = p
+            .stack()
+            .map(|v| v.pretty().depth(2).items(8).to_string())
+            .collect();
+        println!("<{}> {}", values.len(), values.join(" "));
+    }
+}
+
+fn report(diags: &[Diagnostic], source: &str) {
+    for d in diags {
+        println!("{}", d.render(source));
+    }
+}
+
+fn fail(fault: &VmFault) {
+    println!("error: {fault}");
+}
+
+fn main() {
+    let mut repl = Repl::new();
+    for path in env::args().skip(1) {
+        repl.load(&path);
+    }
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("pv> ");
+        io::stdout().flush().ok();
+        let Some(Ok(line)) = lines.next() else {
+            println!();
+            break;
+        };
+        if !repl.line(&line) {
+            break;
+        }
+    }
+}
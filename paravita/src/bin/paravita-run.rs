@@ -0,0 +1,140 @@
+//! Runs a module outside of the kernel: loads it, starts `module:main/0` (or the function
+//! given) in a new process and schedules processes until all of them exit. The exit status is
+//! 0 if the entry process exits normally, 1 if it faults and 2 if it can't be started.
+
+use std::{env, fs, net::Ipv6Addr, path::Path, process::ExitCode};
+
+use paravita::{
+    forth,
+    vm::{Atom, Event, Module, Scheduler, Vm, MODULE_MAGIC},
+};
+
+const USAGE: &str = "\
+usage: paravita-run [options] <module> [function]
+
+Runs function/0 of the module, main by default. The module is a file written by
+Module::to_bytes, or Forth source named after the file.
+
+options:
+  --budget <n>   operations a process runs per turn (default 4000)
+  --heap <n>     kill processes holding more than n bytes
+  --seed <n>     shuffle the turn order with this seed, to repeat an interleaving
+  --trace        print every operation as it runs";
+
+struct Options {
+    scheduler: Scheduler,
+    trace: bool,
+    path: String,
+    function: String,
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut scheduler = Scheduler::new();
+    let mut trace = false;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut number = |name: &str| -> Result<u64, String> {
+            let value = args.next().ok_or(format!("{name} needs a value"))?;
+            value.parse().map_err(|_| format!("{name}: `{value}` isn't a number"))
+        };
+        match arg.as_str() {
+            "--budget" => scheduler = scheduler.budget(number("--budget")? as usize),
+            "--heap" => scheduler = scheduler.heap_quota(number("--heap")? as usize),
+            "--seed" => scheduler = scheduler.seed(number("--seed")?),
+            "--trace" => trace = true,
+            "--help" | "-h" => return Err(String::new()),
+            a if a.starts_with("--") => return Err(format!("unknown option {a}")),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let path = positional.next().ok_or("no module given")?;
+    let function = positional.next().unwrap_or_else(|| "main".to_string());
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument {extra}"));
+    }
+    Ok(Options {
+        scheduler: scheduler.trace(trace),
+        trace,
+        path,
+        function,
+    })
+}
+
+fn read_module(path: &str) -> Result<Module, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    if bytes.starts_with(&MODULE_MAGIC) {
+        return Module::from_bytes(&bytes).map_err(|e| format!("{path}: {e}"));
+    }
+    let source = String::from_utf8(bytes).map_err(|_| format!("{path}: neither a module nor text"))?;
+    let name = Path::new(path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("main");
+    forth::compile_module(Atom::from(name), &source).map_err(|diags| {
+        diags
+            .iter()
+            .map(|d| format!("{path}:{}", d.render(&source)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
+}
+
+fn main() -> ExitCode {
+    let mut options = match parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("paravita-run: {e}");
+            }
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let module = match read_module(&options.path) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+    let start = vm.load(module).and_then(|name| Ok((name, vm.spawn()?)));
+    let (name, pid) = match start {
+        Ok(started) => started,
+        Err(e) => {
+            eprintln!("{}: {e}", options.path);
+            return ExitCode::from(2);
+        }
+    };
+    if let Err(fault) = vm.start(pid, name, Atom::from(options.function.as_str()), ()) {
+        eprintln!("{fault}");
+        return ExitCode::from(2);
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    options.scheduler.run(&mut vm, |event| match event {
+        Event::Step { pid, op, at } if options.trace => match at {
+            Some(at) => eprintln!(
+                "{pid} {}:{}/{} {:>4}  {op}",
+                <&str>::from(at.module),
+                <&str>::from(at.function),
+                at.arity,
+                at.pc
+            ),
+            None => eprintln!("{pid} {op}"),
+        },
+        Event::Step { .. } => {}
+        Event::Exit(exit) => {
+            match &exit.fault {
+                None => eprintln!("{} exited normally", exit.pid),
+                Some(fault) => eprintln!("{} exited: {fault}", exit.pid),
+            }
+            if exit.pid == pid && !exit.is_normal() {
+                status = ExitCode::FAILURE;
+            }
+        }
+    });
+    status
+}
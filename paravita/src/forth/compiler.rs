@@ -0,0 +1,672 @@
+use alloc::{collections::BTreeMap, format, vec::Vec};
+
+use crate::vm::{
+    Atom, Combine, Constant, Endian, Function, IntOpImmediate, Module, Operation, PrimOpKind, Reduce,
+};
+
+use super::{
+    lexer::{lex, Token, TokenKind},
+    Diagnostic, Span,
+};
+
+/// Compiles source into a module. Each definition becomes an exported function, its arity
+/// taken from its stack comment, and code outside of definitions becomes an exported `main/0`.
+/// Definitions can call each other in any order.
+pub fn compile_module(name: Atom, source: &str) -> Result<Module, Vec<Diagnostic>> {
+    let tokens = lex(source)?;
+    let mut diags = Vec::new();
+    let (defs, main) = split(&tokens, &mut diags);
+
+    let mut words = BTreeMap::new();
+    for (i, d) in defs.iter().enumerate() {
+        if words.insert(d.name, i as u32).is_some() {
+            diags.push(Diagnostic::new(d.span, format!("`{}` is already defined", d.name)));
+        }
+    }
+    if !main.is_empty() {
+        if let Some(d) = defs.iter().find(|d| d.name == "main" && d.arity == 0) {
+            diags.push(Diagnostic::new(
+                d.span,
+                "`main` with no inputs clashes with the code outside of definitions",
+            ));
+        }
+    }
+
+    let mut m = Module::new(name);
+    let mut functions = Vec::new();
+    for (i, d) in defs.iter().enumerate() {
+        let mut body = Body::new(Some(&mut m), &words, Some(i as u32), &mut diags);
+        body.compile(&d.body);
+        functions.push(Function {
+            name: Atom::from(d.name),
+            arity: d.arity,
+            locals: body.locals,
+            code: body.code,
+        });
+    }
+    if !main.is_empty() {
+        let mut body = Body::new(Some(&mut m), &words, None, &mut diags);
+        body.compile(&main);
+        functions.push(Function {
+            name: atom!("main"),
+            arity: 0,
+            locals: body.locals,
+            code: body.code,
+        });
+    }
+    if !diags.is_empty() {
+        return Err(diags);
+    }
+    for f in functions {
+        let i = m.function(f);
+        m.export(i);
+    }
+    Ok(m)
+}
+
+/// Compiles source into code to run on its own, with [Process::run](crate::vm::Process::run).
+/// Without a module to hold them there are no definitions, strings, imports or do loops.
+pub fn compile(source: &str) -> Result<Vec<Operation>, Vec<Diagnostic>> {
+    let tokens = lex(source)?;
+    let mut diags = Vec::new();
+    let (defs, main) = split(&tokens, &mut diags);
+    for d in &defs {
+        diags.push(Diagnostic::new(d.span, "definitions need a module, see compile_module"));
+    }
+    let words = BTreeMap::new();
+    let mut body = Body::new(None, &words, None, &mut diags);
+    body.compile(&main);
+    let code = body.code;
+    if diags.is_empty() {
+        Ok(code)
+    } else {
+        Err(diags)
+    }
+}
+
+/// The definitions in source, as their names and their text from `:` to `;`. An interactive
+/// session can keep them, to compile again along with later input.
+pub fn definitions(source: &str) -> Result<Vec<(&str, &str)>, Vec<Diagnostic>> {
+    let tokens = lex(source)?;
+    let mut diags = Vec::new();
+    let (defs, _) = split(&tokens, &mut diags);
+    if !diags.is_empty() {
+        return Err(diags);
+    }
+    Ok(defs
+        .iter()
+        .map(|d| (d.name, &source[d.text.start..d.text.end]))
+        .collect())
+}
+
+/// A `: name ( inputs -- outputs ) ... ;` definition.
+struct Definition<'t, 'a> {
+    name: &'a str,
+    /// The span of the name.
+    span: Span,
+    /// The span of the whole definition.
+    text: Span,
+    arity: u16,
+    body: Vec<&'t Token<'a>>,
+}
+
+/// Separates definitions from the code outside of them.
+fn split<'t, 'a>(
+    tokens: &'t [Token<'a>],
+    diags: &mut Vec<Diagnostic>,
+) -> (Vec<Definition<'t, 'a>>, Vec<&'t Token<'a>>) {
+    let mut defs = Vec::new();
+    let mut main = Vec::new();
+    let mut tokens = tokens.iter().peekable();
+    while let Some(t) = tokens.next() {
+        match t.kind {
+            TokenKind::Word(":") => {}
+            TokenKind::Word(";") => {
+                diags.push(Diagnostic::new(t.span, "`;` outside of a definition"));
+                continue;
+            }
+            _ => {
+                main.push(t);
+                continue;
+            }
+        }
+        let (name, span) = match tokens.next() {
+            Some(Token {
+                kind: TokenKind::Word(name),
+                span,
+            }) if !matches!(*name, ":" | ";") => (*name, *span),
+            _ => {
+                diags.push(Diagnostic::new(t.span, "expected a name after `:`"));
+                continue;
+            }
+        };
+        let mut arity = 0;
+        if let Some(Token {
+            kind: TokenKind::Comment(comment),
+            span,
+        }) = tokens.peek()
+        {
+            let inputs = comment.split("--").next().unwrap_or("");
+            match u16::try_from(inputs.split_whitespace().count()) {
+                Ok(n) => arity = n,
+                Err(_) => diags.push(Diagnostic::new(*span, "too many inputs")),
+            }
+            tokens.next();
+        }
+        let mut body = Vec::new();
+        let mut closed = false;
+        let mut end = span.end;
+        for t in tokens.by_ref() {
+            end = t.span.end;
+            match t.kind {
+                TokenKind::Word(";") => {
+                    closed = true;
+                    break;
+                }
+                TokenKind::Word(":") => {
+                    diags.push(Diagnostic::new(t.span, "`:` inside of a definition"))
+                }
+                _ => body.push(t),
+            }
+        }
+        if !closed {
+            diags.push(Diagnostic::new(span, format!("`{name}` is missing its `;`")));
+        }
+        defs.push(Definition {
+            name,
+            span,
+            text: Span { start: t.span.start, end },
+            arity,
+            body,
+        });
+    }
+    (defs, main)
+}
+
+/// An op taking the kind it works on.
+type KindOp = fn(PrimOpKind) -> Operation;
+
+/// An op reading or writing an int of some kind and byte order.
+type AccessOp = fn(PrimOpKind, Endian) -> Operation;
+
+/// An open control structure, with where its jump is or where it loops back to.
+enum Control {
+    If(usize, Span),
+    Else(usize, Span),
+    Begin(usize, Span),
+    Do(usize, Span),
+}
+
+/// Compiles the body of one function.
+struct Body<'m, 'a> {
+    /// Where constants and imports go, if compiling a module.
+    module: Option<&'m mut Module>,
+    words: &'m BTreeMap<&'a str, u32>,
+    /// The function being compiled, for `recurse`.
+    this: Option<u32>,
+    code: Vec<Operation>,
+    control: Vec<Control>,
+    /// How many do loops are open.
+    loops: u16,
+    locals: u16,
+    /// The last offset a jump might go to. A literal before it can't be folded into the next
+    /// op, since a jump would skip it.
+    label: usize,
+    diags: &'m mut Vec<Diagnostic>,
+}
+
+impl<'m, 'a> Body<'m, 'a> {
+    fn new(
+        module: Option<&'m mut Module>,
+        words: &'m BTreeMap<&'a str, u32>,
+        this: Option<u32>,
+        diags: &'m mut Vec<Diagnostic>,
+    ) -> Body<'m, 'a> {
+        Body {
+            module,
+            words,
+            this,
+            code: Vec::new(),
+            control: Vec::new(),
+            loops: 0,
+            locals: 0,
+            label: 0,
+            diags,
+        }
+    }
+
+    fn compile(&mut self, tokens: &[&Token<'a>]) {
+        for t in tokens {
+            match &t.kind {
+                TokenKind::Word(w) => self.word(w, t.span),
+                TokenKind::Str(s) => match self.module.as_mut() {
+                    Some(m) => {
+                        let i = m.constant(Constant::Str(s.clone()));
+                        self.code.push(Operation::PushConst(i));
+                    }
+                    None => self.error(t.span, "strings need a module, see compile_module"),
+                },
+                TokenKind::Comment(_) => {}
+            }
+        }
+        for c in core::mem::take(&mut self.control) {
+            let (word, span) = match c {
+                Control::If(_, span) => ("if", span),
+                Control::Else(_, span) => ("else", span),
+                Control::Begin(_, span) => ("begin", span),
+                Control::Do(_, span) => ("do", span),
+            };
+            self.error(span, format!("`{word}` is never closed"));
+        }
+    }
+
+    fn error(&mut self, span: Span, message: impl Into<alloc::string::String>) {
+        self.diags.push(Diagnostic::new(span, message));
+    }
+
+    fn emit(&mut self, op: Operation) {
+        self.code.push(op);
+    }
+
+    /// The offset of the next op, as a jump target.
+    fn here(&mut self) -> u32 {
+        self.label = self.code.len();
+        self.code.len() as u32
+    }
+
+    fn word(&mut self, w: &'a str, span: Span) {
+        if let Some(&i) = self.words.get(w) {
+            return self.emit(Operation::Call(i));
+        }
+        match number(w) {
+            Some(Ok((kind, imm))) => return self.emit(Operation::PushImm(kind, imm)),
+            Some(Err(e)) => return self.error(span, e),
+            None => {}
+        }
+        let op = match w {
+            "drop" => Operation::Drop,
+            "dup" => Operation::Dup,
+            "swap" => Operation::Swap,
+            "over" => Operation::Over,
+            "rot" => Operation::Rot,
+            "-rot" => Operation::MinusRot,
+            "nip" => Operation::Nip,
+            "tuck" => Operation::Tuck,
+            "2dup" => Operation::TwoDup,
+            "2drop" => Operation::TwoDrop,
+            "2swap" => Operation::TwoSwap,
+            "depth" => Operation::Depth,
+            "compare" => Operation::Compare,
+            "hash" => Operation::Hash,
+            "dict" => Operation::MakeDict,
+            "keys" => Operation::MapKeys,
+            "values" => Operation::MapValues,
+            "entries" => Operation::MapEntries,
+            "merge" => Operation::MapMerge,
+            "filter" => Operation::MapFilter,
+            "binary" => Operation::MakeBinary,
+            "length" => Operation::Length,
+            "slice" => Operation::BinarySlice,
+            "concat" => Operation::BinaryConcat,
+            "fill" => Operation::PackedFill,
+            "copy" => Operation::PackedCopy,
+            "v+" => Operation::PackedCombine(Combine::Add),
+            "v-" => Operation::PackedCombine(Combine::Sub),
+            "v*" => Operation::PackedCombine(Combine::Mul),
+            "vsum" => Operation::PackedReduce(Reduce::Sum),
+            "vmin" => Operation::PackedReduce(Reduce::Min),
+            "vmax" => Operation::PackedReduce(Reduce::Max),
+            "." => Operation::DebugOut,
+            "throw" => Operation::Throw,
+            "exit" => Operation::Return,
+            "array" => Operation::MakeArray,
+            "map" => Operation::MakeObject(0),
+            "@" => Operation::IndexArray,
+            "!" => Operation::SetArray,
+            "0=" => return self.zero_eq(),
+            "pick" => return self.with_literal(w, span, Operation::Pick),
+            "roll" => return self.with_literal(w, span, Operation::Roll),
+            "syscall" => return self.with_literal(w, span, Operation::Syscall),
+            "recurse" => match self.this {
+                Some(i) => Operation::Call(i),
+                None => return self.error(span, "`recurse` outside of a definition"),
+            },
+            "if" | "else" | "then" | "begin" | "until" | "again" | "do" | "loop" | "i" | "j" => {
+                return self.control(w, span)
+            }
+            _ => return self.other(w, span),
+        };
+        self.emit(op)
+    }
+
+    /// Arithmetic, typed access, packed arrays, atoms and imports.
+    fn other(&mut self, w: &'a str, span: Span) {
+        const ARITH: [(&str, KindOp); 5] = [
+            ("/mod", Operation::Div),
+            ("+", Operation::Add),
+            ("-", Operation::Sub),
+            ("*", Operation::Mul),
+            ("=", Operation::Sub),
+        ];
+        for (name, op) in ARITH {
+            if let Some(kind) = w.strip_prefix(name).and_then(kind) {
+                self.emit(op(kind));
+                if name == "=" {
+                    self.zero_eq();
+                }
+                return;
+            }
+        }
+        const ACCESS: [(&str, AccessOp); 2] =
+            [("@", Operation::BinaryRead), ("!", Operation::BinaryWrite)];
+        for (name, op) in ACCESS {
+            let Some(typed) = w.strip_prefix(name) else {
+                continue;
+            };
+            let order = typed
+                .len()
+                .checked_sub(2)
+                .filter(|&at| typed.is_char_boundary(at))
+                .and_then(|at| Some((&typed[..at], Endian::from_name(&typed[at..])?)));
+            let (k, endian) = match order {
+                Some((k, e)) => (k, Some(e)),
+                None => (typed, None),
+            };
+            let Some(kind) = PrimOpKind::from_name(k) else {
+                continue;
+            };
+            // Byte order only matters past one byte.
+            return match endian {
+                Some(e) => self.emit(op(kind, e)),
+                None if kind.size() == 1 => self.emit(op(kind, Endian::Little)),
+                None => self.error(span, format!("`{w}` needs a byte order, as in `{w}be`")),
+            };
+        }
+        if let Some(kind) = w.strip_suffix("array").and_then(PrimOpKind::from_name) {
+            return self.emit(Operation::MakePacked(kind));
+        }
+        if let Some(atom) = w.strip_prefix(':') {
+            if !atom.is_empty() {
+                return self.emit(Operation::PushAtom(Atom::from(atom)));
+            }
+        }
+        if let Some((module, rest)) = w.split_once(':') {
+            if let Some((function, arity)) = rest.rsplit_once('/') {
+                let Ok(arity) = arity.parse::<u16>() else {
+                    return self.error(span, format!("bad arity in `{w}`"));
+                };
+                let Some(m) = self.module.as_mut() else {
+                    return self.error(span, "calls to other modules need a module, see compile_module");
+                };
+                let i = m.import(Atom::from(module), Atom::from(function), arity);
+                return self.emit(Operation::CallImport(i));
+            }
+        }
+        self.error(span, format!("unknown word `{w}`"))
+    }
+
+    /// `0=` as ( n -- flag ), jumping over the push of 0 when n is zero.
+    fn zero_eq(&mut self) {
+        let at = self.code.len() as u32;
+        self.emit(Operation::JumpIfZero(at + 3));
+        self.emit(Operation::PushImm(PrimOpKind::I64, 0i64.into()));
+        self.emit(Operation::Jump(at + 4));
+        self.emit(Operation::PushImm(PrimOpKind::I64, 1i64.into()));
+        self.here();
+    }
+
+    /// Folds the literal just before into an op taking a u16 operand, as in `2 pick`.
+    fn with_literal(&mut self, w: &str, span: Span, op: fn(u16) -> Operation) {
+        let n = match self.code.last() {
+            Some(Operation::PushImm(kind, imm)) if self.code.len() > self.label => {
+                u16::try_from(imm.to_i128(*kind)).ok()
+            }
+            _ => return self.error(span, format!("`{w}` needs a literal before it")),
+        };
+        match n {
+            Some(n) => {
+                self.code.pop();
+                self.emit(op(n));
+            }
+            None => self.error(span, format!("`{w}` takes 0 to {}", u16::MAX)),
+        }
+    }
+
+    fn control(&mut self, w: &str, span: Span) {
+        match w {
+            "if" => {
+                self.control.push(Control::If(self.code.len(), span));
+                self.emit(Operation::JumpIfZero(0));
+            }
+            "else" => {
+                let Some(Control::If(at, span)) = self.control.pop_if(|c| matches!(c, Control::If(..)))
+                else {
+                    return self.error(span, "`else` without `if`");
+                };
+                let jump = self.code.len();
+                self.emit(Operation::Jump(0));
+                self.code[at] = Operation::JumpIfZero(self.here());
+                self.control.push(Control::Else(jump, span));
+            }
+            "then" => match self
+                .control
+                .pop_if(|c| matches!(c, Control::If(..) | Control::Else(..)))
+            {
+                Some(Control::If(at, _)) => self.code[at] = Operation::JumpIfZero(self.here()),
+                Some(Control::Else(at, _)) => self.code[at] = Operation::Jump(self.here()),
+                _ => self.error(span, "`then` without `if`"),
+            },
+            "begin" => {
+                let at = self.here() as usize;
+                self.control.push(Control::Begin(at, span));
+            }
+            "until" | "again" => {
+                let Some(Control::Begin(at, _)) =
+                    self.control.pop_if(|c| matches!(c, Control::Begin(..)))
+                else {
+                    return self.error(span, format!("`{w}` without `begin`"));
+                };
+                self.emit(match w {
+                    "until" => Operation::JumpIfZero(at as u32),
+                    _ => Operation::Jump(at as u32),
+                });
+            }
+            "do" => {
+                if self.module.is_none() {
+                    return self.error(span, "do loops need a module, see compile_module");
+                }
+                // ( limit start -- ), kept in a pair of locals per nesting level.
+                let index = self.loops * 2;
+                self.emit(Operation::StoreLocal(index));
+                self.emit(Operation::StoreLocal(index + 1));
+                self.loops += 1;
+                self.locals = self.locals.max(self.loops * 2);
+                let at = self.here() as usize;
+                self.control.push(Control::Do(at, span));
+            }
+            "loop" => {
+                let Some(Control::Do(at, _)) = self.control.pop_if(|c| matches!(c, Control::Do(..)))
+                else {
+                    return self.error(span, "`loop` without `do`");
+                };
+                self.loops -= 1;
+                let index = self.loops * 2;
+                let exit = self.code.len() as u32 + 8;
+                self.emit(Operation::LoadLocal(index));
+                self.emit(Operation::AddImm(PrimOpKind::I64, 1i64.into()));
+                self.emit(Operation::Dup);
+                self.emit(Operation::StoreLocal(index));
+                self.emit(Operation::LoadLocal(index + 1));
+                self.emit(Operation::Sub(PrimOpKind::I64));
+                self.emit(Operation::JumpIfZero(exit));
+                self.emit(Operation::Jump(at as u32));
+                self.here();
+            }
+            "i" | "j" => {
+                let outer = if w == "i" { 1 } else { 2 };
+                if self.loops < outer {
+                    return self.error(span, format!("`{w}` outside of a do loop"));
+                }
+                self.emit(Operation::LoadLocal((self.loops - outer) * 2));
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The kind an op suffix names, i64 if there's none.
+fn kind(suffix: &str) -> Option<PrimOpKind> {
+    if suffix.is_empty() {
+        return Some(PrimOpKind::I64);
+    }
+    PrimOpKind::from_name(suffix)
+}
+
+/// Parses an int literal, or returns None if the word isn't one.
+fn number(w: &str) -> Option<Result<(PrimOpKind, IntOpImmediate), alloc::string::String>> {
+    let (negative, rest) = match w.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, w),
+    };
+    let (radix, rest) = match rest.strip_prefix("0x") {
+        Some(rest) => (16, rest),
+        None => (10, rest),
+    };
+    let end = rest.find(|c: char| !c.is_digit(radix)).unwrap_or(rest.len());
+    let (digits, suffix) = rest.split_at(end);
+    if digits.is_empty() {
+        return None;
+    }
+    let kind = kind(suffix)?;
+    let (min, max) = kind.bounds();
+    Some(
+        match i128::from_str_radix(digits, radix).map(|v| if negative { -v } else { v }) {
+            Ok(v) if (min..=max).contains(&v) => Ok((kind, IntOpImmediate::from(v as u64))),
+            _ => Err(format!("`{w}` doesn't fit in {}", kind.name())),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv6Addr;
+
+    use alloc::{string::String, vec::Vec};
+
+    use super::{compile, compile_module};
+    use crate::vm::{Operation, PrimOpKind, Vm};
+
+    fn run(source: &str) -> Vm {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let m = compile_module(atom!("forth"), source).unwrap();
+        vm.load(m).unwrap();
+        vm
+    }
+
+    #[test]
+    pub fn definitions() {
+        let mut vm = run(
+            ": square ( n -- n*n ) dup * ;
+             \\ Forward references and recursion.
+             : fact ( n -- n! ) dup 1 = if exit then dup 1 - recurse * ;
+             : tri ( n -- sum ) 0 swap 0 do i + loop ;
+             : grid ( -- n ) 0 3 0 do 4 0 do j 10 * i + + loop loop ;
+             : sign ( n -- s ) dup 0= if drop :zero else 0x10i32 drop -1 = if :minus else :plus then then ;",
+        );
+        let pid = vm.spawn().unwrap();
+        let n: i64 = vm.apply(pid, atom!("forth"), atom!("square"), (12i64,)).unwrap();
+        assert_eq!(n, 144);
+        let n: i64 = vm.apply(pid, atom!("forth"), atom!("fact"), (20i64,)).unwrap();
+        assert_eq!(n, 2_432_902_008_176_640_000);
+        let n: i64 = vm.apply(pid, atom!("forth"), atom!("tri"), (100i64,)).unwrap();
+        assert_eq!(n, 4950);
+        let n: i64 = vm.apply(pid, atom!("forth"), atom!("grid"), ()).unwrap();
+        assert_eq!(n, (0..3).flat_map(|j| (0..4).map(move |i| j * 10 + i)).sum::<i64>());
+        for (n, sign) in [(0i64, "zero"), (-1, "minus"), (5, "plus")] {
+            let s: String = vm.apply(pid, atom!("forth"), atom!("sign"), (n,)).unwrap();
+            assert_eq!(s, sign);
+        }
+    }
+
+    #[test]
+    pub fn main_and_strings() {
+        let mut vm = run(
+            "array dup 0 \"a\\tb\" ! dup 1 \"two\" ! 5 6 2dup 2drop nip
+             : twice ( x -- x x ) dup ;
+             1 pick drop 2 twice + depth",
+        );
+        let pid = vm.spawn().unwrap();
+        let depth: u64 = vm.apply(pid, atom!("forth"), atom!("main"), ()).unwrap();
+        assert_eq!(depth, 3);
+        assert_eq!(vm.pop::<i64>(pid).unwrap(), 4);
+        assert_eq!(vm.pop::<i64>(pid).unwrap(), 6);
+        let arr: Vec<String> = vm.pop(pid).unwrap();
+        assert_eq!(arr, ["a\tb", "two"]);
+    }
+
+    #[test]
+    pub fn sequences() {
+        assert_eq!(
+            compile("1u8 2u8 +u8 ( a comment ) 3 roll").unwrap(),
+            [
+                Operation::PushImm(PrimOpKind::U8, 1u8.into()),
+                Operation::PushImm(PrimOpKind::U8, 2u8.into()),
+                Operation::Add(PrimOpKind::U8),
+                Operation::Roll(3),
+            ]
+        );
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().unwrap();
+        let code = compile("begin 1 - dup 0= until drop 7i32 *i32").unwrap();
+        let n: i32 = vm.call(pid, &code, (7i32, 10i64)).unwrap();
+        assert_eq!(n, 49);
+    }
+
+    #[test]
+    pub fn diagnostics() {
+        // The lexer's errors come on their own.
+        let source = "1 .\n\"x\\q\" ( open";
+        let diags = compile_module(atom!("forth"), source).unwrap_err();
+        let found: Vec<_> = diags
+            .iter()
+            .map(|d| (d.span.line_col(source), d.message.as_str()))
+            .collect();
+        assert_eq!(found, [((2, 3), "unknown escape"), ((2, 7), "unterminated comment")]);
+
+        let source = ": f ( a -- ) if 1 + ;\n: f 300u8 nope pick ;\n1 then j ;";
+        let diags = compile_module(atom!("forth"), source).unwrap_err();
+        let found: Vec<_> = diags
+            .iter()
+            .map(|d| (d.span.line_col(source), d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ((3, 10), "`;` outside of a definition"),
+                ((2, 3), "`f` is already defined"),
+                ((1, 14), "`if` is never closed"),
+                ((2, 5), "`300u8` doesn't fit in u8"),
+                ((2, 11), "unknown word `nope`"),
+                ((2, 16), "`pick` needs a literal before it"),
+                ((3, 3), "`then` without `if`"),
+                ((3, 8), "`j` outside of a do loop"),
+            ]
+        );
+        assert_eq!(
+            diags[4].render(source),
+            "2:11: unknown word `nope`\n    : f 300u8 nope pick ;\n              ^^^^"
+        );
+
+        let diags = compile(": f ;\n\"s\" 0 do loop").unwrap_err();
+        let found: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            found,
+            [
+                "definitions need a module, see compile_module",
+                "strings need a module, see compile_module",
+                "do loops need a module, see compile_module",
+                "`loop` without `do`",
+            ]
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use alloc::{string::String, vec::Vec};
+
+use super::{Diagnostic, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum TokenKind<'a> {
+    Word(&'a str),
+    Str(String),
+    /// The inside of a `( ... )` comment, kept for stack comments.
+    Comment(&'a str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Token<'a> {
+    pub(super) kind: TokenKind<'a>,
+    pub(super) span: Span,
+}
+
+/// Splits source into words, strings and comments.
+pub(super) fn lex(source: &str) -> Result<Vec<Token<'_>>, Vec<Diagnostic>> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    let mut at = 0;
+    while at < bytes.len() {
+        if bytes[at].is_ascii_whitespace() {
+            at += 1;
+            continue;
+        }
+        let start = at;
+        while at < bytes.len() && !bytes[at].is_ascii_whitespace() {
+            at += 1;
+        }
+        let word = &source[start..at];
+
+        if word == "\\" {
+            while at < bytes.len() && bytes[at] != b'\n' {
+                at += 1;
+            }
+        } else if word == "(" {
+            match source[at..].find(')') {
+                Some(end) => {
+                    tokens.push(Token {
+                        kind: TokenKind::Comment(&source[at..at + end]),
+                        span: Span {
+                            start,
+                            end: at + end + 1,
+                        },
+                    });
+                    at += end + 1;
+                }
+                None => {
+                    errors.push(Diagnostic::new(Span { start, end: at }, "unterminated comment"));
+                    at = bytes.len();
+                }
+            }
+        } else if word.starts_with('"') {
+            at = start + 1;
+            match string(source, &mut at) {
+                Ok(s) => tokens.push(Token {
+                    kind: TokenKind::Str(s),
+                    span: Span { start, end: at },
+                }),
+                Err(e) => errors.push(e),
+            }
+        } else {
+            tokens.push(Token {
+                kind: TokenKind::Word(word),
+                span: Span { start, end: at },
+            });
+        }
+    }
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Reads a string literal from just after its opening quote, leaving `at` past the closing one.
+fn string(source: &str, at: &mut usize) -> Result<String, Diagnostic> {
+    let start = *at - 1;
+    let mut out = String::new();
+    let mut error = None;
+    let mut chars = source[*at..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                *at += i + 1;
+                return match error {
+                    Some(span) => Err(Diagnostic::new(span, "unknown escape")),
+                    None => Ok(out),
+                };
+            }
+            '\\' => match chars.next() {
+                Some((_, 'n')) => out.push('\n'),
+                Some((_, 't')) => out.push('\t'),
+                Some((_, '0')) => out.push('\0'),
+                Some((_, '"')) => out.push('"'),
+                Some((_, '\\')) => out.push('\\'),
+                Some((j, c)) => {
+                    error.get_or_insert(Span {
+                        start: *at + i,
+                        end: *at + j + c.len_utf8(),
+                    });
+                }
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+    *at = source.len();
+    Err(Diagnostic::new(
+        Span {
+            start,
+            end: source.len(),
+        },
+        "unterminated string",
+    ))
+}
@@ -0,0 +1,95 @@
+//! A small Forth dialect that compiles to Paravita bytecode.
+//!
+//! Source is a list of whitespace separated words, run left to right on the process stack:
+//!
+//! - `: name ( a b -- c ) ... ;` defines a word. The stack comment is optional, its inputs
+//!   give the arity the word is exported under.
+//! - Ints are `42`, `-1`, `0xff`, with an optional kind suffix as in `1i32` or `255u8`. Without
+//!   one they're i64. `:ok` is an atom, `"text"` a string, with `\n`, `\t`, `\"` and `\\`.
+//! - `if ... else ... then`, `begin ... until`, `begin ... again` and `limit start do ... loop`,
+//!   with `i` and `j` for the loop indices. `exit` returns early, `recurse` calls the word
+//!   being defined.
+//! - `+ - * /mod` work on i64, `+i32`, `*u8` and so on on other kinds. `=` and `0=` push 1 or 0.
+//!   `compare ( x y -- n )` and `hash ( x -- h )` work on any values.
+//! - Stack words are named as in Forth: `dup drop swap over rot -rot nip tuck 2dup 2drop 2swap
+//!   depth`, and `n pick`, `n roll` for a literal n.
+//! - `module:word/arity` calls a word of another module or a native.
+//! - `.` prints, `throw` raises, `n syscall` calls the host. `array`, `map`, `dict`,
+//!   `@ ( arr idx -- val )` and `! ( arr idx val -- )` work on objects, `@` and `!` taking a key
+//!   for maps and dicts. `keys values entries ( map -- arr )`, `merge ( m1 m2 -- m )` and
+//!   `filter ( map keys -- map )` work on maps and dicts.
+//! - `binary ( n -- bin )` makes n zero bytes, which `@` and `!` read and write as u8.
+//!   `slice ( bin start len -- bin )` shares bytes rather than copying them, `concat ( b1 b2 --
+//!   b )` copies. `@u32be ( bin off -- n )` and `!i16le ( bin off n -- )` read and write ints
+//!   of any kind at byte offsets, `be` or `le` giving the byte order, which `@u8` and `@i8`
+//!   don't need. `length ( obj -- n )` counts elements, entries or bytes.
+//! - `u8array ( n -- arr )` to `i64array` make packed arrays of n zeros, which hold ints densely
+//!   and work with `@`, `!` and `length`. `fill ( arr start count n -- )`, `copy ( src from dst
+//!   to count -- )`, `v+ v- v* ( dst src -- )` element by element, and `vsum vmin vmax ( arr --
+//!   n )`.
+//!
+//! `( ... )` and `\ ...` to the end of the line are comments.
+
+mod compiler;
+mod lexer;
+
+use core::fmt::Display;
+
+use alloc::{format, string::String};
+
+pub use compiler::{compile, compile_module, definitions};
+
+/// A range of bytes in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The 1-based line and column the span starts at.
+    pub fn line_col(&self, source: &str) -> (usize, usize) {
+        let before = &source[..self.start.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let col = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+        (line, col)
+    }
+}
+
+/// An error in Forth source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub(crate) fn new(span: Span, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// Formats the diagnostic with the line it points into, the span underlined.
+    pub fn render(&self, source: &str) -> String {
+        let (line, col) = self.span.line_col(source);
+        let text = source.lines().nth(line - 1).unwrap_or("");
+        let width = source
+            .get(self.span.start..self.span.end)
+            .map_or(1, |s| s.lines().next().unwrap_or("").chars().count().max(1));
+        format!(
+            "{line}:{col}: {}\n    {text}\n    {:>pad$}{}",
+            self.message,
+            "",
+            "^".repeat(width),
+            pad = col - 1
+        )
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}..{}: {}", self.span.start, self.span.end, self.message)
+    }
+}
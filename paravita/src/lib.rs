@@ -2,12 +2,19 @@
 #![feature(generic_const_exprs)]
 #![feature(allocator_api)]
 #![feature(variant_count)]
-#![feature(ip_in_core)]
-#![feature(error_in_core)]
 #![no_std]
 
 extern crate alloc;
-#[cfg(std)]
+// Lets derived code name this crate as `::paravita` from inside it too.
+extern crate self as paravita;
+#[cfg(feature = "std")]
 extern crate std;
 
+#[macro_use]
 pub mod vm;
+
+pub mod asm;
+pub mod forth;
+
+#[cfg(feature = "derive")]
+pub use paravita_derive::PvObject;
@@ -1,13 +1,20 @@
-use alloc::{boxed::Box, string::ToString};
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
 use async_lock::*;
 use bytemuck::Contiguous;
 use core::hash::Hash;
 use core::{
-    num::NonZeroU32,
+    fmt::Display,
+    num::{NonZeroU32, NonZeroUsize},
     ops::{Deref, DerefMut},
 };
 use indexmap::IndexSet;
-use once_cell::race::OnceBox;
+use once_cell::race::{OnceBox, OnceNonZeroUsize};
+
+use super::error::{VmError, VmResult};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Atom {
@@ -16,19 +23,87 @@ pub struct Atom {
 
 static ATOM_STORE: OnceBox<AtomStore> = OnceBox::new();
 
+/// Well-known atoms, pre-interned at build time from `atoms.txt`.
+/// These occupy handles `1..=STATIC_ATOMS.len()`, runtime interning continues after them.
+#[macro_use]
+pub mod statics {
+    use super::Atom;
+    include!(concat!(env!("OUT_DIR"), "/static_atoms.rs"));
+}
+
+impl Atom {
+    pub(crate) const fn from_static(handle: u32) -> Atom {
+        match NonZeroU32::new(handle) {
+            Some(handle) => Atom { handle },
+            None => panic!("Atom handles start at 1."),
+        }
+    }
+
+    /// The raw handle of this atom. Only meaningful within this VM instance, use an [AtomTable]
+    /// to move atoms anywhere else.
+    pub fn handle(&self) -> u32 {
+        self.handle.into_integer()
+    }
+
+    /// Looks up an atom by raw handle, returning None if no such atom has been interned.
+    pub fn from_handle(handle: u32) -> Option<Atom> {
+        let handle = NonZeroU32::new(handle)?;
+        if handle.into_integer() as usize > atoms_count() {
+            return None;
+        }
+        Some(Atom { handle })
+    }
+
+    /// Returns true if this atom is one of the build-time [statics].
+    pub fn is_static(&self) -> bool {
+        self.handle.into_integer() as usize <= statics::STATIC_ATOMS.len()
+    }
+}
+
 impl From<Atom> for &'static str {
     fn from(value: Atom) -> Self {
         AtomStore::read(value)
     }
 }
 
+impl Display for Atom {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(AtomStore::read(*self))
+    }
+}
+
 impl From<&str> for Atom {
     fn from(value: &str) -> Self {
         AtomStore::insert(value)
     }
 }
 
-struct AtomStore {
+/// An atom interned on first use and remembered after that, for names known at compile time
+/// that [atom!] can't resolve to a static. `#[derive(PvObject)]` keeps its keys in these.
+pub struct LazyAtom {
+    name: &'static str,
+    handle: OnceNonZeroUsize,
+}
+
+impl LazyAtom {
+    pub const fn new(name: &'static str) -> LazyAtom {
+        LazyAtom {
+            name,
+            handle: OnceNonZeroUsize::new(),
+        }
+    }
+
+    pub fn get(&self) -> Atom {
+        let handle = self.handle.get_or_init(|| {
+            let a = AtomStore::insert(self.name);
+            NonZeroUsize::try_from(a.handle).unwrap()
+        });
+        Atom::from_static(handle.get() as u32)
+    }
+}
+
+/// The process-global atom interner.
+pub struct AtomStore {
     // SAFETY: DO NOT REMOVE ATOMS FROM THE SET. Shit explodes!
     atoms: RwLock<IndexSet<&'static str, fnv::FnvBuildHasher>>,
 }
@@ -37,26 +112,26 @@ unsafe impl Sync for AtomStore {}
 
 impl AtomStore {
     fn map(&self) -> RwLockReadGuard<'_, IndexSet<&'static str, fnv::FnvBuildHasher>> {
-        let idx = loop {
+        loop {
             if let Some(x) = self.atoms.try_read() {
                 break x;
             }
-        };
-
-        return idx;
+        }
     }
 
     fn mut_map(&self) -> RwLockWriteGuard<'_, IndexSet<&'static str, fnv::FnvBuildHasher>> {
-        let idx = loop {
+        loop {
             if let Some(x) = self.atoms.try_write() {
                 break x;
             }
-        };
-
-        return idx;
+        }
     }
 
     pub fn insert(s: &str) -> Atom {
+        if let Some(a) = statics::lookup(s) {
+            return a;
+        }
+
         let this = Self::get();
         // Necessary block to prevent deadlock over handles.
         {
@@ -75,12 +150,14 @@ impl AtomStore {
         let mut idx = this.mut_map();
         let idx: &mut IndexSet<&'static str, fnv::FnvBuildHasher> = idx.deref_mut();
 
-        // MEMSAFETY: This /will/ explode on OOM. FIXME!
+        // MEMSAFETY: Panics on OOM, like init. Interning sits behind `Atom::from`, which can't
+        // fail, and names are short, so a host that can't intern one is already out of memory
+        // for anything else the VM would do with it.
         let (i, _) = idx.insert_full(s.to_string().leak());
 
-        return Atom {
+        Atom {
             handle: unsafe { NonZeroU32::new_unchecked(i as u32 + 1) },
-        };
+        }
     }
 
     fn get<'a>() -> &'a Self {
@@ -90,18 +167,159 @@ impl AtomStore {
     fn init() -> Box<AtomStore> {
         Box::new({
             AtomStore {
-                atoms: RwLock::new(IndexSet::with_hasher(fnv::FnvBuildHasher::default())),
+                // MEMSAFETY: Panics on OOM, which is fine here. This runs once, on the first use
+                // of any atom, for a table whose size atoms.txt fixes at build time. A host that
+                // can't spare that much can't run the VM at all.
+                atoms: RwLock::new(statics::STATIC_ATOMS.iter().copied().collect()),
             }
         })
     }
 
     pub fn read(h: Atom) -> &'static str {
+        if h.is_static() {
+            return statics::STATIC_ATOMS[h.handle.into_integer() as usize - 1];
+        }
+
         let idx = Self::get().map();
         let idx: &IndexSet<&'static str, fnv::FnvBuildHasher> = idx.deref();
         idx[h.handle.into_integer() as usize - 1]
     }
 }
 
+impl AtomStore {
+    /// Exports every atom interned so far. Handles in the table match local handles.
+    pub fn snapshot() -> AtomTable {
+        let idx = Self::get().map();
+        let idx: &IndexSet<&'static str, fnv::FnvBuildHasher> = idx.deref();
+        let mut table = AtomTable::new();
+        for s in idx.iter() {
+            table.names.insert(s.to_string());
+        }
+        table
+    }
+
+    /// Maps the handles of a foreign table to ours. Names are only interned as their handles
+    /// get translated, so a foreign table can't fill the store with names nothing refers to.
+    pub fn import(table: &AtomTable) -> AtomTranslation<'_> {
+        AtomTranslation {
+            table,
+            atoms: Vec::new(),
+        }
+    }
+}
+
+/// A list of atom names, in handle order. This is how atoms travel between VM instances,
+/// in serialized bytecode or in messages: by name, never by raw handle.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AtomTable {
+    names: IndexSet<String, fnv::FnvBuildHasher>,
+}
+
+impl AtomTable {
+    pub fn new() -> AtomTable {
+        AtomTable::default()
+    }
+
+    /// Adds an atom to the table if not already present, returning its handle within the table.
+    pub fn add(&mut self, atom: Atom) -> u32 {
+        let s: &'static str = atom.into();
+        if let Some(i) = self.names.get_index_of(s) {
+            return i as u32 + 1;
+        }
+        let (i, _) = self.names.insert_full(s.to_string());
+        i as u32 + 1
+    }
+
+    /// Returns the name behind a handle within the table.
+    pub fn get(&self, handle: u32) -> Option<&str> {
+        let i = (handle as usize).checked_sub(1)?;
+        self.names.get_index(i).map(|s| s.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(|s| s.as_str())
+    }
+
+    /// Encodes the table as a u32 LE count, followed by each name as a u32 LE length and UTF-8 bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&(self.names.len() as u32).to_le_bytes());
+        for s in self.names.iter() {
+            out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+        out
+    }
+
+    /// Decodes a table written by [AtomTable::to_bytes], returning it and the number of bytes consumed.
+    pub fn from_bytes(bytes: &[u8]) -> VmResult<(AtomTable, usize)> {
+        fn read(bytes: &[u8], at: usize, len: usize) -> VmResult<&[u8]> {
+            let end = at.checked_add(len).ok_or(VmError::MalformedAtomTable())?;
+            bytes.get(at..end).ok_or(VmError::MalformedAtomTable())
+        }
+        fn read_u32(bytes: &[u8], at: usize) -> VmResult<u32> {
+            let b = read(bytes, at, 4)?;
+            Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        }
+
+        let count = read_u32(bytes, 0)?;
+        let mut at = 4;
+        let mut table = AtomTable::new();
+        for _ in 0..count {
+            let len = read_u32(bytes, at)? as usize;
+            at += 4;
+            let b = read(bytes, at, len)?;
+            let s = core::str::from_utf8(b).map_err(|_| VmError::MalformedAtomTable())?;
+            // Duplicate names would make two handles alias one atom, reject them.
+            if !table.names.insert(s.to_string()) {
+                return Err(VmError::MalformedAtomTable());
+            }
+            at += len;
+        }
+        Ok((table, at))
+    }
+}
+
+/// Maps the handles of a foreign [AtomTable] to local atoms. Built by [AtomStore::import].
+#[derive(Debug, Clone)]
+pub struct AtomTranslation<'a> {
+    table: &'a AtomTable,
+    /// The atoms translated so far, by foreign handle - 1.
+    atoms: Vec<Option<Atom>>,
+}
+
+impl AtomTranslation<'_> {
+    /// Translates a foreign handle into the local atom naming the same string, interning it
+    /// the first time.
+    pub fn translate(&mut self, foreign: u32) -> VmResult<Atom> {
+        let unknown = || VmError::UnknownAtom(foreign);
+        let i = (foreign as usize).checked_sub(1).ok_or_else(unknown)?;
+        let name = self.table.names.get_index(i).ok_or_else(unknown)?;
+        if self.atoms.is_empty() {
+            self.atoms.try_reserve_exact(self.table.len())?;
+            self.atoms.resize(self.table.len(), None);
+        }
+        Ok(*self.atoms[i].get_or_insert_with(|| AtomStore::insert(name)))
+    }
+
+    /// The number of handles in the foreign table.
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+}
+
 // Returns the number of atoms the VM has in cache.
 pub fn atoms_count() -> usize {
     AtomStore::get().map().len()
@@ -115,7 +333,9 @@ impl Hash for Atom {
 
 #[cfg(test)]
 mod tests {
-    use super::{atoms_count, Atom};
+    use alloc::string::String;
+
+    use super::{atoms_count, statics, Atom, AtomStore, AtomTable};
 
     #[test]
     pub fn insert_get() {
@@ -126,6 +346,73 @@ mod tests {
         let baz_atom = Atom::from("baz");
         assert_eq!("bar", <Atom as Into<&str>>::into(bar_atom));
         assert_eq!("baz", <Atom as Into<&str>>::into(baz_atom));
-        assert_eq!(atoms_count(), 3);
+        assert_ne!(foo_atom, bar_atom);
+        assert_eq!(foo_atom, Atom::from("foo"));
+        assert!(!foo_atom.is_static());
+        assert!(atoms_count() >= statics::STATIC_ATOMS.len() + 3);
+    }
+
+    #[test]
+    pub fn static_atoms() {
+        const OK: Atom = atom!("ok");
+
+        assert_eq!(Atom::from("ok"), OK);
+        assert_eq!(Atom::from("false"), statics::FALSE);
+        assert_eq!("error", <Atom as Into<&str>>::into(atom!("error")));
+        assert!(statics::TIMEOUT.is_static());
+
+        match Atom::from("normal") {
+            atom!("ok") => panic!(),
+            atom!("normal") => {}
+            _ => panic!(),
+        }
+
+        // Not well-known, so this interns at runtime.
+        let dynamic = atom!("not_well_known");
+        assert!(!dynamic.is_static());
+        assert_eq!("not_well_known", <Atom as Into<&str>>::into(dynamic));
+    }
+
+    #[test]
+    pub fn table_translation() {
+        // Pretend another node interned these in a different order.
+        let mut foreign = AtomTable::new();
+        assert_eq!(foreign.add(Atom::from("remote_b")), 1);
+        assert_eq!(foreign.add(atom!("ok")), 2);
+        assert_eq!(foreign.add(Atom::from("remote_a")), 3);
+        assert_eq!(foreign.add(Atom::from("remote_b")), 1);
+
+        let bytes = foreign.to_bytes();
+        let (decoded, used) = AtomTable::from_bytes(&bytes).unwrap();
+        assert_eq!(used, bytes.len());
+        assert_eq!(decoded, foreign);
+        assert!(AtomTable::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut map = AtomStore::import(&decoded);
+        assert_eq!(map.translate(1).unwrap(), Atom::from("remote_b"));
+        assert_eq!(map.translate(2).unwrap(), atom!("ok"));
+        assert_eq!(map.translate(3).unwrap(), Atom::from("remote_a"));
+        assert!(map.translate(0).is_err());
+        assert!(map.translate(4).is_err());
+
+        // Names are interned only once something refers to them.
+        let mut foreign = AtomTable::new();
+        foreign.names.insert(String::from("remote_unused"));
+        foreign.names.insert(String::from("remote_used"));
+        let mut map = AtomStore::import(&foreign);
+        assert_eq!(<&str>::from(map.translate(2).unwrap()), "remote_used");
+        assert!(!AtomStore::snapshot().iter().any(|s| s == "remote_unused"));
+
+        // Lengths running past the end are malformed.
+        let mut huge = 1u32.to_le_bytes().to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(AtomTable::from_bytes(&huge).is_err());
+
+        // A snapshot of our own store maps every handle to itself.
+        let snap = AtomStore::snapshot();
+        let ok = atom!("ok");
+        assert_eq!(snap.get(ok.handle()), Some("ok"));
+        assert_eq!(Atom::from_handle(ok.handle()), Some(ok));
+        assert_eq!(Atom::from_handle(0), None);
     }
 }
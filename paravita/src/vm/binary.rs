@@ -0,0 +1,282 @@
+use core::{cell::Cell, fmt::Debug};
+
+use alloc::{rc::Rc, vec::Vec};
+
+use super::{
+    error::{VmError, VmResult},
+    Endian, PrimOpKind, Value, ValueKind,
+};
+
+/// A run of raw bytes, for packets, disk blocks and the like. A binary is a view of `len`
+/// bytes from `start` of a shared buffer: [slicing](Binary::slice) makes another view of the
+/// same buffer rather than copying it, so writes through one show through the others.
+/// Cloning copies the bytes, giving an unshared binary.
+pub struct Binary {
+    bytes: Rc<[Cell<u8>]>,
+    start: usize,
+    len: usize,
+}
+
+impl Binary {
+    /// A binary of `len` zero bytes.
+    pub fn zeroed(len: usize) -> VmResult<Binary> {
+        let mut bytes = Vec::new();
+        bytes.try_reserve_exact(len)?;
+        bytes.resize(len, Cell::new(0));
+        Ok(Binary::from_cells(bytes))
+    }
+
+    /// A binary holding a copy of `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> VmResult<Binary> {
+        let mut cells = Vec::new();
+        cells.try_reserve_exact(bytes.len())?;
+        cells.extend(bytes.iter().copied().map(Cell::new));
+        Ok(Binary::from_cells(cells))
+    }
+
+    fn from_cells(cells: Vec<Cell<u8>>) -> Binary {
+        let len = cells.len();
+        Binary {
+            //MEMSAFETY: The Rc's counts are allocated next to the bytes, and panic on OOM.
+            bytes: Rc::from(cells),
+            start: 0,
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn cells(&self) -> &[Cell<u8>] {
+        &self.bytes[self.start..self.start + self.len]
+    }
+
+    /// The `count` bytes at `start`, or OutOfBounds if they run past the end.
+    fn range(&self, start: usize, count: usize) -> VmResult<&[Cell<u8>]> {
+        let out_of_bounds = VmError::OutOfBounds {
+            start,
+            count,
+            length: self.len,
+        };
+        match start.checked_add(count) {
+            Some(end) if end <= self.len => Ok(&self.cells()[start..end]),
+            _ => Err(out_of_bounds),
+        }
+    }
+
+    pub fn bytes(&self) -> impl ExactSizeIterator<Item = u8> + '_ {
+        self.cells().iter().map(Cell::get)
+    }
+
+    /// The bytes, in a new Vec.
+    pub fn to_vec(&self) -> VmResult<Vec<u8>> {
+        let mut out = Vec::new();
+        out.try_reserve_exact(self.len)?;
+        out.extend(self.bytes());
+        Ok(out)
+    }
+
+    /// A view of `len` bytes from `start`, sharing this binary's buffer.
+    pub fn slice(&self, start: usize, len: usize) -> VmResult<Binary> {
+        self.range(start, len)?;
+        Ok(Binary {
+            bytes: self.bytes.clone(),
+            start: self.start + start,
+            len,
+        })
+    }
+
+    /// A new binary of this one's bytes, then `other`'s.
+    pub fn concat(&self, other: &Binary) -> VmResult<Binary> {
+        let mut cells = Vec::new();
+        cells.try_reserve_exact(self.len + other.len)?;
+        cells.extend(self.cells().iter().chain(other.cells()).cloned());
+        Ok(Binary::from_cells(cells))
+    }
+
+    /// The byte at `i`, or None past the end.
+    pub fn get(&self, i: usize) -> Option<u8> {
+        self.cells().get(i).map(Cell::get)
+    }
+
+    /// Sets the byte at `i`, failing with OutOfBounds past the end.
+    pub fn set(&self, i: usize, byte: u8) -> VmResult<()> {
+        self.range(i, 1)?[0].set(byte);
+        Ok(())
+    }
+
+    /// Reads an int of kind `k` at byte `offset`, in the given byte order.
+    pub fn read(&self, offset: usize, k: PrimOpKind, endian: Endian) -> VmResult<Value> {
+        let mut buf = [0; 8];
+        let buf = &mut buf[..k.size()];
+        for (b, c) in buf.iter_mut().zip(self.range(offset, k.size())?) {
+            *b = c.get();
+        }
+        if endian == Endian::Big {
+            buf.reverse();
+        }
+        let bits = k.read_le(buf).expect("the buffer fits the kind");
+        Ok(Value::int(k, bits))
+    }
+
+    /// Writes an int of any kind, truncated to kind `k`, at byte `offset` in the given byte
+    /// order.
+    pub fn write(&self, offset: usize, v: &Value, k: PrimOpKind, endian: Endian) -> VmResult<()> {
+        let (_, bits) = v.int_bits().ok_or(VmError::PopExpectedType {
+            expected: ValueKind::Int,
+            found: v.kind(),
+        })?;
+        let cells = self.range(offset, k.size())?;
+        let mut buf = [0; 8];
+        let buf = &mut buf[..k.size()];
+        k.write_le(bits, buf);
+        if endian == Endian::Big {
+            buf.reverse();
+        }
+        for (c, b) in cells.iter().zip(buf) {
+            c.set(*b);
+        }
+        Ok(())
+    }
+}
+
+impl Clone for Binary {
+    fn clone(&self) -> Self {
+        //MEMSAFETY: Clone is falliable by panic, like the other objects'.
+        Binary::from_cells(self.cells().to_vec())
+    }
+}
+
+impl Debug for Binary {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.bytes()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv6Addr;
+
+    use alloc::{format, string::String, vec::Vec};
+
+    use super::Binary;
+    use crate::{
+        forth,
+        vm::{Endian, Operation, PrimOpKind, Value, Vm, VmError},
+    };
+
+    #[test]
+    pub fn endianness() {
+        let b = Binary::zeroed(8).unwrap();
+        let v = Value::int(PrimOpKind::U32, 0x0102_0304);
+        b.write(0, &v, PrimOpKind::U32, Endian::Big).unwrap();
+        b.write(4, &v, PrimOpKind::U32, Endian::Little).unwrap();
+        assert_eq!(b.to_vec().unwrap(), [1, 2, 3, 4, 4, 3, 2, 1]);
+        assert_eq!(
+            b.read(0, PrimOpKind::U16, Endian::Big).unwrap(),
+            Value::int(PrimOpKind::U16, 0x0102)
+        );
+        assert_eq!(
+            b.read(6, PrimOpKind::U16, Endian::Little).unwrap(),
+            Value::int(PrimOpKind::U16, 0x0102)
+        );
+        // Writes truncate, reads sign extend.
+        b.write(
+            0,
+            &Value::from(-2i64, PrimOpKind::I64),
+            PrimOpKind::I16,
+            Endian::Big,
+        )
+        .unwrap();
+        assert_eq!(
+            b.read(0, PrimOpKind::I16, Endian::Big).unwrap(),
+            Value::int(PrimOpKind::I16, -2i64 as u64)
+        );
+        assert!(matches!(
+            b.read(6, PrimOpKind::U32, Endian::Big),
+            Err(VmError::OutOfBounds {
+                start: 6,
+                count: 4,
+                length: 8
+            })
+        ));
+    }
+
+    #[test]
+    pub fn slices_share() {
+        let b = Binary::from_bytes(&[1, 2, 3, 4, 5]).unwrap();
+        let s = b.slice(1, 3).unwrap();
+        s.set(0, 9).unwrap();
+        assert_eq!(b.to_vec().unwrap(), [1, 9, 3, 4, 5]);
+        assert_eq!(s.slice(1, 2).unwrap().to_vec().unwrap(), [3, 4]);
+        assert!(s.slice(2, 2).is_err());
+        assert_eq!(s.get(3), None);
+        assert!(s.set(3, 0).is_err());
+
+        // Clones and concatenations don't.
+        let c = s.clone();
+        let cat = s.concat(&b).unwrap();
+        s.set(1, 0).unwrap();
+        assert_eq!(c.to_vec().unwrap(), [9, 3, 4]);
+        assert_eq!(cat.to_vec().unwrap(), [9, 3, 4, 1, 9, 3, 4, 5]);
+        assert!(Binary::zeroed(usize::MAX).is_err());
+    }
+
+    #[test]
+    pub fn huge_binary() {
+        // Far more than any host has, so the allocation fails rather than the host.
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().unwrap();
+        let fault = vm
+            .run(
+                pid,
+                &[
+                    Operation::PushImm(PrimOpKind::U64, (1u64 << 46).into()),
+                    Operation::MakeBinary,
+                ],
+            )
+            .unwrap_err();
+        assert!(matches!(fault.error, VmError::MemoryReserveFailed(_)));
+    }
+
+    #[test]
+    pub fn binary_ops() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let name = vm
+            .load(
+                forth::compile_module(
+                    atom!("binaries"),
+                    r#"4 binary dup 0 258 !u16be dup 2 -1 !i16le
+                    dup 0 @u16be over 2 @i8 2 pick 3 @
+                    3 roll 1 2 slice dup length swap 2 binary concat "hi" length"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let pid = vm.spawn().unwrap();
+        vm.start(pid, name, atom!("main"), ()).unwrap();
+        vm.resume(pid, usize::MAX).unwrap();
+        let stack: Vec<String> = vm
+            .process(pid)
+            .unwrap()
+            .stack()
+            .map(|v| format!("{v}"))
+            .collect();
+        assert_eq!(
+            stack,
+            [
+                "258u16",
+                "-1i8",
+                "255u8",
+                "2u64",
+                "<<2, 255, 0, 0>>",
+                "2u64"
+            ]
+        );
+    }
+}
@@ -0,0 +1,485 @@
+use core::{
+    cmp::Ordering,
+    hash::{Hash, Hasher},
+};
+
+use alloc::{rc::Rc, vec::Vec};
+use fnv::{FnvBuildHasher, FnvHasher};
+use indexmap::IndexMap;
+
+use super::{Closure, PVObject, PVObjectType, PVString, Value};
+
+impl Value {
+    /// Compares values in the term order, which is total over all values:
+    ///
+    /// `null < ints < atoms < strings < functions < userdata < arrays < maps < dicts < binaries
+    /// < packed arrays`
+    ///
+    /// Ints compare by value whatever their kinds, then by kind, so `1u8 < 1i64 < 2u8`. Atoms
+    /// and strings compare by their text, functions by module, name and arity and then what
+    /// they captured. Arrays compare element by element, maps by size, then by their keys in
+    /// order, then by the values of those keys, and dicts the same way. Binaries compare byte
+    /// by byte, packed arrays element by element like ints and then by kind. Userdata only
+    /// equal themselves.
+    ///
+    /// Values are equal when they're the same structurally, which is what `==` tests. Cycles
+    /// are fine: two cyclic values are equal if they unfold the same way forever.
+    pub fn term_cmp(&self, other: &Value) -> Ordering {
+        Comparer::default().value(self, other)
+    }
+
+    /// A hash of the value's structure, the same for equal values. It doesn't depend on the
+    /// target or on atom handles, so it's the same in every VM. Only the first
+    /// [HASH_LIMIT](Value::HASH_LIMIT) values nested in it count, which keeps big and cyclic
+    /// values cheap to hash.
+    pub fn structural_hash(&self) -> u64 {
+        let mut h = StructuralHasher {
+            state: FnvHasher::default(),
+            budget: Value::HASH_LIMIT,
+        };
+        h.value(self);
+        h.state.finish()
+    }
+
+    /// How many values nested in a value [Value::structural_hash] looks at.
+    pub const HASH_LIMIT: usize = 256;
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.term_cmp(other).is_eq()
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.term_cmp(other)
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.structural_hash());
+    }
+}
+
+impl PartialEq for PVObject {
+    fn eq(&self, other: &Self) -> bool {
+        Comparer::default().object(self, other).is_eq()
+    }
+}
+
+impl PartialEq for PVObjectType {
+    fn eq(&self, other: &Self) -> bool {
+        Comparer::default().objects(self, other).is_eq()
+    }
+}
+
+/// Where each type sits in the term order.
+fn rank(v: &Value) -> u8 {
+    match v {
+        Value::Null => 0,
+        Value::Int(..) => 1,
+        Value::Object(o) => object_rank(&o.get()),
+    }
+}
+
+fn object_rank(o: &PVObjectType) -> u8 {
+    match o {
+        PVObjectType::String(PVString::Atom(_)) => 2,
+        PVObjectType::String(PVString::Str(_)) => 3,
+        PVObjectType::Function(_) => 4,
+        PVObjectType::UserData(_) => 5,
+        PVObjectType::Array(_) => 6,
+        PVObjectType::Map(_) => 7,
+        PVObjectType::Dict(_) => 8,
+        PVObjectType::Binary(_) => 9,
+        PVObjectType::Packed(_) => 10,
+    }
+}
+
+/// Compares strings in the term order: atoms before strings, each by their text.
+pub(super) fn string_cmp(a: &PVString, b: &PVString) -> Ordering {
+    match (a, b) {
+        (PVString::Atom(a), PVString::Atom(b)) if a == b => Ordering::Equal,
+        (PVString::Atom(a), PVString::Atom(b)) => <&str>::from(*a).cmp(<&str>::from(*b)),
+        (PVString::Str(a), PVString::Str(b)) => a.cmp(b),
+        (PVString::Atom(_), PVString::Str(_)) => Ordering::Less,
+        (PVString::Str(_), PVString::Atom(_)) => Ordering::Greater,
+    }
+}
+
+fn sorted_keys<V>(map: &IndexMap<PVString, V, FnvBuildHasher>) -> Vec<&PVString> {
+    let mut keys: Vec<_> = map.keys().collect();
+    keys.sort_unstable_by(|a, b| string_cmp(a, b));
+    keys
+}
+
+fn sorted_dict_keys(dict: &IndexMap<Value, Value, FnvBuildHasher>) -> Vec<&Value> {
+    let mut keys: Vec<_> = dict.keys().collect();
+    keys.sort_unstable();
+    keys
+}
+
+#[derive(Default)]
+struct Comparer {
+    /// The pairs of objects being compared. Meeting one again means going around a cycle in
+    /// both values at once, and nothing has differed so far.
+    path: Vec<(*const (), *const ())>,
+}
+
+impl Comparer {
+    fn value(&mut self, a: &Value, b: &Value) -> Ordering {
+        match (a, b) {
+            (Value::Int(..), Value::Int(..)) => {
+                let kinds = (a.int_bits().map(|(k, _)| k), b.int_bits().map(|(k, _)| k));
+                a.as_i128().cmp(&b.as_i128()).then(kinds.0.cmp(&kinds.1))
+            }
+            (Value::Object(x), Value::Object(y)) => self.object(x, y),
+            _ => rank(a).cmp(&rank(b)),
+        }
+    }
+
+    fn object(&mut self, x: &PVObject, y: &PVObject) -> Ordering {
+        let pair = (x.as_ptr(), y.as_ptr());
+        if pair.0 == pair.1 || self.path.contains(&pair) {
+            return Ordering::Equal;
+        }
+        self.path.push(pair);
+        let ord = self.objects(&x.get(), &y.get());
+        self.path.pop();
+        ord
+    }
+
+    fn objects(&mut self, x: &PVObjectType, y: &PVObjectType) -> Ordering {
+        match (x, y) {
+            (PVObjectType::String(a), PVObjectType::String(b)) => string_cmp(a, b),
+            (PVObjectType::Function(a), PVObjectType::Function(b)) => self.closures(a, b),
+            (PVObjectType::UserData(a), PVObjectType::UserData(b)) => {
+                Rc::as_ptr(a).cast::<()>().cmp(&Rc::as_ptr(b).cast::<()>())
+            }
+            (PVObjectType::Array(a), PVObjectType::Array(b)) => self.values(a, b),
+            (PVObjectType::Binary(a), PVObjectType::Binary(b)) => a.bytes().cmp(b.bytes()),
+            (PVObjectType::Packed(a), PVObjectType::Packed(b)) => a.term_cmp(b),
+            (PVObjectType::Map(a), PVObjectType::Map(b)) => {
+                let ord = a.len().cmp(&b.len());
+                if ord.is_ne() {
+                    return ord;
+                }
+                let (ka, kb) = (sorted_keys(a), sorted_keys(b));
+                let ord = ka
+                    .iter()
+                    .zip(&kb)
+                    .map(|(x, y)| string_cmp(x, y))
+                    .find(|o| o.is_ne());
+                if let Some(ord) = ord {
+                    return ord;
+                }
+                for k in ka {
+                    let ord = self.value(&a[k], &b[k]);
+                    if ord.is_ne() {
+                        return ord;
+                    }
+                }
+                Ordering::Equal
+            }
+            (PVObjectType::Dict(a), PVObjectType::Dict(b)) => {
+                let ord = a.len().cmp(&b.len());
+                if ord.is_ne() {
+                    return ord;
+                }
+                let (ka, kb) = (sorted_dict_keys(a), sorted_dict_keys(b));
+                for (x, y) in ka.iter().zip(&kb) {
+                    let ord = self.value(x, y);
+                    if ord.is_ne() {
+                        return ord;
+                    }
+                }
+                for (x, y) in ka.iter().zip(&kb) {
+                    let ord = self.value(&a[*x], &b[*y]);
+                    if ord.is_ne() {
+                        return ord;
+                    }
+                }
+                Ordering::Equal
+            }
+            _ => object_rank(x).cmp(&object_rank(y)),
+        }
+    }
+
+    fn closures(&mut self, a: &Closure, b: &Closure) -> Ordering {
+        let text = |atom| <&str>::from(atom);
+        text(a.module())
+            .cmp(text(b.module()))
+            .then_with(|| text(a.name()).cmp(text(b.name())))
+            .then(a.arity().cmp(&b.arity()))
+            .then_with(|| self.values(a.captured(), b.captured()))
+    }
+
+    fn values(&mut self, a: &[Value], b: &[Value]) -> Ordering {
+        for (x, y) in a.iter().zip(b) {
+            let ord = self.value(x, y);
+            if ord.is_ne() {
+                return ord;
+            }
+        }
+        a.len().cmp(&b.len())
+    }
+}
+
+/// Hashes a value and what's in it, in an order that only depends on its structure, until
+/// the budget runs out. Equal values look the same until then, cycles and all.
+struct StructuralHasher {
+    state: FnvHasher,
+    budget: usize,
+}
+
+impl StructuralHasher {
+    fn value(&mut self, v: &Value) {
+        match v {
+            Value::Null => self.state.write_u8(0),
+            Value::Int(..) => {
+                let (kind, bits) = v.int_bits().expect("an int");
+                self.state.write(&[1, kind as u8]);
+                self.state.write(&bits.to_le_bytes());
+            }
+            Value::Object(o) => self.object(&o.get()),
+        }
+    }
+
+    fn object(&mut self, o: &PVObjectType) {
+        match o {
+            PVObjectType::String(s) => self.string(s),
+            PVObjectType::Function(c) => {
+                self.state.write_u8(4);
+                self.text(c.module().into());
+                self.text(c.name().into());
+                self.state.write(&c.arity().to_le_bytes());
+                self.values(c.captured());
+            }
+            PVObjectType::UserData(_) => self.state.write_u8(5),
+            PVObjectType::Array(a) => {
+                self.state.write_u8(6);
+                self.len(a.len());
+                self.values(a);
+            }
+            PVObjectType::Map(m) => {
+                self.state.write_u8(7);
+                self.len(m.len());
+                for k in sorted_keys(m) {
+                    if !self.spend() {
+                        return;
+                    }
+                    self.string(k);
+                    self.value(&m[k]);
+                }
+            }
+            PVObjectType::Dict(d) => {
+                self.state.write_u8(8);
+                self.len(d.len());
+                for k in sorted_dict_keys(d) {
+                    if !self.spend() {
+                        return;
+                    }
+                    self.value(k);
+                    self.value(&d[k]);
+                }
+            }
+            PVObjectType::Binary(b) => {
+                self.state.write_u8(9);
+                self.len(b.len());
+                b.bytes().for_each(|byte| self.state.write_u8(byte));
+            }
+            PVObjectType::Packed(p) => {
+                self.state.write(&[10, p.kind() as u8]);
+                self.len(p.len());
+                p.ints().for_each(|n| self.state.write(&n.to_le_bytes()));
+            }
+        }
+    }
+
+    fn string(&mut self, s: &PVString) {
+        match s {
+            PVString::Atom(a) => {
+                self.state.write_u8(2);
+                self.text((*a).into());
+            }
+            PVString::Str(s) => {
+                self.state.write_u8(3);
+                self.text(s);
+            }
+        }
+    }
+
+    fn values(&mut self, vs: &[Value]) {
+        for v in vs {
+            if !self.spend() {
+                return;
+            }
+            self.value(v);
+        }
+    }
+
+    /// Takes one from the budget, false if there's none left.
+    fn spend(&mut self) -> bool {
+        self.budget = match self.budget.checked_sub(1) {
+            Some(budget) => budget,
+            None => return false,
+        };
+        true
+    }
+
+    fn text(&mut self, s: &str) {
+        self.len(s.len());
+        self.state.write(s.as_bytes());
+    }
+
+    fn len(&mut self, n: usize) {
+        self.state.write(&(n as u64).to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cmp::Ordering;
+
+    use alloc::{string::String, vec, vec::Vec};
+
+    use crate::vm::{PVObject, PVObjectType, PVString, Value};
+
+    fn int(n: impl Into<Value>) -> Value {
+        n.into()
+    }
+
+    fn array(elems: Vec<Value>) -> Value {
+        Value::Object(PVObject::make_array_from(elems).unwrap())
+    }
+
+    fn atom(name: &str) -> Value {
+        Value::Object(PVObject::from(crate::vm::Atom::from(name)))
+    }
+
+    fn str(s: &str) -> Value {
+        Value::Object(PVObject::make_string(PVString::Str(String::from(s))).unwrap())
+    }
+
+    fn map(entries: &[(&str, Value)]) -> Value {
+        let m = PVObject::make_map().unwrap();
+        if let PVObjectType::Map(inner) = &mut *m.get_mut() {
+            for (k, v) in entries {
+                inner.insert(PVString::Atom((*k).into()), v.clone());
+            }
+        };
+        Value::Object(m)
+    }
+
+    fn dict(entries: &[(Value, Value)]) -> Value {
+        let d = PVObject::make_dict().unwrap();
+        if let PVObjectType::Dict(inner) = &mut *d.get_mut() {
+            inner.extend(entries.iter().cloned());
+        };
+        Value::Object(d)
+    }
+
+    #[test]
+    pub fn term_order() {
+        // In order, each less than the next.
+        let sorted = [
+            Value::Null,
+            int(-1i64),
+            int(1u8),
+            int(1i64),
+            int(2u8),
+            atom("apple"),
+            atom("banana"),
+            str("apple"),
+            str("apples"),
+            array(vec![]),
+            array(vec![int(1i64), int(2i64)]),
+            array(vec![int(1i64), int(3i64)]),
+            array(vec![int(2i64)]),
+            map(&[("z", int(0i64))]),
+            map(&[("a", int(0i64)), ("b", int(0i64))]),
+            map(&[("a", int(1i64)), ("b", int(0i64))]),
+            dict(&[(int(2i64), Value::Null)]),
+            dict(&[(int(1i64), Value::Null), (int(3i64), Value::Null)]),
+            dict(&[(int(2i64), Value::Null), (int(3i64), Value::Null)]),
+        ];
+        for (i, a) in sorted.iter().enumerate() {
+            for (j, b) in sorted.iter().enumerate() {
+                assert_eq!(a.cmp(b), i.cmp(&j), "{a} against {b}");
+            }
+        }
+        let mut shuffled: Vec<_> = sorted.iter().rev().cloned().collect();
+        shuffled.sort();
+        assert_eq!(shuffled, sorted);
+    }
+
+    #[test]
+    pub fn equality_and_hash() {
+        // Equal values hash alike, whatever the order of map entries.
+        let pairs = [
+            (int(7u8), Value::int(crate::vm::PrimOpKind::U8, 0x107)),
+            (
+                map(&[("a", int(1i64)), ("b", str("x"))]),
+                map(&[("b", str("x")), ("a", int(1i64))]),
+            ),
+            (
+                array(vec![atom("ok"), Value::Null]),
+                array(vec![atom("ok"), Value::Null]),
+            ),
+            (
+                dict(&[(int(1u8), atom("a")), (str("b"), Value::Null)]),
+                dict(&[(str("b"), Value::Null), (int(1u8), atom("a"))]),
+            ),
+        ];
+        for (a, b) in &pairs {
+            assert_eq!(a, b);
+            assert_eq!(a.structural_hash(), b.structural_hash());
+        }
+        assert_ne!(int(7u8), int(7u16));
+        assert_ne!(int(7u8).structural_hash(), int(7u16).structural_hash());
+        assert_ne!(atom("ok"), str("ok"));
+        assert_ne!(atom("ok").structural_hash(), str("ok").structural_hash());
+        // Stable across targets and VMs.
+        assert_eq!(int(7u8).structural_hash(), 0x3233_1fbf_92b7_8323);
+    }
+
+    #[test]
+    pub fn cycles() {
+        // a = [1, a] and b = [1, [1, b]] unfold to the same thing.
+        let a = PVObject::make_array().unwrap();
+        let b = PVObject::make_array().unwrap();
+        let b_inner = array(vec![int(1i64), Value::Object(b.clone())]);
+        if let PVObjectType::Array(v) = &mut *a.get_mut() {
+            v.extend([int(1i64), Value::Object(a.clone())]);
+        };
+        if let PVObjectType::Array(v) = &mut *b.get_mut() {
+            v.extend([int(1i64), b_inner]);
+        };
+        let (va, vb) = (Value::Object(a.clone()), Value::Object(b.clone()));
+        assert_eq!(va, vb);
+        assert_eq!(va.structural_hash(), vb.structural_hash());
+
+        // c = [2, c] doesn't.
+        let c = PVObject::make_array().unwrap();
+        if let PVObjectType::Array(v) = &mut *c.get_mut() {
+            v.extend([int(2i64), Value::Object(c.clone())]);
+        };
+        assert_eq!(va.cmp(&Value::Object(c.clone())), Ordering::Less);
+
+        for o in [a, b, c] {
+            if let PVObjectType::Array(v) = &mut *o.get_mut() {
+                v.clear();
+            };
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use core::hash::{BuildHasher, Hash};
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use indexmap::IndexMap;
+
+use super::{
+    error::{VmError, VmResult},
+    statics, Atom, PVObject, PVObjectType, PVString, PrimOpKind, Value, ValueKind,
+};
+
+/// Conversion of host data into a [Value].
+pub trait IntoValue {
+    fn into_value(self) -> VmResult<Value>;
+}
+
+/// Conversion of a [Value] back into host data. Fails, rather than panics, on values of the
+/// wrong kind or out of range.
+pub trait FromValue: Sized {
+    fn from_value(v: &Value) -> VmResult<Self>;
+}
+
+/// Types usable as keys of map objects.
+pub trait MapKey: Sized {
+    fn into_key(self) -> VmResult<PVString>;
+    fn from_key(k: &PVString) -> VmResult<Self>;
+}
+
+fn mismatch(expected: ValueKind, found: &Value) -> VmError {
+    VmError::PopExpectedType {
+        expected,
+        found: found.kind(),
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> VmResult<Value> {
+        Ok(self)
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(v: &Value) -> VmResult<Self> {
+        Ok(v.clone())
+    }
+}
+
+impl IntoValue for PVObject {
+    fn into_value(self) -> VmResult<Value> {
+        Ok(Value::Object(self))
+    }
+}
+
+impl FromValue for PVObject {
+    fn from_value(v: &Value) -> VmResult<Self> {
+        match v {
+            Value::Object(o) => Ok(o.clone()),
+            _ => Err(mismatch(ValueKind::Object, v)),
+        }
+    }
+}
+
+macro_rules! int_conversions {
+    ($($t:ty => $k:ident),* $(,)?) => {
+        $(
+            impl IntoValue for $t {
+                fn into_value(self) -> VmResult<Value> {
+                    Ok(Value::from(self, PrimOpKind::$k))
+                }
+            }
+
+            impl FromValue for $t {
+                fn from_value(v: &Value) -> VmResult<Self> {
+                    let i = v.as_i128().ok_or_else(|| mismatch(ValueKind::Int, v))?;
+                    <$t>::try_from(i).map_err(|_| VmError::IntOutOfRange {
+                        value: i,
+                        target: PrimOpKind::$k,
+                    })
+                }
+            }
+        )*
+    };
+}
+
+int_conversions! {
+    u8 => U8,
+    i8 => I8,
+    u16 => U16,
+    i16 => I16,
+    u32 => U32,
+    i32 => I32,
+    u64 => U64,
+    i64 => I64,
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> VmResult<Value> {
+        (if self { statics::TRUE } else { statics::FALSE }).into_value()
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(v: &Value) -> VmResult<Self> {
+        match Atom::from_value(v)? {
+            statics::TRUE => Ok(true),
+            statics::FALSE => Ok(false),
+            _ => Err(mismatch(ValueKind::Atom, v)),
+        }
+    }
+}
+
+impl IntoValue for Atom {
+    fn into_value(self) -> VmResult<Value> {
+        Ok(Value::Object(PVObject::from(self)))
+    }
+}
+
+impl FromValue for Atom {
+    fn from_value(v: &Value) -> VmResult<Self> {
+        if let Value::Object(o) = v {
+            if let PVObjectType::String(PVString::Atom(a)) = &*o.get() {
+                return Ok(*a);
+            }
+        }
+        Err(mismatch(ValueKind::Atom, v))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> VmResult<Value> {
+        PVString::Str(self.to_string()).into_value()
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> VmResult<Value> {
+        PVString::Str(self).into_value()
+    }
+}
+
+/// Atoms convert to their name.
+impl FromValue for String {
+    fn from_value(v: &Value) -> VmResult<Self> {
+        match PVString::from_value(v)? {
+            PVString::Str(s) => Ok(s),
+            PVString::Atom(a) => Ok(<&str>::from(a).to_string()),
+        }
+    }
+}
+
+impl IntoValue for PVString {
+    fn into_value(self) -> VmResult<Value> {
+        Ok(Value::Object(PVObject::make_string(self)?))
+    }
+}
+
+impl FromValue for PVString {
+    fn from_value(v: &Value) -> VmResult<Self> {
+        if let Value::Object(o) = v {
+            if let PVObjectType::String(s) = &*o.get() {
+                return Ok(s.clone());
+            }
+        }
+        Err(mismatch(ValueKind::String, v))
+    }
+}
+
+/// None is [Value::Null].
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> VmResult<Value> {
+        match self {
+            Some(v) => v.into_value(),
+            None => Ok(Value::Null),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(v: &Value) -> VmResult<Self> {
+        match v {
+            Value::Null => Ok(None),
+            v => T::from_value(v).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> VmResult<Value> {
+        let mut elems = Vec::new();
+        elems.try_reserve_exact(self.len())?;
+        for v in self {
+            elems.push(v.into_value()?);
+        }
+        Ok(Value::Object(PVObject::make_array_from(elems)?))
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(v: &Value) -> VmResult<Self> {
+        if let Value::Object(o) = v {
+            if let PVObjectType::Array(elems) = &*o.get() {
+                let mut out = Vec::new();
+                out.try_reserve_exact(elems.len())?;
+                for e in elems {
+                    out.push(T::from_value(e)?);
+                }
+                return Ok(out);
+            }
+        }
+        Err(mismatch(ValueKind::Array, v))
+    }
+}
+
+// Tuples are fixed length arrays.
+macro_rules! tuple_conversions {
+    ($(($len:literal; $($t:ident $i:tt),+)),* $(,)?) => {
+        $(
+            impl<$($t: IntoValue),+> IntoValue for ($($t,)+) {
+                fn into_value(self) -> VmResult<Value> {
+                    let mut elems = Vec::new();
+                    elems.try_reserve_exact($len)?;
+                    $(elems.push(self.$i.into_value()?);)+
+                    Ok(Value::Object(PVObject::make_array_from(elems)?))
+                }
+            }
+
+            impl<$($t: FromValue),+> FromValue for ($($t,)+) {
+                fn from_value(v: &Value) -> VmResult<Self> {
+                    if let Value::Object(o) = v {
+                        if let PVObjectType::Array(elems) = &*o.get() {
+                            if elems.len() != $len {
+                                return Err(VmError::WrongLength {
+                                    expected: $len,
+                                    found: elems.len(),
+                                });
+                            }
+                            return Ok(($($t::from_value(&elems[$i])?,)+));
+                        }
+                    }
+                    Err(mismatch(ValueKind::Array, v))
+                }
+            }
+        )*
+    };
+}
+
+tuple_conversions! {
+    (1; A 0),
+    (2; A 0, B 1),
+    (3; A 0, B 1, C 2),
+    (4; A 0, B 1, C 2, D 3),
+    (5; A 0, B 1, C 2, D 3, E 4),
+    (6; A 0, B 1, C 2, D 3, E 4, F 5),
+}
+
+impl MapKey for PVString {
+    fn into_key(self) -> VmResult<PVString> {
+        Ok(self)
+    }
+
+    fn from_key(k: &PVString) -> VmResult<Self> {
+        Ok(k.clone())
+    }
+}
+
+impl MapKey for Atom {
+    fn into_key(self) -> VmResult<PVString> {
+        Ok(PVString::Atom(self))
+    }
+
+    fn from_key(k: &PVString) -> VmResult<Self> {
+        match k {
+            PVString::Atom(a) => Ok(*a),
+            PVString::Str(_) => Err(VmError::PopExpectedType {
+                expected: ValueKind::Atom,
+                found: ValueKind::String,
+            }),
+        }
+    }
+}
+
+/// Atom keys convert to their name.
+impl MapKey for String {
+    fn into_key(self) -> VmResult<PVString> {
+        Ok(PVString::Str(self))
+    }
+
+    fn from_key(k: &PVString) -> VmResult<Self> {
+        match k {
+            PVString::Atom(a) => Ok(<&str>::from(*a).to_string()),
+            PVString::Str(s) => Ok(s.clone()),
+        }
+    }
+}
+
+fn map_into_value<K: MapKey, V: IntoValue>(
+    entries: impl Iterator<Item = (K, V)>,
+) -> VmResult<Value> {
+    let obj = PVObject::make_map()?;
+    {
+        let mut map = obj.get_mut();
+        if let PVObjectType::Map(m) = &mut *map {
+            for (k, v) in entries {
+                m.try_reserve(1)
+                    .map_err(|_| VmError::MemoryAllocFailed(core::alloc::AllocError))?;
+                m.insert(k.into_key()?, v.into_value()?);
+            }
+        }
+    }
+    Ok(Value::Object(obj))
+}
+
+fn map_from_value<K: MapKey, V: FromValue>(
+    v: &Value,
+    mut insert: impl FnMut(K, V),
+) -> VmResult<()> {
+    if let Value::Object(o) = v {
+        if let PVObjectType::Map(m) = &*o.get() {
+            for (k, v) in m {
+                insert(K::from_key(k)?, V::from_value(v)?);
+            }
+            return Ok(());
+        }
+    }
+    Err(mismatch(ValueKind::Map, v))
+}
+
+impl<K: MapKey, V: IntoValue> IntoValue for BTreeMap<K, V> {
+    fn into_value(self) -> VmResult<Value> {
+        map_into_value(self.into_iter())
+    }
+}
+
+impl<K: MapKey + Ord, V: FromValue> FromValue for BTreeMap<K, V> {
+    fn from_value(v: &Value) -> VmResult<Self> {
+        let mut out = BTreeMap::new();
+        map_from_value(v, |k, v| {
+            out.insert(k, v);
+        })?;
+        Ok(out)
+    }
+}
+
+impl<K: MapKey, V: IntoValue, S> IntoValue for IndexMap<K, V, S> {
+    fn into_value(self) -> VmResult<Value> {
+        map_into_value(self.into_iter())
+    }
+}
+
+impl<K: MapKey + Hash + Eq, V: FromValue, S: BuildHasher + Default> FromValue
+    for IndexMap<K, V, S>
+{
+    fn from_value(v: &Value) -> VmResult<Self> {
+        let mut out = IndexMap::default();
+        map_from_value(v, |k, v| {
+            out.insert(k, v);
+        })?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+
+    use super::{FromValue, IntoValue};
+    use crate::vm::{statics, Atom, PVObject, PrimOpKind, RecordReader, ValueKind, VmError};
+
+    #[test]
+    pub fn round_trips() {
+        let v = (1u8, -2i64, "three", vec![Some(4u32), None], statics::OK)
+            .into_value()
+            .unwrap();
+        let back = <(u8, i64, String, Vec<Option<u32>>, Atom)>::from_value(&v).unwrap();
+        assert_eq!(
+            back,
+            (
+                1,
+                -2,
+                String::from("three"),
+                vec![Some(4), None],
+                statics::OK
+            )
+        );
+
+        let mut m = BTreeMap::new();
+        m.insert(String::from("a"), vec![true, false]);
+        m.insert(String::from("b"), vec![]);
+        let v = m.clone().into_value().unwrap();
+        assert_eq!(BTreeMap::<String, Vec<bool>>::from_value(&v).unwrap(), m);
+    }
+
+    #[test]
+    pub fn errors() {
+        let v = 300u32.into_value().unwrap();
+        assert_eq!(u16::from_value(&v).unwrap(), 300);
+        assert!(matches!(
+            u8::from_value(&v),
+            Err(VmError::IntOutOfRange { value: 300, .. })
+        ));
+        assert!(matches!(
+            String::from_value(&v),
+            Err(VmError::PopExpectedType { .. })
+        ));
+        assert!(matches!(
+            PVObject::from_value(&v),
+            Err(VmError::PopExpectedType {
+                expected: ValueKind::Object,
+                found: ValueKind::Int
+            })
+        ));
+
+        // Reported values wider than 64 bits clamp, and say so.
+        for (value, reported, clamped) in [
+            (300, 300u64, false),
+            (i128::MAX, u64::MAX, true),
+        ] {
+            let err = VmError::IntOutOfRange {
+                value,
+                target: PrimOpKind::U8,
+            };
+            let v = err.to_value().unwrap();
+            let r = RecordReader::new(&v).unwrap();
+            assert_eq!(r.field::<u64>(statics::VALUE, "value").unwrap(), reported);
+            assert_eq!(r.field::<bool>(statics::CLAMPED, "clamped").unwrap(), clamped);
+        }
+        let v = VmError::IntOutOfRange {
+            value: i128::MIN,
+            target: PrimOpKind::I8,
+        }
+        .to_value()
+        .unwrap();
+        let r = RecordReader::new(&v).unwrap();
+        assert_eq!(r.field::<i64>(statics::VALUE, "value").unwrap(), i64::MIN);
+
+        let v = (1u8, 2u8).into_value().unwrap();
+        assert!(matches!(
+            <(u8, u8, u8)>::from_value(&v),
+            Err(VmError::WrongLength {
+                expected: 3,
+                found: 2
+            })
+        ));
+    }
+}
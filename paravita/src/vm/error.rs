@@ -1,23 +1,243 @@
 use core::{
-    any::TypeId,
     error::Error,
-    fmt::{Debug, Display}, alloc::AllocError,
+    fmt::Display, alloc::AllocError,
+    net::Ipv6Addr,
 };
 
-use alloc::collections::TryReserveError;
+use alloc::{boxed::Box, collections::TryReserveError, string::String, vec::Vec};
+
+use super::{
+    statics, Atom, IntoValue, Operation, PVObject, PVString, PrimOpKind, Value, ValueKind,
+};
 
 pub type VmResult<T> = core::result::Result<T, VmError>;
 
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum VmError {
-    StackUnderflow(),
-    PopExpectedType(),
-    PopExpectedObject(),
-    PopExpectedArray(),
-    PopExpectedUserData(),
+    /// An operation needed more values than the stack holds.
+    StackUnderflow { needed: usize, depth: usize },
+    /// An operation popped a value of the wrong kind.
+    PopExpectedType { expected: ValueKind, found: ValueKind },
     MemoryReserveFailed(TryReserveError),
     MemoryAllocFailed(AllocError),
+    MalformedAtomTable(),
+    UnknownAtom(u32),
+    DivideByZero(),
+    /// An EndTry ran with no handler installed.
+    UnbalancedTry(),
+    /// A value raised by Throw.
+    Thrown(Value),
+    /// A Trap operation ran, at this offset.
+    Trap(usize),
+    /// A Syscall for a number the host hasn't installed a handler for.
+    UnknownSyscall(u16),
+    /// An int didn't fit the kind it was converted to.
+    IntOutOfRange { value: i128, target: PrimOpKind },
+    /// An array had the wrong number of elements.
+    WrongLength { expected: usize, found: usize },
+    NoSuchProcess(Ipv6Addr),
+    /// A record was missing a required field.
+    MissingField(&'static str),
+    /// A record field failed to convert.
+    BadField { field: &'static str, error: Box<VmError> },
+    /// A tagged record named a variant the target enum doesn't have.
+    UnknownVariant(Atom),
+    /// A module failed to decode or validate, for the given reason.
+    MalformedModule(&'static str),
+    /// A call named a function that isn't loaded or exported.
+    UndefinedFunction { module: Atom, function: Atom, arity: u16 },
+    /// Purging old code would have killed this many processes still using it, or reset globals
+    /// holding closures of it.
+    CodeInUse { module: Atom, processes: usize },
+    /// A local slot past the ones the running function has.
+    NoSuchLocal(u16),
+    /// Strict verification found a path that reads a local before writing it.
+    UnassignedLocal { function: Atom, pc: usize, local: u16 },
+    /// A function value was called with the wrong number of arguments.
+    BadArity { expected: u16, found: u16 },
+    /// A process held more memory than its scheduler allows, see
+    /// [Process::heap_size](super::Process::heap_size).
+    HeapQuotaExceeded { used: usize, quota: usize },
+    /// A range of `count` bytes or elements at `start` went past the end of an object
+    /// `length` long.
+    OutOfBounds { start: usize, count: usize, length: usize },
+    /// Calling into a process stopped partway through its code would throw away the rest of it.
+    ProcessSuspended(Ipv6Addr),
 }
 
+impl VmError {
+    /// A stable numeric code for this error, for hosts to match on and show to users.
+    /// Codes are never reused or renumbered, new errors get new codes.
+    pub fn code(&self) -> u16 {
+        match self {
+            VmError::StackUnderflow { .. } => 1,
+            VmError::PopExpectedType { .. } => 2,
+            VmError::MemoryReserveFailed(_) => 3,
+            VmError::MemoryAllocFailed(_) => 4,
+            VmError::MalformedAtomTable() => 5,
+            VmError::UnknownAtom(_) => 6,
+            VmError::DivideByZero() => 7,
+            VmError::UnbalancedTry() => 8,
+            VmError::Thrown(_) => 9,
+            VmError::Trap(_) => 10,
+            VmError::UnknownSyscall(_) => 11,
+            VmError::IntOutOfRange { .. } => 12,
+            VmError::WrongLength { .. } => 13,
+            VmError::NoSuchProcess(_) => 14,
+            VmError::MissingField(_) => 15,
+            VmError::BadField { .. } => 16,
+            VmError::UnknownVariant(_) => 17,
+            VmError::MalformedModule(_) => 18,
+            VmError::UndefinedFunction { .. } => 19,
+            VmError::CodeInUse { .. } => 20,
+            VmError::NoSuchLocal(_) => 21,
+            VmError::UnassignedLocal { .. } => 22,
+            VmError::BadArity { .. } => 23,
+            VmError::HeapQuotaExceeded { .. } => 24,
+            VmError::OutOfBounds { .. } => 25,
+            VmError::ProcessSuspended(_) => 26,
+        }
+    }
+
+    /// The atom identifying this error in exception values.
+    pub fn tag(&self) -> Atom {
+        match self {
+            VmError::StackUnderflow { .. } => statics::STACK_UNDERFLOW,
+            VmError::PopExpectedType { .. } => statics::TYPE_MISMATCH,
+            VmError::MemoryReserveFailed(_) => statics::OUT_OF_MEMORY,
+            VmError::MemoryAllocFailed(_) => statics::OUT_OF_MEMORY,
+            VmError::MalformedAtomTable() => statics::BAD_ATOM_TABLE,
+            VmError::UnknownAtom(_) => statics::UNKNOWN_ATOM,
+            VmError::DivideByZero() => statics::DIVIDE_BY_ZERO,
+            VmError::UnbalancedTry() => statics::UNBALANCED_TRY,
+            VmError::Thrown(_) => statics::THROW,
+            VmError::Trap(_) => statics::TRAP,
+            VmError::UnknownSyscall(_) => statics::UNKNOWN_SYSCALL,
+            VmError::IntOutOfRange { .. } => statics::INT_OUT_OF_RANGE,
+            VmError::WrongLength { .. } => statics::WRONG_LENGTH,
+            VmError::NoSuchProcess(_) => statics::NO_SUCH_PROCESS,
+            VmError::MissingField(_) => statics::MISSING_FIELD,
+            VmError::BadField { .. } => statics::BAD_FIELD,
+            VmError::UnknownVariant(_) => statics::UNKNOWN_VARIANT,
+            VmError::MalformedModule(_) => statics::BAD_MODULE,
+            VmError::UndefinedFunction { .. } => statics::UNDEFINED_FUNCTION,
+            VmError::CodeInUse { .. } => statics::CODE_IN_USE,
+            VmError::NoSuchLocal(_) => statics::NO_SUCH_LOCAL,
+            VmError::UnassignedLocal { .. } => statics::UNASSIGNED_LOCAL,
+            VmError::BadArity { .. } => statics::BAD_ARITY,
+            VmError::HeapQuotaExceeded { .. } => statics::HEAP_QUOTA_EXCEEDED,
+            VmError::OutOfBounds { .. } => statics::OUT_OF_BOUNDS,
+            VmError::ProcessSuspended(_) => statics::PROCESS_SUSPENDED,
+        }
+    }
+
+    /// Converts this error into the value a handler catches. Thrown values are caught as-is,
+    /// errors raised by the VM become a map of `error` (the [tag](VmError::tag)), `code` and
+    /// any details.
+    pub fn to_value(&self) -> VmResult<Value> {
+        if let VmError::Thrown(v) = self {
+            return Ok(v.clone());
+        }
+
+        let obj = PVObject::make_map()?;
+        {
+            let mut map = obj.get_mut();
+            let map = &mut *map;
+            map.set_field(statics::ERROR, Value::Object(PVObject::from(self.tag())))?;
+            map.set_field(statics::CODE, self.code().into())?;
+            match self {
+                VmError::StackUnderflow { needed, depth } => {
+                    map.set_field(statics::NEEDED, (*needed as u64).into())?;
+                    map.set_field(statics::DEPTH, (*depth as u64).into())?;
+                }
+                VmError::PopExpectedType { expected, found } => {
+                    let expected = Atom::from(expected.name());
+                    let found = Atom::from(found.name());
+                    map.set_field(statics::EXPECTED, Value::Object(PVObject::from(expected)))?;
+                    map.set_field(statics::FOUND, Value::Object(PVObject::from(found)))?;
+                }
+                VmError::UnknownAtom(h) => {
+                    map.set_field(statics::HANDLE, (*h).into())?;
+                }
+                VmError::Trap(pc) => {
+                    map.set_field(statics::PC, (*pc as u64).into())?;
+                }
+                VmError::UnknownSyscall(n) => {
+                    map.set_field(statics::SYSCALL, (*n).into())?;
+                }
+                VmError::IntOutOfRange { value, target } => {
+                    // Values are at most 64 bits wide, wider ones clamp and say so.
+                    let (value, clamped) = if let Ok(v) = i64::try_from(*value) {
+                        (v.into(), false)
+                    } else if let Ok(v) = u64::try_from(*value) {
+                        (v.into(), false)
+                    } else if *value < 0 {
+                        (i64::MIN.into(), true)
+                    } else {
+                        (u64::MAX.into(), true)
+                    };
+                    let target = Atom::from(target.name());
+                    map.set_field(statics::VALUE, value)?;
+                    map.set_field(statics::CLAMPED, clamped.into_value()?)?;
+                    map.set_field(statics::TARGET, Value::Object(PVObject::from(target)))?;
+                }
+                VmError::WrongLength { expected, found } => {
+                    map.set_field(statics::EXPECTED, (*expected as u64).into())?;
+                    map.set_field(statics::FOUND, (*found as u64).into())?;
+                }
+                VmError::MissingField(field) => {
+                    map.set_field(statics::FIELD, Value::Object(PVObject::from(Atom::from(*field))))?;
+                }
+                VmError::BadField { field, error } => {
+                    map.set_field(statics::FIELD, Value::Object(PVObject::from(Atom::from(*field))))?;
+                    map.set_field(statics::REASON, error.to_value()?)?;
+                }
+                VmError::UnknownVariant(tag) => {
+                    map.set_field(statics::TAG, Value::Object(PVObject::from(*tag)))?;
+                }
+                VmError::MalformedModule(reason) => {
+                    let reason = PVObject::make_string(PVString::Str(String::from(*reason)))?;
+                    map.set_field(statics::REASON, Value::Object(reason))?;
+                }
+                VmError::UndefinedFunction { module, function, arity } => {
+                    map.set_field(statics::MODULE, Value::Object(PVObject::from(*module)))?;
+                    map.set_field(statics::FUNCTION, Value::Object(PVObject::from(*function)))?;
+                    map.set_field(statics::ARITY, (*arity).into())?;
+                }
+                VmError::CodeInUse { module, processes } => {
+                    map.set_field(statics::MODULE, Value::Object(PVObject::from(*module)))?;
+                    map.set_field(statics::PROCESSES, (*processes as u64).into())?;
+                }
+                VmError::NoSuchLocal(local) => {
+                    map.set_field(statics::LOCAL, (*local).into())?;
+                }
+                VmError::UnassignedLocal { function, pc, local } => {
+                    map.set_field(statics::FUNCTION, Value::Object(PVObject::from(*function)))?;
+                    map.set_field(statics::PC, (*pc as u64).into())?;
+                    map.set_field(statics::LOCAL, (*local).into())?;
+                }
+                VmError::BadArity { expected, found } => {
+                    map.set_field(statics::EXPECTED, (*expected).into())?;
+                    map.set_field(statics::FOUND, (*found).into())?;
+                }
+                VmError::HeapQuotaExceeded { used, quota } => {
+                    map.set_field(statics::USED, (*used as u64).into())?;
+                    map.set_field(statics::QUOTA, (*quota as u64).into())?;
+                }
+                VmError::OutOfBounds { start, count, length } => {
+                    map.set_field(statics::START, (*start as u64).into())?;
+                    map.set_field(statics::COUNT, (*count as u64).into())?;
+                    map.set_field(statics::LENGTH, (*length as u64).into())?;
+                }
+                _ => {}
+            }
+        }
+        Ok(Value::Object(obj))
+    }
+}
+
+
 impl Error for VmError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
@@ -39,21 +259,139 @@ impl Error for VmError {
 
 impl Display for VmError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            VmError::StackUnderflow { needed, depth } => {
+                write!(f, "Process stack underflow, needed {needed} values but the stack holds {depth}.")
+            }
+            VmError::PopExpectedType { expected, found } => {
+                write!(f, "VM expected {expected} on pop, found {found}.")
+            }
+            VmError::MemoryReserveFailed(e) => write!(f, "{e}"),
+            VmError::MemoryAllocFailed(e) => write!(f, "{e}"),
+            VmError::MalformedAtomTable() => write!(f, "Malformed atom table."),
+            VmError::UnknownAtom(h) => write!(f, "Atom handle {h} is not in the atom table."),
+            VmError::DivideByZero() => write!(f, "Division by zero."),
+            VmError::UnbalancedTry() => write!(f, "EndTry without a matching Try."),
+            VmError::Thrown(v) => write!(f, "Uncaught exception {v}."),
+            VmError::Trap(pc) => write!(f, "Trap at offset {pc}."),
+            VmError::UnknownSyscall(n) => write!(f, "No handler installed for syscall {n}."),
+            VmError::IntOutOfRange { value, target } => {
+                write!(f, "{value} is out of range for {}.", target.name())
+            }
+            VmError::WrongLength { expected, found } => {
+                write!(f, "Expected {expected} elements, found {found}.")
+            }
+            VmError::NoSuchProcess(pid) => write!(f, "No process {pid}."),
+            VmError::MissingField(field) => write!(f, "Missing field `{field}`."),
+            VmError::BadField { field, error } => write!(f, "In field `{field}`: {error}"),
+            VmError::UnknownVariant(tag) => write!(f, "Unknown variant `{}`.", <&str>::from(*tag)),
+            VmError::MalformedModule(reason) => write!(f, "Malformed module: {reason}."),
+            VmError::UndefinedFunction { module, function, arity } => write!(
+                f,
+                "Undefined function {}:{}/{arity}.",
+                <&str>::from(*module),
+                <&str>::from(*function)
+            ),
+            VmError::CodeInUse { module, processes } => write!(
+                f,
+                "Old code of {} is still used by {processes} processes or by globals.",
+                <&str>::from(*module)
+            ),
+            VmError::NoSuchLocal(local) => write!(f, "No local slot {local} in this frame."),
+            VmError::UnassignedLocal { function, pc, local } => write!(
+                f,
+                "Local {local} may be read before it's written, in {} at pc {pc}.",
+                <&str>::from(*function)
+            ),
+            VmError::BadArity { expected, found } => {
+                write!(f, "Function takes {expected} arguments, called with {found}.")
+            }
+            VmError::HeapQuotaExceeded { used, quota } => {
+                write!(f, "Process holds {used} bytes, over its quota of {quota}.")
+            }
+            VmError::OutOfBounds { start, count, length } => {
+                write!(f, "{count} at offset {start} is out of bounds for length {length}.")
+            }
+            VmError::ProcessSuspended(pid) => {
+                write!(f, "Process {pid} is suspended, resume it to the end first.")
+            }
+        }
     }
 }
 
-impl Debug for VmError {
+/// A [VmError] along with where in a process it happened.
+#[derive(Debug)]
+pub struct VmFault {
+    pub error: VmError,
+    /// The process that faulted.
+    pub pid: Ipv6Addr,
+    /// Offset of the faulting operation in the code being run.
+    pub pc: usize,
+    /// The faulting operation, None if the fault came from the host side, like a failed conversion.
+    pub op: Option<Operation>,
+    /// The module functions that were running, innermost first. Empty for code run directly.
+    pub trace: Vec<CallSite>,
+}
+
+/// A function activation in a [VmFault] backtrace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallSite {
+    pub module: Atom,
+    pub function: Atom,
+    pub arity: u16,
+    /// Offset of the running operation in the function, the call for outer frames.
+    pub pc: usize,
+}
+
+impl VmFault {
+    /// A fault from host-side work on a process rather than from running an operation.
+    pub fn host(pid: Ipv6Addr, error: VmError) -> VmFault {
+        VmFault {
+            error,
+            pid,
+            pc: 0,
+            op: None,
+            trace: Vec::new(),
+        }
+    }
+
+    /// See [VmError::code].
+    pub fn code(&self) -> u16 {
+        self.error.code()
+    }
+}
+
+impl Error for VmFault {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl Display for VmFault {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        match self {
-            VmError::StackUnderflow() => write!(f, "Process stack underflow."),
-            VmError::PopExpectedType() => write!(f, "VM expected value on pop."),
-            VmError::PopExpectedObject() => write!(f, "VM expected object on pop."),
-            VmError::PopExpectedArray() => write!(f, "VM expected array on pop."),
-            VmError::PopExpectedUserData() => write!(f, "VM expected userdata on pop."),
-            VmError::MemoryReserveFailed(e) => write!(f, "{e:?}"),
-            VmError::MemoryAllocFailed(e) => write!(f, "{e:?}"),
+        match self.op {
+            Some(op) => write!(
+                f,
+                "[E{:04}] {} (process {}, pc {}, {})",
+                self.code(),
+                self.error,
+                self.pid,
+                self.pc,
+                op
+            ),
+            None => write!(f, "[E{:04}] {} (process {})", self.code(), self.error, self.pid),
+        }?;
+        for site in &self.trace {
+            write!(
+                f,
+                "\n    in {}:{}/{} at pc {}",
+                <&str>::from(site.module),
+                <&str>::from(site.function),
+                site.arity,
+                site.pc
+            )?;
         }
+        Ok(())
     }
 }
 
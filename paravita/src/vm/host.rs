@@ -0,0 +1,806 @@
+use core::net::Ipv6Addr;
+
+use core::cell::RefCell;
+
+use alloc::{rc::Rc, vec::Vec};
+use fnv::FnvBuildHasher;
+use indexmap::IndexMap;
+
+use super::{
+    error::{VmError, VmFault, VmResult},
+    Atom, FromValue, Import, IntoValue, Module, Operation, OutputDevice, Process, Registry,
+    RunStatus, SyscallTable, Value,
+};
+
+/// Owns a set of processes and moves typed data in and out of them. This is the entry point for
+/// embedding the VM in a host.
+pub struct Vm {
+    prefix: Ipv6Addr,
+    processes: IndexMap<Ipv6Addr, Process, FnvBuildHasher>,
+    syscalls: Option<Rc<SyscallTable>>,
+    output: Option<Rc<dyn OutputDevice>>,
+    registry: Rc<RefCell<Registry>>,
+}
+
+impl Vm {
+    /// Creates a VM whose processes get pids under the given prefix.
+    pub fn new(prefix: Ipv6Addr) -> Vm {
+        Vm {
+            prefix,
+            processes: IndexMap::default(),
+            syscalls: None,
+            output: None,
+            registry: Rc::new(RefCell::new(Registry::new())),
+        }
+    }
+
+    /// Installs the syscall table for every process, current and future.
+    pub fn set_syscalls(&mut self, table: SyscallTable) -> VmResult<()> {
+        let table = Rc::try_new(table)?;
+        for p in self.processes.values_mut() {
+            p.set_syscalls(table.clone());
+        }
+        self.syscalls = Some(table);
+        Ok(())
+    }
+
+    /// Installs the device DebugOut writes to, for every process, current and future.
+    pub fn set_output(&mut self, device: Rc<dyn OutputDevice>) {
+        for p in self.processes.values_mut() {
+            p.set_output(device.clone());
+        }
+        self.output = Some(device);
+    }
+
+    /// Validates and loads a module, returning its name. See [Registry::load].
+    pub fn load(&mut self, module: Module) -> VmResult<Atom> {
+        Ok(self.registry.borrow_mut().load(module)?.name())
+    }
+
+    /// Decodes, validates and loads a module written by [Module::to_bytes].
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> VmResult<Atom> {
+        self.load(Module::from_bytes(bytes)?)
+    }
+
+    /// Makes a host function callable as `module:function/arity`. See [Registry::install_native].
+    pub fn install_native<F>(&mut self, module: Atom, function: Atom, arity: u16, f: F)
+    where
+        F: Fn(&mut Process) -> VmResult<()> + 'static,
+    {
+        self.registry
+            .borrow_mut()
+            .install_native(module, function, arity, f);
+    }
+
+    /// The processes using old versions of a module, running them or holding closures of them.
+    /// See [Registry].
+    pub fn lingering(&self, module: Atom) -> Vec<Ipv6Addr> {
+        let old = self.registry.borrow().old_versions(module);
+        if old.is_empty() {
+            return Vec::new();
+        }
+        self.processes
+            .values()
+            .filter(|p| p.uses(&old))
+            .map(|p| p.pid())
+            .collect()
+    }
+
+    /// Purges the old versions of a module if nothing uses them any more, but for their own
+    /// globals. Fails with [VmError::CodeInUse] if a process or the globals of another module
+    /// still do, killing and resetting nothing.
+    pub fn soft_purge(&self, module: Atom) -> VmResult<()> {
+        let registry = self.registry.borrow();
+        let old = registry.old_versions(module);
+        let processes = self.lingering(module).len();
+        if processes > 0 || registry.globals_reach(&old) {
+            return Err(VmError::CodeInUse { module, processes });
+        }
+        registry.release(&old);
+        Ok(())
+    }
+
+    /// Purges the old versions of a module, killing the processes still using them and
+    /// resetting the globals holding closures of them to null. Returns the pids of the
+    /// processes killed.
+    pub fn purge(&mut self, module: Atom) -> Vec<Ipv6Addr> {
+        let pids = self.lingering(module);
+        for pid in &pids {
+            self.kill(*pid);
+        }
+        let registry = self.registry.borrow();
+        registry.release(&registry.old_versions(module));
+        pids
+    }
+
+    /// The modules and natives loaded into this VM.
+    pub fn registry(&self) -> &Rc<RefCell<Registry>> {
+        &self.registry
+    }
+
+    /// Starts a new, empty process and returns its pid.
+    pub fn spawn(&mut self) -> VmResult<Ipv6Addr> {
+        let mut p = Process::new(self.prefix)?;
+        if let Some(t) = &self.syscalls {
+            p.set_syscalls(t.clone());
+        }
+        if let Some(d) = &self.output {
+            p.set_output(d.clone());
+        }
+        p.set_registry(self.registry.clone());
+        let pid = p.pid();
+        self.processes
+            .try_reserve(1)
+            .map_err(|_| VmError::MemoryAllocFailed(core::alloc::AllocError))?;
+        self.processes.insert(pid, p);
+        Ok(pid)
+    }
+
+    /// Removes a process from the VM, handing it back.
+    pub fn kill(&mut self, pid: Ipv6Addr) -> Option<Process> {
+        self.processes.shift_remove(&pid)
+    }
+
+    pub fn process(&self, pid: Ipv6Addr) -> Option<&Process> {
+        self.processes.get(&pid)
+    }
+
+    pub fn process_mut(&mut self, pid: Ipv6Addr) -> Option<&mut Process> {
+        self.processes.get_mut(&pid)
+    }
+
+    /// The pids of every process, in spawn order.
+    pub fn pids(&self) -> impl Iterator<Item = Ipv6Addr> + '_ {
+        self.processes.keys().copied()
+    }
+
+    fn get_mut(&mut self, pid: Ipv6Addr) -> VmResult<&mut Process> {
+        self.processes
+            .get_mut(&pid)
+            .ok_or(VmError::NoSuchProcess(pid))
+    }
+
+    /// Pushes a value onto a process's stack.
+    pub fn push<T: IntoValue>(&mut self, pid: Ipv6Addr, v: T) -> VmResult<()> {
+        let v = v.into_value()?;
+        self.get_mut(pid)?.push(v);
+        Ok(())
+    }
+
+    /// Pops a value off a process's stack. The value is consumed even if it fails to convert.
+    pub fn pop<T: FromValue>(&mut self, pid: Ipv6Addr) -> VmResult<T> {
+        let v = self.get_mut(pid)?.pop()?;
+        T::from_value(&v)
+    }
+
+    /// A process that isn't partway through its code, so it can start something else.
+    fn get_idle(&mut self, pid: Ipv6Addr) -> Result<&mut Process, VmFault> {
+        let p = self.get_mut(pid).map_err(|e| VmFault::host(pid, e))?;
+        p.check_idle().map_err(|e| VmFault::host(pid, e))?;
+        Ok(p)
+    }
+
+    /// Runs a program on a process. Like every call into a process, this fails with
+    /// [VmError::ProcessSuspended] while it's partway through other code, see [Vm::resume].
+    pub fn run(&mut self, pid: Ipv6Addr, code: &[Operation]) -> Result<(), VmFault> {
+        self.get_idle(pid)?.run(code)
+    }
+
+    /// Pushes `args`, runs `code` and pops the result. Pass a tuple to push several arguments,
+    /// they are pushed in order so the last one ends up on top.
+    pub fn call<A: PushArgs, R: FromValue>(
+        &mut self,
+        pid: Ipv6Addr,
+        code: &[Operation],
+        args: A,
+    ) -> Result<R, VmFault> {
+        let p = self.get_idle(pid)?;
+        args.push_args(p).map_err(|e| VmFault::host(pid, e))?;
+        p.run(code)?;
+        let v = p.pop().map_err(|e| VmFault::host(pid, e))?;
+        R::from_value(&v).map_err(|e| VmFault::host(pid, e))
+    }
+
+    /// Calls the exported function `module:function` with `args` and pops the result. The
+    /// arity is the number of arguments, see [Vm::call] for how they're pushed.
+    pub fn apply<A: PushArgs, R: FromValue>(
+        &mut self,
+        pid: Ipv6Addr,
+        module: Atom,
+        function: Atom,
+        args: A,
+    ) -> Result<R, VmFault> {
+        self.start(pid, module, function, args)?;
+        let p = self.get_mut(pid).map_err(|e| VmFault::host(pid, e))?;
+        p.resume(usize::MAX)?;
+        let v = p.pop().map_err(|e| VmFault::host(pid, e))?;
+        R::from_value(&v).map_err(|e| VmFault::host(pid, e))
+    }
+
+    /// Calls a function value, like [Vm::apply]. Fails with [VmError::BadArity] unless it takes
+    /// as many arguments as there are in `args`.
+    pub fn apply_fun<A: PushArgs, R: FromValue>(
+        &mut self,
+        pid: Ipv6Addr,
+        fun: &Value,
+        args: A,
+    ) -> Result<R, VmFault> {
+        let p = self.get_idle(pid)?;
+        args.push_args(p).map_err(|e| VmFault::host(pid, e))?;
+        let (m, f) = p
+            .push_captured(fun, A::COUNT)
+            .map_err(|e| VmFault::host(pid, e))?;
+        p.run_function(m, f)?;
+        let v = p.pop().map_err(|e| VmFault::host(pid, e))?;
+        R::from_value(&v).map_err(|e| VmFault::host(pid, e))
+    }
+
+    /// Pushes `args` and sets a process up to call `module:function`, without running it yet.
+    /// Run it with [Vm::resume]. Natives have nothing to suspend, they run right away.
+    pub fn start<A: PushArgs>(
+        &mut self,
+        pid: Ipv6Addr,
+        module: Atom,
+        function: Atom,
+        args: A,
+    ) -> Result<(), VmFault> {
+        let import = Import { module, function, arity: A::COUNT };
+        let callee = self
+            .registry
+            .borrow()
+            .resolve(import)
+            .map_err(|e| VmFault::host(pid, e))?;
+        let p = self.get_idle(pid)?;
+        args.push_args(p).map_err(|e| VmFault::host(pid, e))?;
+        p.invoke(callee, 0)?;
+        Ok(())
+    }
+
+    /// Runs a process for at most `budget` operations, see [Process::resume].
+    pub fn resume(&mut self, pid: Ipv6Addr, budget: usize) -> Result<RunStatus, VmFault> {
+        self.get_mut(pid)
+            .map_err(|e| VmFault::host(pid, e))?
+            .resume(budget)
+    }
+}
+
+/// Arguments for [Vm::call]. Single values push themselves, tuples push each element in order.
+pub trait PushArgs {
+    /// How many values get pushed.
+    const COUNT: u16;
+
+    fn push_args(self, p: &mut Process) -> VmResult<()>;
+}
+
+impl PushArgs for () {
+    const COUNT: u16 = 0;
+
+    fn push_args(self, _p: &mut Process) -> VmResult<()> {
+        Ok(())
+    }
+}
+
+macro_rules! push_args_tuples {
+    ($(($($t:ident $i:tt),+)),* $(,)?) => {
+        $(
+            impl<$($t: IntoValue),+> PushArgs for ($($t,)+) {
+                const COUNT: u16 = [$(stringify!($t)),+].len() as u16;
+
+                fn push_args(self, p: &mut Process) -> VmResult<()> {
+                    $(p.push(self.$i.into_value()?);)+
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+push_args_tuples! {
+    (A 0),
+    (A 0, B 1),
+    (A 0, B 1, C 2),
+    (A 0, B 1, C 2, D 3),
+    (A 0, B 1, C 2, D 3, E 4),
+    (A 0, B 1, C 2, D 3, E 4, F 5),
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv6Addr;
+
+    use alloc::{rc::Rc, string::String, vec};
+
+    use super::Vm;
+    use crate::vm::{
+        BufferDevice, Constant, Function, Module, Operation, PVObjectType, PrimOpKind, RunStatus, Value, VmError,
+    };
+
+    #[test]
+    pub fn call() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().unwrap();
+
+        let sum: i32 = vm
+            .call(pid, &[Operation::Add(PrimOpKind::I32)], (40i32, 2i32))
+            .unwrap();
+        assert_eq!(sum, 42);
+
+        vm.push(pid, vec![String::from("a"), String::from("b")])
+            .unwrap();
+        vm.push(pid, 1u64).unwrap();
+        vm.run(pid, &[Operation::IndexArray]).unwrap();
+        assert_eq!(vm.pop::<String>(pid).unwrap(), "b");
+
+        assert!(matches!(
+            vm.pop::<i32>(pid),
+            Err(VmError::StackUnderflow { .. })
+        ));
+        let gone = vm.kill(pid).unwrap();
+        assert_eq!(gone.pid(), pid);
+        assert!(matches!(vm.push(pid, 1u8), Err(VmError::NoSuchProcess(_))));
+        assert_eq!(vm.pids().count(), 0);
+    }
+
+    #[test]
+    pub fn modules() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().unwrap();
+        vm.install_native(atom!("host"), atom!("double"), 1, |p| {
+            let x: i32 = p.pop()?.reinterpret()?;
+            p.push((x * 2).into());
+            Ok(())
+        });
+
+        // lib:add_ten/1 adds a constant through a local helper.
+        let mut lib = Module::new(atom!("lib"));
+        let ten = lib.constant(Constant::Int(PrimOpKind::I32, 10));
+        let add = lib.function(Function {
+            name: atom!("add"),
+            arity: 2,
+            locals: 0,
+            code: vec![Operation::Add(PrimOpKind::I32)],
+        });
+        let add_ten = lib.function(Function {
+            name: atom!("add_ten"),
+            arity: 1,
+            locals: 0,
+            code: vec![Operation::PushConst(ten), Operation::Call(add), Operation::Return],
+        });
+        lib.export(add_ten);
+        let bytes = lib.to_bytes();
+        assert_eq!(vm.load_bytes(&bytes).unwrap(), atom!("lib"));
+
+        // app:run/1 doubles through the native then calls into lib.
+        let mut app = Module::new(atom!("app"));
+        let double = app.import(atom!("host"), atom!("double"), 1);
+        let add_ten = app.import(atom!("lib"), atom!("add_ten"), 1);
+        let missing = app.import(atom!("lib"), atom!("add"), 2);
+        let run = app.function(Function {
+            name: atom!("run"),
+            arity: 1,
+            locals: 0,
+            code: vec![Operation::CallImport(double), Operation::CallImport(add_ten)],
+        });
+        let broken = app.function(Function {
+            name: atom!("broken"),
+            arity: 0,
+            locals: 0,
+            code: vec![
+                Operation::PushImm(PrimOpKind::I32, 1i32.into()),
+                Operation::Dup,
+                Operation::CallImport(missing),
+            ],
+        });
+        app.export(run);
+        app.export(broken);
+        vm.load(app).unwrap();
+
+        let r: i32 = vm.apply(pid, atom!("app"), atom!("run"), (16i32,)).unwrap();
+        assert_eq!(r, 42);
+        let r: i32 = vm.apply(pid, atom!("host"), atom!("double"), (4i32,)).unwrap();
+        assert_eq!(r, 8);
+
+        // lib:add/2 isn't exported, so it can't be called from outside.
+        let fault = vm.apply::<_, Value>(pid, atom!("app"), atom!("broken"), ()).unwrap_err();
+        assert!(matches!(
+            fault.error,
+            VmError::UndefinedFunction { arity: 2, .. }
+        ));
+        assert_eq!(fault.trace.len(), 1);
+        assert_eq!((fault.trace[0].function, fault.trace[0].pc), (atom!("broken"), 2));
+        assert!(vm.apply::<_, i32>(pid, atom!("lib"), atom!("add"), (1i32, 2i32)).is_err());
+    }
+
+    /// counter:run/0 returns ten times the version it runs in, plus the current version.
+    fn counter(version: u8) -> Module {
+        let mut m = Module::new(atom!("counter"));
+        let current = m.import(atom!("counter"), atom!("version"), 0);
+        let v = m.function(Function {
+            name: atom!("version"),
+            arity: 0,
+            locals: 0,
+            code: vec![Operation::PushImm(PrimOpKind::U8, version.into())],
+        });
+        let run = m.function(Function {
+            name: atom!("run"),
+            arity: 0,
+            locals: 0,
+            code: vec![
+                Operation::Call(v),
+                Operation::MulImm(PrimOpKind::U8, 10u8.into()),
+                Operation::CallImport(current),
+                Operation::Add(PrimOpKind::U8),
+            ],
+        });
+        m.export(v);
+        m.export(run);
+        m
+    }
+
+    #[test]
+    pub fn hot_reload() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let a = vm.spawn().unwrap();
+        let b = vm.spawn().unwrap();
+        vm.load(counter(1)).unwrap();
+
+        // Stop a inside counter:version/0 of version 1, then upgrade.
+        vm.start(a, atom!("counter"), atom!("run"), ()).unwrap();
+        assert_eq!(vm.resume(a, 1).unwrap(), RunStatus::Suspended);
+        vm.load(counter(2)).unwrap();
+        let version = vm.registry().borrow().module(atom!("counter")).unwrap().version();
+        assert_eq!(version, 2);
+        assert_eq!(vm.lingering(atom!("counter")), vec![a]);
+        assert!(matches!(
+            vm.soft_purge(atom!("counter")),
+            Err(VmError::CodeInUse { processes: 1, .. })
+        ));
+
+        // a finishes in version 1, except for its call by import.
+        assert_eq!(vm.resume(a, usize::MAX).unwrap(), RunStatus::Finished);
+        assert_eq!(vm.pop::<u8>(a).unwrap(), 12);
+        assert!(vm.registry().borrow().old_versions(atom!("counter")).is_empty());
+        vm.soft_purge(atom!("counter")).unwrap();
+        let r: u8 = vm.apply(b, atom!("counter"), atom!("run"), ()).unwrap();
+        assert_eq!(r, 22);
+
+        // A hard purge kills whoever is left in old code.
+        vm.start(b, atom!("counter"), atom!("run"), ()).unwrap();
+        vm.resume(b, 2).unwrap();
+        vm.load(counter(3)).unwrap();
+        assert_eq!(vm.purge(atom!("counter")), vec![b]);
+        assert!(vm.process(b).is_none());
+        assert!(vm.registry().borrow().old_versions(atom!("counter")).is_empty());
+        let r: u8 = vm.apply(a, atom!("counter"), atom!("run"), ()).unwrap();
+        assert_eq!(r, 33);
+    }
+
+    #[test]
+    pub fn suspended_calls() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().unwrap();
+        vm.load(counter(1)).unwrap();
+        vm.start(pid, atom!("counter"), atom!("run"), ()).unwrap();
+        assert_eq!(vm.resume(pid, 1).unwrap(), RunStatus::Suspended);
+
+        // Nothing can be called in until the process is done, and nothing is pushed trying.
+        let depth = vm.process(pid).unwrap().depth();
+        let suspended = |e: VmError| matches!(e, VmError::ProcessSuspended(p) if p == pid);
+        let err = vm.call::<_, u8>(pid, &[Operation::Add(PrimOpKind::U8)], (1u8, 2u8));
+        assert!(suspended(err.unwrap_err().error));
+        let err = vm.apply::<_, u8>(pid, atom!("counter"), atom!("version"), ());
+        assert!(suspended(err.unwrap_err().error));
+        assert!(suspended(vm.run(pid, &[]).unwrap_err().error));
+        assert_eq!(vm.process(pid).unwrap().depth(), depth);
+
+        assert_eq!(vm.resume(pid, usize::MAX).unwrap(), RunStatus::Finished);
+        assert_eq!(vm.pop::<u8>(pid).unwrap(), 11);
+        let r: u8 = vm.apply(pid, atom!("counter"), atom!("version"), ()).unwrap();
+        assert_eq!(r, 1);
+    }
+
+    /// maker:make/0 returns a closure of maker:version/0, and maker:keep/0 stores one in a
+    /// global of its own module.
+    fn maker(version: u8) -> Module {
+        let mut m = Module::new(atom!("maker"));
+        m.globals = 1;
+        let v = m.function(Function {
+            name: atom!("version"),
+            arity: 0,
+            locals: 0,
+            code: vec![Operation::PushImm(PrimOpKind::U8, version.into())],
+        });
+        let make = m.function(Function {
+            name: atom!("make"),
+            arity: 0,
+            locals: 0,
+            code: vec![Operation::MakeClosure(v, 0)],
+        });
+        let keep = m.function(Function {
+            name: atom!("keep"),
+            arity: 0,
+            locals: 0,
+            code: vec![Operation::MakeClosure(v, 0), Operation::StoreGlobal(0)],
+        });
+        m.export(make);
+        m.export(keep);
+        m
+    }
+
+    #[test]
+    pub fn hot_reload_closures() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let a = vm.spawn().unwrap();
+        let b = vm.spawn().unwrap();
+        let mut stash = Module::new(atom!("stash"));
+        stash.globals = 1;
+        let put = stash.function(Function {
+            name: atom!("put"),
+            arity: 1,
+            locals: 0,
+            code: vec![Operation::StoreGlobal(0)],
+        });
+        stash.export(put);
+        vm.load(stash).unwrap();
+        vm.load(maker(1)).unwrap();
+
+        // a keeps a closure of version 1 on its stack, and version 1 one in its own global.
+        let f: Value = vm.apply(a, atom!("maker"), atom!("make"), ()).unwrap();
+        vm.push(a, f.clone()).unwrap();
+        vm.start(b, atom!("maker"), atom!("keep"), ()).unwrap();
+        vm.resume(b, usize::MAX).unwrap();
+        vm.load(maker(2)).unwrap();
+        assert_eq!(vm.lingering(atom!("maker")), vec![a]);
+        assert!(matches!(
+            vm.soft_purge(atom!("maker")),
+            Err(VmError::CodeInUse { processes: 1, .. })
+        ));
+        let r: u8 = vm.apply_fun(b, &f, ()).unwrap();
+        assert_eq!(r, 1);
+
+        // Once a lets go, only version 1's own global holds it, which doesn't count.
+        vm.pop::<Value>(a).unwrap();
+        drop(f);
+        assert!(vm.lingering(atom!("maker")).is_empty());
+        assert_eq!(vm.registry().borrow().old_versions(atom!("maker")).len(), 1);
+        vm.soft_purge(atom!("maker")).unwrap();
+        assert!(vm.registry().borrow().old_versions(atom!("maker")).is_empty());
+
+        // Another module's global does, until a hard purge resets it.
+        let f: Value = vm.apply(a, atom!("maker"), atom!("make"), ()).unwrap();
+        vm.start(a, atom!("stash"), atom!("put"), (f,)).unwrap();
+        vm.resume(a, usize::MAX).unwrap();
+        vm.load(maker(3)).unwrap();
+        assert!(vm.lingering(atom!("maker")).is_empty());
+        assert!(matches!(
+            vm.soft_purge(atom!("maker")),
+            Err(VmError::CodeInUse { processes: 0, .. })
+        ));
+        assert!(vm.purge(atom!("maker")).is_empty());
+        assert!(vm.registry().borrow().old_versions(atom!("maker")).is_empty());
+        let held = vm.registry().borrow().module(atom!("stash")).unwrap().global(0);
+        assert_eq!(held, Value::Null);
+        assert_eq!(vm.pids().count(), 2);
+    }
+
+    #[test]
+    pub fn locals_and_globals() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        vm.registry().borrow_mut().set_strict(true);
+        let a = vm.spawn().unwrap();
+        let b = vm.spawn().unwrap();
+
+        let mut m = Module::new(atom!("slots"));
+        m.globals = 1;
+        // rsub/2 is ( x y -- y-x ), keeping its arguments in locals around a call that uses
+        // its own.
+        let clobber = m.function(Function {
+            name: atom!("clobber"),
+            arity: 0,
+            locals: 2,
+            code: vec![
+                Operation::PushImm(PrimOpKind::I32, 99i32.into()),
+                Operation::Dup,
+                Operation::StoreLocal(0),
+                Operation::StoreLocal(1),
+            ],
+        });
+        let rsub = m.function(Function {
+            name: atom!("rsub"),
+            arity: 2,
+            locals: 2,
+            code: vec![
+                Operation::StoreLocal(1),
+                Operation::StoreLocal(0),
+                Operation::Call(clobber),
+                Operation::LoadLocal(1),
+                Operation::LoadLocal(0),
+                Operation::Sub(PrimOpKind::I32),
+            ],
+        });
+        let bump = m.function(Function {
+            name: atom!("bump"),
+            arity: 0,
+            locals: 0,
+            code: vec![
+                Operation::LoadGlobal(0),
+                Operation::AddImm(PrimOpKind::U32, 1u32.into()),
+                Operation::Dup,
+                Operation::StoreGlobal(0),
+            ],
+        });
+        let reset = m.function(Function {
+            name: atom!("reset"),
+            arity: 0,
+            locals: 0,
+            code: vec![
+                Operation::PushImm(PrimOpKind::U32, 0u32.into()),
+                Operation::StoreGlobal(0),
+                Operation::PushAtom(atom!("ok")),
+            ],
+        });
+        for f in [rsub, bump, reset] {
+            m.export(f);
+        }
+
+        // Strict loading catches a function reading a local it never wrote.
+        let mut bad = m.clone();
+        bad.functions[0].code.insert(0, Operation::LoadLocal(1));
+        assert!(matches!(
+            vm.load(bad),
+            Err(VmError::UnassignedLocal { pc: 0, local: 1, .. })
+        ));
+        vm.load(m).unwrap();
+
+        let r: i32 = vm.apply(a, atom!("slots"), atom!("rsub"), (2i32, 10i32)).unwrap();
+        assert_eq!(r, 8);
+        assert_eq!(vm.process(a).unwrap().depth(), 0);
+
+        // Globals belong to the module, so processes share them.
+        let _: Value = vm.apply(a, atom!("slots"), atom!("reset"), ()).unwrap();
+        let _: u32 = vm.apply(a, atom!("slots"), atom!("bump"), ()).unwrap();
+        let n: u32 = vm.apply(b, atom!("slots"), atom!("bump"), ()).unwrap();
+        assert_eq!(n, 2);
+
+        // Code run directly has no local slots.
+        assert!(matches!(
+            vm.run(a, &[Operation::LoadLocal(0)]).unwrap_err().error,
+            VmError::NoSuchLocal(0)
+        ));
+    }
+
+    #[test]
+    pub fn debug_output() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let before = vm.spawn().unwrap();
+        let out = Rc::new(BufferDevice::new());
+        vm.set_output(out.clone());
+        let after = vm.spawn().unwrap();
+
+        let code = [
+            Operation::PushImm(PrimOpKind::U8, 7u8.into()),
+            Operation::DebugOut,
+        ];
+        vm.run(before, &code).unwrap();
+        vm.run(after, &code).unwrap();
+        assert_eq!(out.take(), "7u8\n7u8\n");
+        assert_eq!(vm.process(after).unwrap().depth(), 0);
+
+        // DebugOut pops what it prints, so an empty stack faults.
+        let fault = vm.run(after, &[Operation::DebugOut]).unwrap_err();
+        assert!(matches!(fault.error, VmError::StackUnderflow { needed: 1, depth: 0 }));
+        assert_eq!(out.contents(), "");
+    }
+
+    #[test]
+    pub fn closures() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().unwrap();
+
+        let mut m = Module::new(atom!("funs"));
+        let add = m.function(Function {
+            name: atom!("add"),
+            arity: 2,
+            locals: 0,
+            code: vec![Operation::Add(PrimOpKind::I32)],
+        });
+        let adder = m.function(Function {
+            name: atom!("adder"),
+            arity: 1,
+            locals: 0,
+            code: vec![Operation::MakeClosure(add, 1)],
+        });
+        // twice/2 is ( x f -- f(f(x)) ).
+        let twice = m.function(Function {
+            name: atom!("twice"),
+            arity: 2,
+            locals: 1,
+            code: vec![
+                Operation::StoreLocal(0),
+                Operation::LoadLocal(0),
+                Operation::CallIndirect(1),
+                Operation::LoadLocal(0),
+                Operation::CallIndirect(1),
+            ],
+        });
+        m.export(adder);
+        m.export(twice);
+        vm.load(m).unwrap();
+
+        let add10: Value = vm.apply(pid, atom!("funs"), atom!("adder"), (10i32,)).unwrap();
+        let Value::Object(o) = &add10 else { panic!() };
+        let PVObjectType::Function(c) = &*o.get() else {
+            panic!()
+        };
+        assert_eq!(alloc::format!("{c:?}"), "#Fun<funs:add/1>");
+        let r: i32 = vm.apply_fun(pid, &add10, (5i32,)).unwrap();
+        assert_eq!(r, 15);
+        let r: i32 = vm
+            .apply(pid, atom!("funs"), atom!("twice"), (1i32, add10.clone()))
+            .unwrap();
+        assert_eq!(r, 21);
+
+        let err = vm.apply_fun::<_, i32>(pid, &add10, (1i32, 2i32)).unwrap_err();
+        assert!(matches!(err.error, VmError::BadArity { expected: 1, found: 2 }));
+        let err = vm
+            .apply::<_, i32>(pid, atom!("funs"), atom!("twice"), (1i32, 2i32))
+            .unwrap_err();
+        assert!(matches!(err.error, VmError::PopExpectedType { .. }));
+    }
+
+    #[test]
+    pub fn tail_calls() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().unwrap();
+
+        // count/1 is an endless loop, ( n -- ) calling itself with n + 1.
+        let mut m = Module::new(atom!("tail"));
+        let count = m.function(Function {
+            name: atom!("count"),
+            arity: 1,
+            locals: 1,
+            code: vec![
+                Operation::AddImm(PrimOpKind::U32, 1u32.into()),
+                Operation::Dup,
+                Operation::StoreLocal(0),
+                Operation::Call(0),
+            ],
+        });
+        // apply/2 is ( x f -- f(x) ).
+        let apply = m.function(Function {
+            name: atom!("apply"),
+            arity: 2,
+            locals: 0,
+            code: vec![Operation::CallIndirect(1), Operation::Return],
+        });
+        let make = m.function(Function {
+            name: atom!("make"),
+            arity: 0,
+            locals: 0,
+            code: vec![Operation::MakeClosure(count, 0)],
+        });
+        assert_eq!(m.functions[count as usize].code[3], Operation::TailCall(0));
+        assert_eq!(m.functions[apply as usize].code[0], Operation::TailCallIndirect(1));
+        m.export(count);
+        m.export(apply);
+        m.export(make);
+        vm.load(m).unwrap();
+
+        // A million iterations, four operations each, in one frame.
+        vm.start(pid, atom!("tail"), atom!("count"), (0u32,)).unwrap();
+        assert_eq!(vm.resume(pid, 4_000_000).unwrap(), RunStatus::Suspended);
+        let p = vm.process(pid).unwrap();
+        assert_eq!((p.call_depth(), p.depth()), (1, 1));
+        assert_eq!(vm.pop::<u32>(pid).unwrap(), 1_000_000);
+
+        // Through a function value, the loop takes over apply's frame. The first loop never
+        // ends, so this one runs in a process of its own.
+        let pid = vm.spawn().unwrap();
+        let f: Value = vm.apply(pid, atom!("tail"), atom!("make"), ()).unwrap();
+        vm.start(pid, atom!("tail"), atom!("apply"), (0u32, f)).unwrap();
+        vm.resume(pid, 1 + 4 * 1000).unwrap();
+        let p = vm.process(pid).unwrap();
+        assert_eq!((p.call_depth(), p.depth()), (1, 1));
+        assert_eq!(vm.pop::<u32>(pid).unwrap(), 1000);
+    }
+}
@@ -0,0 +1,333 @@
+use core::alloc::AllocError;
+
+use alloc::{collections::BTreeSet, vec, vec::Vec};
+use indexmap::{IndexMap, TryReserveError};
+
+use super::{
+    error::{VmError, VmResult},
+    FromValue, IntoValue, PVObject, PVObjectType, PVString, PrimOpKind, Value, ValueKind,
+};
+
+fn not_a_map(o: &PVObjectType) -> VmError {
+    VmError::PopExpectedType {
+        expected: ValueKind::Map,
+        found: o.kind(),
+    }
+}
+
+fn oom(_: TryReserveError) -> VmError {
+    VmError::MemoryAllocFailed(AllocError)
+}
+
+fn array(elems: Vec<Value>) -> VmResult<Value> {
+    Ok(Value::Object(PVObject::make_array_from(elems)?))
+}
+
+/// Map and dict operations, for the map ops of [Operation](super::Operation). Each fails with
+/// [VmError::PopExpectedType] on other objects. Map keys come out as atoms and strings.
+impl PVObjectType {
+    /// Reads an element of an array or packed array or a binary's byte by index, or a map or dict entry by key.
+    /// Missing entries read as null, as do keys a map can't hold.
+    pub fn index(&self, key: &Value) -> VmResult<Value> {
+        let found = match self {
+            PVObjectType::Array(_) => self.load(index(key)?),
+            PVObjectType::Map(m) => PVString::from_value(key)
+                .ok()
+                .and_then(|k| m.get(&k).cloned()),
+            PVObjectType::Dict(d) => d.get(key).cloned(),
+            PVObjectType::Binary(b) => b.get(index(key)?).map(|byte| Value::from(byte, PrimOpKind::U8)),
+            PVObjectType::Packed(p) => p.get(index(key)?),
+            _ => {
+                return Err(VmError::PopExpectedType {
+                    expected: ValueKind::Array,
+                    found: self.kind(),
+                })
+            }
+        };
+        Ok(found.unwrap_or(Value::Null))
+    }
+
+    /// Sets an element of an array or packed array by index, growing it if needed, a binary's
+    /// byte by index, or a map or dict entry by key. Map keys must be atoms or strings, dict
+    /// keys pass [check_key]. Bytes and packed elements are set from ints of any kind,
+    /// truncated to theirs.
+    pub fn set_index(&mut self, key: Value, value: Value) -> VmResult<()> {
+        match self {
+            PVObjectType::Array(_) => self.store(index(&key)?, value),
+            PVObjectType::Map(m) => {
+                let key = PVString::from_value(&key)?;
+                m.try_reserve(1).map_err(oom)?;
+                m.insert(key, value);
+                Ok(())
+            }
+            PVObjectType::Dict(d) => {
+                check_key(&key)?;
+                d.try_reserve(1).map_err(oom)?;
+                d.insert(key, value);
+                Ok(())
+            }
+            PVObjectType::Binary(b) => b.set(index(&key)?, value.reinterpret::<u8>()?),
+            PVObjectType::Packed(p) => p.set(index(&key)?, &value),
+            _ => Err(VmError::PopExpectedType {
+                expected: ValueKind::Array,
+                found: self.kind(),
+            }),
+        }
+    }
+
+    /// The keys in insertion order.
+    pub fn map_keys(&self) -> VmResult<Vec<Value>> {
+        let mut keys = Vec::new();
+        match self {
+            PVObjectType::Map(m) => {
+                keys.try_reserve(m.len())?;
+                for k in m.keys() {
+                    keys.push(k.clone().into_value()?);
+                }
+            }
+            PVObjectType::Dict(d) => {
+                keys.try_reserve(d.len())?;
+                keys.extend(d.keys().cloned());
+            }
+            _ => return Err(not_a_map(self)),
+        }
+        Ok(keys)
+    }
+
+    /// The values in insertion order.
+    pub fn map_values(&self) -> VmResult<Vec<Value>> {
+        let mut values = Vec::new();
+        match self {
+            PVObjectType::Map(m) => {
+                values.try_reserve(m.len())?;
+                values.extend(m.values().cloned());
+            }
+            PVObjectType::Dict(d) => {
+                values.try_reserve(d.len())?;
+                values.extend(d.values().cloned());
+            }
+            _ => return Err(not_a_map(self)),
+        }
+        Ok(values)
+    }
+
+    /// The entries in insertion order, each a `[key, value]` array.
+    pub fn map_entries(&self) -> VmResult<Vec<Value>> {
+        let mut entries = Vec::new();
+        match self {
+            PVObjectType::Map(m) => {
+                entries.try_reserve(m.len())?;
+                for (k, v) in m {
+                    entries.push(array(vec![k.clone().into_value()?, v.clone()])?);
+                }
+            }
+            PVObjectType::Dict(d) => {
+                entries.try_reserve(d.len())?;
+                for (k, v) in d {
+                    entries.push(array(vec![k.clone(), v.clone()])?);
+                }
+            }
+            _ => return Err(not_a_map(self)),
+        }
+        Ok(entries)
+    }
+
+    /// A new map with the entries of this one, then those of `other`, which win where both
+    /// have a key. Two maps merge into a map, anything else into a dict.
+    pub fn merge(&self, other: &PVObjectType) -> VmResult<PVObjectType> {
+        match (self, other) {
+            (PVObjectType::Map(a), PVObjectType::Map(b)) => {
+                let mut m = IndexMap::default();
+                m.try_reserve(a.len() + b.len()).map_err(oom)?;
+                m.extend(a.iter().chain(b).map(|(k, v)| (k.clone(), v.clone())));
+                Ok(PVObjectType::Map(m))
+            }
+            (
+                PVObjectType::Map(_) | PVObjectType::Dict(_),
+                PVObjectType::Map(_) | PVObjectType::Dict(_),
+            ) => {
+                let mut d = IndexMap::default();
+                for side in [self, other] {
+                    match side {
+                        PVObjectType::Map(m) => {
+                            d.try_reserve(m.len()).map_err(oom)?;
+                            for (k, v) in m {
+                                d.insert(k.clone().into_value()?, v.clone());
+                            }
+                        }
+                        PVObjectType::Dict(m) => {
+                            d.try_reserve(m.len()).map_err(oom)?;
+                            d.extend(m.iter().map(|(k, v)| (k.clone(), v.clone())));
+                        }
+                        _ => unreachable!("checked above"),
+                    }
+                }
+                Ok(PVObjectType::Dict(d))
+            }
+            (PVObjectType::Map(_) | PVObjectType::Dict(_), other) => Err(not_a_map(other)),
+            _ => Err(not_a_map(self)),
+        }
+    }
+
+    /// A new map of the same type with only the entries whose keys are among `keys`, in this
+    /// map's order.
+    pub fn filter(&self, keys: &[Value]) -> VmResult<PVObjectType> {
+        match self {
+            PVObjectType::Map(m) => {
+                let keep: BTreeSet<_> = keys
+                    .iter()
+                    .filter_map(|k| PVString::from_value(k).ok())
+                    .collect();
+                let mut out = IndexMap::default();
+                out.try_reserve(keep.len().min(m.len())).map_err(oom)?;
+                out.extend(
+                    m.iter()
+                        .filter(|(k, _)| keep.contains(*k))
+                        .map(|(k, v)| (k.clone(), v.clone())),
+                );
+                Ok(PVObjectType::Map(out))
+            }
+            PVObjectType::Dict(d) => {
+                let mut keep: Vec<_> = Vec::new();
+                keep.try_reserve(keys.len())?;
+                keep.extend(keys);
+                keep.sort_unstable();
+                let mut out = IndexMap::default();
+                out.try_reserve(keep.len().min(d.len())).map_err(oom)?;
+                out.extend(
+                    d.iter()
+                        .filter(|(k, _)| keep.binary_search(k).is_ok())
+                        .map(|(k, v)| (k.clone(), v.clone())),
+                );
+                Ok(PVObjectType::Dict(out))
+            }
+            _ => Err(not_a_map(self)),
+        }
+    }
+}
+
+/// Checks that a value can be a dict key. A key is hashed once, when it goes in, so it must
+/// never change after: null, ints, atoms, strings, userdata and functions capturing only such
+/// values can be keys. Arrays, maps, dicts, binaries and packed arrays, which VM code can
+/// mutate, fail with PopExpectedType.
+pub(super) fn check_key(key: &Value) -> VmResult<()> {
+    let Value::Object(o) = key else {
+        return Ok(());
+    };
+    match &*o.get() {
+        PVObjectType::String(_) | PVObjectType::UserData(_) => Ok(()),
+        PVObjectType::Function(c) => c.captured().iter().try_for_each(check_key),
+        other => Err(VmError::PopExpectedType {
+            expected: ValueKind::String,
+            found: other.kind(),
+        }),
+    }
+}
+
+/// An array index, which must be a non-negative int.
+pub(super) fn index(v: &Value) -> VmResult<usize> {
+    let i = v.as_i128().ok_or(VmError::PopExpectedType {
+        expected: ValueKind::Int,
+        found: v.kind(),
+    })?;
+    usize::try_from(i).map_err(|_| VmError::IntOutOfRange {
+        value: i,
+        target: PrimOpKind::U64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv6Addr;
+
+    use alloc::{format, string::String, vec::Vec};
+
+    use crate::{
+        forth,
+        vm::{PVObject, Value, ValueKind, Vm, VmError},
+    };
+
+    fn int(n: impl Into<Value>) -> Value {
+        n.into()
+    }
+
+    /// Runs Forth in a new process and shows what it leaves on the stack, bottom first.
+    fn run(source: &str) -> Vec<String> {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let name = vm
+            .load(forth::compile_module(atom!("maps"), source).unwrap())
+            .unwrap();
+        let pid = vm.spawn().unwrap();
+        vm.start(pid, name, atom!("main"), ()).unwrap();
+        vm.resume(pid, usize::MAX).unwrap();
+        let p = vm.process(pid).unwrap();
+        p.stack().map(|v| format!("{v}")).collect()
+    }
+
+    #[test]
+    pub fn dict_keys() {
+        // Keys are compared by structure: another "empty" string finds the entry.
+        let stack = run(r#"dict dup 1 :one ! dup "empty" :e ! dup 1u8 :byte !
+            dup 1 @ over "empty" @ 2 pick 2 @ 3 roll"#);
+        assert_eq!(
+            stack,
+            [
+                ":one",
+                ":e",
+                "null",
+                r#"#{1 => :one, "empty" => :e, 1u8 => :byte}"#
+            ]
+        );
+        // Maps only take atoms and strings.
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().unwrap();
+        assert!(vm.run(pid, &forth::compile("map 1 2 !").unwrap()).is_err());
+    }
+
+    #[test]
+    pub fn mutable_keys() {
+        // An array could change after going in, leaving its entry where its old contents
+        // hashed, so it can't be a key.
+        let dict = PVObject::make_dict().unwrap();
+        let key = PVObject::make_array().unwrap();
+        let r = dict
+            .get_mut()
+            .set_index(Value::Object(key.clone()), int(1i64));
+        assert!(matches!(
+            r,
+            Err(VmError::PopExpectedType {
+                found: ValueKind::Array,
+                ..
+            })
+        ));
+        key.get_mut().set_index(int(0i64), int(5i64)).unwrap();
+        assert_eq!(dict.get().length(), Some(0));
+        assert_eq!(dict.get().index(&Value::Object(key)).unwrap(), Value::Null);
+
+        // Nor can the dict itself, or a binary.
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().unwrap();
+        assert!(vm.run(pid, &forth::compile("dict dup dup 1 !").unwrap()).is_err());
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let pid = vm.spawn().unwrap();
+        assert!(vm.run(pid, &forth::compile("dict 1 binary 1 !").unwrap()).is_err());
+    }
+
+    #[test]
+    pub fn iterate_merge_filter() {
+        let stack = run(r#"map dup :a 1 ! dup "b" 2 !
+            dup keys over values 2 pick entries"#);
+        assert_eq!(
+            stack[1..],
+            [r#"[:a, "b"]"#, "[1, 2]", r#"[[:a, 1], ["b", 2]]"#]
+        );
+
+        let stack = run(r#"map dup :a 1 ! dup :b 2 !  map dup :b 3 ! merge
+            dup dict dup 7 :seven ! merge
+            over array dup 0 :b ! filter"#);
+        assert_eq!(
+            stack,
+            ["{a: 1, b: 3}", "#{:a => 1, :b => 3, 7 => :seven}", "{b: 3}"]
+        );
+    }
+}
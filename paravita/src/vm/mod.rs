@@ -1,41 +1,113 @@
+#[macro_use]
 mod atoms;
+mod binary;
+mod compare;
+mod convert;
 mod error;
+mod host;
+mod map;
+mod module;
+mod numeric;
 mod object;
 mod opcodes;
+mod output;
+mod packed;
+mod pretty;
+mod record;
+mod registry;
+mod scheduler;
+#[cfg(feature = "serde")]
+mod serialize;
+mod slot;
+mod syscall;
 mod value;
-use core::any::TypeId;
-use core::cell::{Ref, RefMut};
-use core::mem::discriminant;
+use core::cell::{Ref, RefCell, RefMut};
 use core::net::Ipv6Addr;
-use core::ops::DerefMut;
-use core::{alloc::AllocError, ops::Deref};
-#[cfg(std)]
-use std::println;
+use core::alloc::AllocError;
 
-use alloc::vec::Vec;
+use alloc::{collections::BTreeSet, rc::Rc, vec::Vec};
 pub use atoms::*;
-use bytemuck::Pod;
+pub use binary::*;
+pub use convert::*;
+pub use error::*;
+pub use host::*;
+pub use module::*;
+pub use numeric::Num;
 use num::{
-    traits::{WrappingAdd, WrappingMul, WrappingSub},
-    FromPrimitive, Integer,
+    traits::{CheckedRem, WrappingAdd, WrappingMul, WrappingNeg, WrappingSub},
+    CheckedDiv, Integer,
 };
 pub use object::*;
 pub use opcodes::*;
+pub use output::*;
+pub use packed::*;
+pub use pretty::*;
+pub use record::*;
+pub use registry::*;
+pub use scheduler::*;
+use registry::Callee;
+use slot::Slot;
+pub use syscall::*;
 use portable_atomic::AtomicU64;
-use tinyvec::TinyVec;
 pub use value::*;
 
-use self::error::{VmError, VmResult};
 
 static PROC_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 pub struct Process {
     pid: Ipv6Addr,
-    stack: Vec<Value>,
+    stack: Vec<Slot>,
+    /// Offset of the next operation to run, in the innermost frame.
+    pc: usize,
+    /// Running functions, innermost last.
+    frames: Vec<Frame>,
+    /// Local slots of every frame, each frame's after its caller's.
+    locals: Vec<Slot>,
+    /// Set when the innermost frame changes, so the run loop picks up the new code.
+    switched: bool,
+    /// Installed exception handlers, innermost last.
+    handlers: Vec<Handler>,
+    /// Why the process exited, once it has.
+    exit_reason: Option<Value>,
+    /// Host services reachable through Syscall.
+    syscalls: Option<Rc<SyscallTable>>,
+    /// Where DebugOut writes, stdout if none is installed and std is available.
+    output: Option<Rc<dyn OutputDevice>>,
+    /// Loaded code, for calls into other modules.
+    registry: Option<Rc<RefCell<Registry>>>,
+}
+
+struct Handler {
+    /// Where to continue when an exception is caught.
+    pc: usize,
+    /// Stack depth to restore before pushing the exception.
+    depth: usize,
+    /// Number of frames to cut back to, the handler's own frame being the last.
+    frames: usize,
+    /// Number of local slots to cut back to.
+    locals: usize,
+}
+
+/// How far a call to [Process::resume] got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    /// The code ran to the end.
+    Finished,
+    /// The budget ran out first. Resuming continues where it stopped.
+    Suspended,
+}
+
+struct Frame {
+    /// The module and index of the running function, None for code given to [Process::run].
+    function: Option<(Rc<LoadedModule>, u32)>,
+    code: Rc<[Operation]>,
+    /// Where to continue once the frame above returns. Unused for the innermost frame.
+    pc: usize,
+    /// Index of the frame's first local slot.
+    base: usize,
 }
 
 impl Process {
-    #[must_use]
     pub fn new(prefix: Ipv6Addr) -> Result<Process, AllocError> {
         let count = PROC_COUNTER.fetch_add(1, portable_atomic::Ordering::Relaxed);
         let mut segs = prefix.segments();
@@ -46,207 +118,521 @@ impl Process {
         Ok(Process {
             pid: segs.into(),
             stack: Vec::new(),
+            pc: 0,
+            frames: Vec::new(),
+            locals: Vec::new(),
+            switched: false,
+            handlers: Vec::new(),
+            exit_reason: None,
+            syscalls: None,
+            output: None,
+            registry: None,
         })
     }
 
-    #[must_use]
-    pub(super) fn pop_into(&mut self, into: &mut Value) -> VmResult<()> {
-        if !into.is_null() {
-            //We'd need to drop it. No.
-            unimplemented!()
+    /// Installs the table Syscall operations are dispatched through.
+    pub fn set_syscalls(&mut self, table: Rc<SyscallTable>) {
+        self.syscalls = Some(table);
+    }
+
+    /// Installs the device DebugOut writes to.
+    pub fn set_output(&mut self, device: Rc<dyn OutputDevice>) {
+        self.output = Some(device);
+    }
+
+    /// Installs the registry CallImport operations look functions up in.
+    pub fn set_registry(&mut self, registry: Rc<RefCell<Registry>>) {
+        self.registry = Some(registry);
+    }
+
+    pub fn pid(&self) -> Ipv6Addr {
+        self.pid
+    }
+
+    /// The exit reason of the process, if it has exited.
+    pub fn exit_reason(&self) -> Option<&Value> {
+        self.exit_reason.as_ref()
+    }
+
+    /// Runs a program from the start, stopping at the end or at the first uncaught exception.
+    /// An uncaught exception exits the process, with the exception value as its exit reason.
+    /// Fails with [VmError::ProcessSuspended] if the process is partway through other code.
+    pub fn run(&mut self, code: &[Operation]) -> Result<(), VmFault> {
+        self.enter(None, Rc::from(code))?;
+        self.resume(usize::MAX).map(|_| ())
+    }
+
+    /// Calls a function of a loaded module, with its arguments already on the stack. Runs
+    /// until the function returns or an exception goes uncaught, like [Process::run].
+    pub fn run_function(&mut self, module: Rc<LoadedModule>, function: u32) -> Result<(), VmFault> {
+        self.invoke(Callee::Function(module, function), usize::MAX).map(|_| ())
+    }
+
+    /// Calls a function or native, running at most `budget` operations of it.
+    pub(super) fn invoke(&mut self, callee: Callee, budget: usize) -> Result<RunStatus, VmFault> {
+        match callee {
+            Callee::Native(f) => {
+                f(self).map_err(|e| VmFault::host(self.pid, e))?;
+                Ok(RunStatus::Finished)
+            }
+            Callee::Function(m, f) => {
+                let arity = m.function(f).arity as usize;
+                self.need(arity).map_err(|e| VmFault::host(self.pid, e))?;
+                let code = m.function(f).code.clone();
+                self.enter(Some((m, f)), code)?;
+                self.resume(budget)
+            }
         }
-        if self.stack.len() == 0 {
-            return Err(VmError::StackUnderflow())
+    }
+
+    /// Fails with [VmError::ProcessSuspended] if the process is partway through its code, which
+    /// starting anything else would throw away.
+    pub fn check_idle(&self) -> VmResult<()> {
+        match self.is_suspended() {
+            true => Err(VmError::ProcessSuspended(self.pid)),
+            false => Ok(()),
         }
+    }
 
-        unsafe {
-            let len = self.stack.len()-1;
-            let src = self.stack.get_unchecked_mut(len) as *const Value;
-            let dest = into as *mut Value;
-            dest.copy_from(src, 1);
-            self.stack.set_len(len);
+    fn enter(&mut self, function: Option<(Rc<LoadedModule>, u32)>, code: Rc<[Operation]>) -> Result<(), VmFault> {
+        self.check_idle().map_err(|e| VmFault::host(self.pid, e))?;
+        self.pc = 0;
+        self.handlers.clear();
+        self.frames.clear();
+        self.locals.clear();
+        self.push_frame(function, code)
+            .map_err(|e| VmFault::host(self.pid, e))
+    }
+
+    /// Pushes a frame along with its local slots, all null.
+    fn push_frame(&mut self, function: Option<(Rc<LoadedModule>, u32)>, code: Rc<[Operation]>) -> VmResult<()> {
+        let locals = match &function {
+            Some((m, f)) => m.function(*f).locals as usize,
+            None => 0,
+        };
+        self.frames.try_reserve(1)?;
+        self.locals.try_reserve(locals)?;
+        let base = self.locals.len();
+        self.locals.resize_with(base + locals, || Slot::NULL);
+        self.frames.push(Frame { function, code, pc: 0, base });
+        Ok(())
+    }
+
+    /// The operation the process runs next when resumed, along with the function it's in.
+    pub fn next_op(&self) -> Option<(Operation, Option<CallSite>)> {
+        let frame = self.frames.last()?;
+        let op = *frame.code.get(self.pc)?;
+        let site = frame.function.as_ref().map(|(m, f)| CallSite {
+            module: m.name(),
+            function: m.function(*f).name,
+            arity: m.function(*f).arity,
+            pc: self.pc,
+        });
+        Some((op, site))
+    }
+
+    /// Whether the process stopped partway through its code, see [Process::resume].
+    pub fn is_suspended(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// Continues running for at most `budget` operations. Every operation costs one, so a
+    /// scheduler can take turns between processes by giving each a budget in turn.
+    pub fn resume(&mut self, budget: usize) -> Result<RunStatus, VmFault> {
+        let mut budget = budget;
+        while let Some(frame) = self.frames.last() {
+            let code = frame.code.clone();
+            self.switched = false;
+            while !self.switched {
+                let Some(&op) = code.get(self.pc) else {
+                    // Running off the end returns.
+                    self.ret();
+                    break;
+                };
+                if budget == 0 {
+                    return Ok(RunStatus::Suspended);
+                }
+                budget -= 1;
+                let pc = self.pc;
+                self.pc += 1;
+                if let Err(error) = self.run_op(op) {
+                    if let Err(error) = self.raise(error) {
+                        let trace = self.backtrace(pc);
+                        self.frames.clear();
+                        return Err(VmFault {
+                            error,
+                            pid: self.pid,
+                            pc,
+                            op: Some(op),
+                            trace,
+                        });
+                    }
+                }
+            }
         }
+        Ok(RunStatus::Finished)
+    }
+
+    /// How many frames are running, the code given to [Process::run] counting as one.
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Whether any frame is running code of this module version.
+    pub fn runs(&self, module: &Rc<LoadedModule>) -> bool {
+        self.frames
+            .iter()
+            .any(|f| f.function.as_ref().is_some_and(|(m, _)| Rc::ptr_eq(m, module)))
+    }
+
+    /// Whether this process still uses one of these module versions: a frame runs it, or a
+    /// closure of it is reachable from the stack, the locals, the exit reason or the globals
+    /// of the modules running.
+    pub fn uses(&self, versions: &[Rc<LoadedModule>]) -> bool {
+        versions.iter().any(|m| self.runs(m))
+            || registry::reaches_closure(
+                self.stack
+                    .iter()
+                    .chain(&self.locals)
+                    .map(Slot::to_value)
+                    .chain(self.exit_reason.clone()),
+                self.frames.iter().filter_map(|f| f.function.as_ref().map(|(m, _)| m)),
+                true,
+                versions,
+            )
+    }
+
+    /// The module functions on the frame stack, innermost first. `pc` is the innermost one's.
+    fn backtrace(&self, pc: usize) -> Vec<CallSite> {
+        let mut pc = pc;
+        let mut trace = Vec::new();
+        for frame in self.frames.iter().rev() {
+            if let Some((m, f)) = &frame.function {
+                let f = m.function(*f);
+                trace.push(CallSite {
+                    module: m.name(),
+                    function: f.name,
+                    arity: f.arity,
+                    pc,
+                });
+            }
+            // Outer frames are stopped just past their call.
+            pc = frame.pc.saturating_sub(1);
+        }
+        trace
+    }
+
+    /// The local slots of the running function.
+    fn frame_locals(&mut self) -> &mut [Slot] {
+        let base = self.frames.last().map_or(0, |f| f.base);
+        &mut self.locals[base..]
+    }
+
+    /// The module of the running function.
+    fn module(&self) -> VmResult<Rc<LoadedModule>> {
+        match self.frames.last().and_then(|f| f.function.as_ref()) {
+            Some((m, _)) => Ok(m.clone()),
+            None => Err(VmError::MalformedModule("no module is running")),
+        }
+    }
+
+    /// Pushes a frame for a function, its arguments being on the stack already.
+    fn call(&mut self, module: Rc<LoadedModule>, function: u32) -> VmResult<()> {
+        self.need(module.function(function).arity as usize)?;
+        let code = module.function(function).code.clone();
+        self.push_frame(Some((module, function)), code)?;
+        let frames = self.frames.len();
+        if frames > 1 {
+            self.frames[frames - 2].pc = self.pc;
+        }
+        self.pc = 0;
+        self.switched = true;
+        Ok(())
+    }
+
+    /// Replaces the innermost frame with one for a function, its arguments being on the stack.
+    fn tail_call(&mut self, module: Rc<LoadedModule>, function: u32) -> VmResult<()> {
+        self.need(module.function(function).arity as usize)?;
+        let code = module.function(function).code.clone();
+        self.pop_frame();
+        self.push_frame(Some((module, function)), code)?;
+        self.pc = 0;
+        self.switched = true;
+        Ok(())
+    }
+
+    /// Pops the innermost frame, along with its locals and any handlers it left installed.
+    fn pop_frame(&mut self) {
+        if let Some(frame) = self.frames.pop() {
+            self.locals.truncate(frame.base);
+        }
+        while self.handlers.last().is_some_and(|h| h.frames > self.frames.len()) {
+            self.handlers.pop();
+        }
+    }
+
+    /// Returns from the innermost frame.
+    fn ret(&mut self) {
+        self.pop_frame();
+        if let Some(caller) = self.frames.last() {
+            self.pc = caller.pc;
+        }
+        self.switched = true;
+    }
+
+    /// Transfers control to the innermost handler, or exits the process if there is none.
+    fn raise(&mut self, error: VmError) -> VmResult<()> {
+        let value = error.to_value();
+        let Some(handler) = self.handlers.pop() else {
+            self.exit_reason = Some(value.unwrap_or_else(|_| Value::Object(PVObject::from(error.tag()))));
+            return Err(error);
+        };
+
+        // Failing to build the exception value is itself an exception, the next handler out gets it.
+        let value = match value {
+            Ok(v) => v,
+            Err(e) => return self.raise(e),
+        };
+        self.stack.truncate(handler.depth);
+        self.push(value);
+        self.frames.truncate(handler.frames);
+        self.locals.truncate(handler.locals);
+        self.pc = handler.pc;
+        self.switched = true;
+        Ok(())
+    }
+
+    pub(super) fn pop_into(&mut self, into: &mut Value) -> VmResult<()> {
+        let slot = self.stack.pop().ok_or(VmError::StackUnderflow { needed: 1, depth: 0 })?;
+        *into = slot.into_value();
         Ok(())
     }
 
+    /// # Safety
+    /// The stack must not be empty, and `into` must be valid for writes. Whatever `into` held
+    /// is overwritten without being dropped.
     pub(super) unsafe fn pop_into_unchecked(&mut self, into: *mut Value) {
-        let len = self.stack.len()-1;
-        let src = self.stack.get_unchecked_mut(len) as *const Value;
-        let dest = into as *mut Value;
-        dest.copy_from(src, 1);
-        self.stack.set_len(len);
+        let slot = self.stack.pop().unwrap_unchecked();
+        into.write(slot.into_value());
     }
 
-    #[must_use]
     pub(super) fn pop2_into(&mut self, x: &mut Value, y: &mut Value) -> VmResult<()> {
         if self.stack.len() < 2 {
-            return Err(VmError::StackUnderflow());
+            return Err(VmError::StackUnderflow { needed: 2, depth: self.stack.len() });
         }
 
         if !x.is_null() || !y.is_null() {
-            //We'd need to drop it. No.
-            unimplemented!()
+            unreachable!("pop2_into overwrites without dropping, callers pass fresh nulls");
         }
 
         unsafe {
-            self.pop_into_unchecked(x as *mut Value);
+            // Top of the stack is the last operand, as in the stack comments.
             self.pop_into_unchecked(y as *mut Value);
+            self.pop_into_unchecked(x as *mut Value);
         }
         Ok(())
     }
 
-    #[must_use]
     pub(super) fn pop3_into(&mut self, x: &mut Value, y: &mut Value, z: &mut Value) -> VmResult<()> {
         if self.stack.len() < 3 {
-            return Err(VmError::StackUnderflow());
+            return Err(VmError::StackUnderflow { needed: 3, depth: self.stack.len() });
         }
 
         if !x.is_null() || !y.is_null() || !z.is_null() {
-            //We'd need to drop it. No.
-            unimplemented!()
+            unreachable!("pop3_into overwrites without dropping, callers pass fresh nulls");
         }
 
         unsafe {
-            self.pop_into_unchecked(x as *mut Value);
-            self.pop_into_unchecked(y as *mut Value);
             self.pop_into_unchecked(z as *mut Value);
+            self.pop_into_unchecked(y as *mut Value);
+            self.pop_into_unchecked(x as *mut Value);
         }
         Ok(())
     }
 
-    pub(super) fn push(&mut self, v: Value) {
-        self.stack.push(v)
+    pub fn push(&mut self, v: Value) {
+        self.stack.push(Slot::from(v))
+    }
+
+    pub fn pop(&mut self) -> VmResult<Value> {
+        let mut v = Value::Null;
+        self.pop_into(&mut v)?;
+        Ok(v)
+    }
+
+    /// The number of values on the stack.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// The values on the stack, bottom first.
+    pub fn stack(&self) -> impl Iterator<Item = Value> + '_ {
+        self.stack.iter().map(Slot::to_value)
+    }
+
+    /// Roughly how many bytes the process holds: its stack, frames and locals, and every object
+    /// they reach, each counted once. Objects shared with other processes count for each of
+    /// them, while module code and constants count for none. This walks the whole heap, so it
+    /// takes time in proportion to it.
+    pub fn heap_size(&self) -> usize {
+        let mut size = (self.stack.capacity() + self.locals.capacity()) * size_of::<Slot>()
+            + self.frames.capacity() * size_of::<Frame>()
+            + self.handlers.capacity() * size_of::<Handler>();
+        let mut seen = BTreeSet::new();
+        let mut pending: Vec<Value> = self.stack.iter().chain(&self.locals).map(Slot::to_value).collect();
+        while let Some(v) = pending.pop() {
+            let Value::Object(o) = v else { continue };
+            if !seen.insert(o.as_ptr()) {
+                continue;
+            }
+            let o = o.get();
+            size += o.size();
+            o.for_each_child(|c| pending.push(c.clone()));
+        }
+        size
     }
 
+    // Inlined into the dispatch loop in run, the call overhead is most of the cost of an op.
+    #[inline(always)]
     pub fn run_op(&mut self, o: Operation) -> VmResult<()> {
         match o {
-            Operation::Trap => todo!(),
+            // Reaching a Trap means running zeroed or otherwise bogus code.
+            Operation::Trap => return Err(VmError::Trap(self.pc.saturating_sub(1))),
+            Operation::Syscall(n) => {
+                let table = self.syscalls.clone();
+                let handler = table.as_deref().and_then(|t| t.get(n));
+                let handler = handler.ok_or(VmError::UnknownSyscall(n))?;
+                handler(self)?;
+            }
             Operation::Add(k) => {
                 fn add<T: WrappingAdd>(x: T, y: T) -> T {
                     x.wrapping_add(&y)
                 }
 
-                let mut x = Value::Null;
-                let mut y = Value::Null;
-                self.pop2_into(&mut x, &mut y)?;
-                let v = match k {
-                    PrimOpKind::U8 => add::<u8>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I8 => add::<i8>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::U16 => add::<u16>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I16 => add::<i16>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::U32 => add::<u32>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I32 => add::<i32>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::U64 => add::<u64>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I64 => add::<i64>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                };
-
-                self.push(v);
+                match k {
+                    PrimOpKind::U8 => self.int_op2::<u8>(add)?,
+                    PrimOpKind::I8 => self.int_op2::<i8>(add)?,
+                    PrimOpKind::U16 => self.int_op2::<u16>(add)?,
+                    PrimOpKind::I16 => self.int_op2::<i16>(add)?,
+                    PrimOpKind::U32 => self.int_op2::<u32>(add)?,
+                    PrimOpKind::I32 => self.int_op2::<i32>(add)?,
+                    PrimOpKind::U64 => self.int_op2::<u64>(add)?,
+                    PrimOpKind::I64 => self.int_op2::<i64>(add)?,
+                }
             }
             Operation::AddImm(k, imm) => {
                 fn add<T: WrappingAdd>(x: T, y: T) -> T {
                     x.wrapping_add(&y)
                 }
 
-                let mut x = Value::Null;
-                self.pop_into(&mut x)?;
-                let v = match k {
-                    PrimOpKind::U8 => add::<u8>(Self::as_num(&x)?, imm.read_u8(k)).into(),
-                    PrimOpKind::I8 => add::<i8>(Self::as_num(&x)?, imm.read_i8(k)).into(),
-                    PrimOpKind::U16 => add::<u16>(Self::as_num(&x)?, imm.read_u16(k)).into(),
-                    PrimOpKind::I16 => add::<i16>(Self::as_num(&x)?, imm.read_i16(k)).into(),
-                    PrimOpKind::U32 => add::<u32>(Self::as_num(&x)?, imm.read_u32(k)).into(),
-                    PrimOpKind::I32 => add::<i32>(Self::as_num(&x)?, imm.read_i32(k)).into(),
-                    PrimOpKind::U64 => add::<u64>(Self::as_num(&x)?, imm.read_u64(k)).into(),
-                    PrimOpKind::I64 => add::<i64>(Self::as_num(&x)?, imm.read_i64(k)).into(),
-                };
-
-                self.push(v);
+                match k {
+                    PrimOpKind::U8 => self.int_op1::<u8>(|x| add(x, imm.read_u8(k)))?,
+                    PrimOpKind::I8 => self.int_op1::<i8>(|x| add(x, imm.read_i8(k)))?,
+                    PrimOpKind::U16 => self.int_op1::<u16>(|x| add(x, imm.read_u16(k)))?,
+                    PrimOpKind::I16 => self.int_op1::<i16>(|x| add(x, imm.read_i16(k)))?,
+                    PrimOpKind::U32 => self.int_op1::<u32>(|x| add(x, imm.read_u32(k)))?,
+                    PrimOpKind::I32 => self.int_op1::<i32>(|x| add(x, imm.read_i32(k)))?,
+                    PrimOpKind::U64 => self.int_op1::<u64>(|x| add(x, imm.read_u64(k)))?,
+                    PrimOpKind::I64 => self.int_op1::<i64>(|x| add(x, imm.read_i64(k)))?,
+                }
             }
             Operation::Sub(k) => {
                 fn sub<T: WrappingSub>(x: T, y: T) -> T {
                     x.wrapping_sub(&y)
                 }
 
-                let mut x = Value::Null;
-                let mut y = Value::Null;
-                self.pop2_into(&mut x, &mut y)?;
-                let v = match k {
-                    PrimOpKind::U8 => sub::<u8>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I8 => sub::<i8>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::U16 => sub::<u16>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I16 => sub::<i16>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::U32 => sub::<u32>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I32 => sub::<i32>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::U64 => sub::<u64>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I64 => sub::<i64>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                };
-
-                self.push(v);
+                match k {
+                    PrimOpKind::U8 => self.int_op2::<u8>(sub)?,
+                    PrimOpKind::I8 => self.int_op2::<i8>(sub)?,
+                    PrimOpKind::U16 => self.int_op2::<u16>(sub)?,
+                    PrimOpKind::I16 => self.int_op2::<i16>(sub)?,
+                    PrimOpKind::U32 => self.int_op2::<u32>(sub)?,
+                    PrimOpKind::I32 => self.int_op2::<i32>(sub)?,
+                    PrimOpKind::U64 => self.int_op2::<u64>(sub)?,
+                    PrimOpKind::I64 => self.int_op2::<i64>(sub)?,
+                }
             }
             Operation::SubImm(k, imm) => {
                 fn sub<T: WrappingSub>(x: T, y: T) -> T {
                     x.wrapping_sub(&y)
                 }
 
-                let mut x = Value::Null;
-                self.pop_into(&mut x)?;
-                let v = match k {
-                    PrimOpKind::U8 => sub::<u8>(Self::as_num(&x)?, imm.read_u8(k)).into(),
-                    PrimOpKind::I8 => sub::<i8>(Self::as_num(&x)?, imm.read_i8(k)).into(),
-                    PrimOpKind::U16 => sub::<u16>(Self::as_num(&x)?, imm.read_u16(k)).into(),
-                    PrimOpKind::I16 => sub::<i16>(Self::as_num(&x)?, imm.read_i16(k)).into(),
-                    PrimOpKind::U32 => sub::<u32>(Self::as_num(&x)?, imm.read_u32(k)).into(),
-                    PrimOpKind::I32 => sub::<i32>(Self::as_num(&x)?, imm.read_i32(k)).into(),
-                    PrimOpKind::U64 => sub::<u64>(Self::as_num(&x)?, imm.read_u64(k)).into(),
-                    PrimOpKind::I64 => sub::<i64>(Self::as_num(&x)?, imm.read_i64(k)).into(),
-                };
-
-                self.push(v);
+                match k {
+                    PrimOpKind::U8 => self.int_op1::<u8>(|x| sub(x, imm.read_u8(k)))?,
+                    PrimOpKind::I8 => self.int_op1::<i8>(|x| sub(x, imm.read_i8(k)))?,
+                    PrimOpKind::U16 => self.int_op1::<u16>(|x| sub(x, imm.read_u16(k)))?,
+                    PrimOpKind::I16 => self.int_op1::<i16>(|x| sub(x, imm.read_i16(k)))?,
+                    PrimOpKind::U32 => self.int_op1::<u32>(|x| sub(x, imm.read_u32(k)))?,
+                    PrimOpKind::I32 => self.int_op1::<i32>(|x| sub(x, imm.read_i32(k)))?,
+                    PrimOpKind::U64 => self.int_op1::<u64>(|x| sub(x, imm.read_u64(k)))?,
+                    PrimOpKind::I64 => self.int_op1::<i64>(|x| sub(x, imm.read_i64(k)))?,
+                }
             }
             Operation::Mul(k) => {
                 fn mul<T: WrappingMul>(x: T, y: T) -> T {
                     x.wrapping_mul(&y)
                 }
 
-                let mut x = Value::Null;
-                let mut y = Value::Null;
-                self.pop2_into(&mut x, &mut y)?;
-                let v = match k {
-                    PrimOpKind::U8 => mul::<u8>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I8 => mul::<i8>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::U16 => mul::<u16>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I16 => mul::<i16>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::U32 => mul::<u32>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I32 => mul::<i32>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::U64 => mul::<u64>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                    PrimOpKind::I64 => mul::<i64>(Self::as_num(&x)?, Self::as_num(&y)?).into(),
-                };
-
-                self.push(v);
+                match k {
+                    PrimOpKind::U8 => self.int_op2::<u8>(mul)?,
+                    PrimOpKind::I8 => self.int_op2::<i8>(mul)?,
+                    PrimOpKind::U16 => self.int_op2::<u16>(mul)?,
+                    PrimOpKind::I16 => self.int_op2::<i16>(mul)?,
+                    PrimOpKind::U32 => self.int_op2::<u32>(mul)?,
+                    PrimOpKind::I32 => self.int_op2::<i32>(mul)?,
+                    PrimOpKind::U64 => self.int_op2::<u64>(mul)?,
+                    PrimOpKind::I64 => self.int_op2::<i64>(mul)?,
+                }
             }
             Operation::MulImm(k, imm) => {
                 fn mul<T: WrappingMul>(x: T, y: T) -> T {
                     x.wrapping_mul(&y)
                 }
 
-                let mut x = Value::Null;
-                self.pop_into(&mut x)?;
-                let v = match k {
-                    PrimOpKind::U8 => mul::<u8>(Self::as_num(&x)?, imm.read_u8(k)).into(),
-                    PrimOpKind::I8 => mul::<i8>(Self::as_num(&x)?, imm.read_i8(k)).into(),
-                    PrimOpKind::U16 => mul::<u16>(Self::as_num(&x)?, imm.read_u16(k)).into(),
-                    PrimOpKind::I16 => mul::<i16>(Self::as_num(&x)?, imm.read_i16(k)).into(),
-                    PrimOpKind::U32 => mul::<u32>(Self::as_num(&x)?, imm.read_u32(k)).into(),
-                    PrimOpKind::I32 => mul::<i32>(Self::as_num(&x)?, imm.read_i32(k)).into(),
-                    PrimOpKind::U64 => mul::<u64>(Self::as_num(&x)?, imm.read_u64(k)).into(),
-                    PrimOpKind::I64 => mul::<i64>(Self::as_num(&x)?, imm.read_i64(k)).into(),
+                match k {
+                    PrimOpKind::U8 => self.int_op1::<u8>(|x| mul(x, imm.read_u8(k)))?,
+                    PrimOpKind::I8 => self.int_op1::<i8>(|x| mul(x, imm.read_i8(k)))?,
+                    PrimOpKind::U16 => self.int_op1::<u16>(|x| mul(x, imm.read_u16(k)))?,
+                    PrimOpKind::I16 => self.int_op1::<i16>(|x| mul(x, imm.read_i16(k)))?,
+                    PrimOpKind::U32 => self.int_op1::<u32>(|x| mul(x, imm.read_u32(k)))?,
+                    PrimOpKind::I32 => self.int_op1::<i32>(|x| mul(x, imm.read_i32(k)))?,
+                    PrimOpKind::U64 => self.int_op1::<u64>(|x| mul(x, imm.read_u64(k)))?,
+                    PrimOpKind::I64 => self.int_op1::<i64>(|x| mul(x, imm.read_i64(k)))?,
+                }
+            }
+            Operation::Div(k) => match k {
+                PrimOpKind::U8 => self.div_op::<u8>(None)?,
+                PrimOpKind::I8 => self.div_op::<i8>(None)?,
+                PrimOpKind::U16 => self.div_op::<u16>(None)?,
+                PrimOpKind::I16 => self.div_op::<i16>(None)?,
+                PrimOpKind::U32 => self.div_op::<u32>(None)?,
+                PrimOpKind::I32 => self.div_op::<i32>(None)?,
+                PrimOpKind::U64 => self.div_op::<u64>(None)?,
+                PrimOpKind::I64 => self.div_op::<i64>(None)?,
+            },
+            Operation::DivImm(k, imm) => match k {
+                PrimOpKind::U8 => self.div_op::<u8>(Some(imm.read_u8(k)))?,
+                PrimOpKind::I8 => self.div_op::<i8>(Some(imm.read_i8(k)))?,
+                PrimOpKind::U16 => self.div_op::<u16>(Some(imm.read_u16(k)))?,
+                PrimOpKind::I16 => self.div_op::<i16>(Some(imm.read_i16(k)))?,
+                PrimOpKind::U32 => self.div_op::<u32>(Some(imm.read_u32(k)))?,
+                PrimOpKind::I32 => self.div_op::<i32>(Some(imm.read_i32(k)))?,
+                PrimOpKind::U64 => self.div_op::<u64>(Some(imm.read_u64(k)))?,
+                PrimOpKind::I64 => self.div_op::<i64>(Some(imm.read_i64(k)))?,
+            },
+            Operation::PushImm(k, v) => {
+                let s = match k {
+                    PrimOpKind::U8 => Slot::from_num(v.read_u8(k)),
+                    PrimOpKind::I8 => Slot::from_num(v.read_i8(k)),
+                    PrimOpKind::U16 => Slot::from_num(v.read_u16(k)),
+                    PrimOpKind::I16 => Slot::from_num(v.read_i16(k)),
+                    PrimOpKind::U32 => Slot::from_num(v.read_u32(k)),
+                    PrimOpKind::I32 => Slot::from_num(v.read_i32(k)),
+                    PrimOpKind::U64 => Slot::from_num(v.read_u64(k)),
+                    PrimOpKind::I64 => Slot::from_num(v.read_i64(k)),
                 };
-
-                self.push(v);
+                self.stack.push(s);
             }
-            Operation::Div(_k) => todo!(),
-            Operation::DivImm(_k, _imm) => todo!(),
-            Operation::PushImm(k, v) => self.push(Value::Int(k, v.as_aligned())),
             Operation::PushAtom(a) => self.push(Value::Object(PVObject::from(a))),
             Operation::MakeObject(_) => self.push(Value::Object(PVObject::make_map()?)),
             Operation::MakeArray => self.push(Value::Object(PVObject::make_array()?)),
@@ -254,79 +640,489 @@ impl Process {
                 let mut arr = Value::Null;
                 let mut idx = Value::Null;
                 self.pop2_into(&mut arr, &mut idx)?;
-                let idx = Self::as_num::<usize>(&idx)?;
-                let arr = Self::as_list(&arr)?;
-                self.push(arr.deref().load(idx).unwrap_or(Value::Null));
+                let v = Self::as_object(&arr, ValueKind::Array)?.index(&idx)?;
+                self.push(v);
             }
             Operation::SetArray => {
                 let mut arr = Value::Null;
                 let mut idx = Value::Null;
                 let mut value = Value::Null;
                 self.pop3_into(&mut arr, &mut idx, &mut value)?;
-                let idx = Self::as_num::<usize>(&idx)?;
-                let mut arr = Self::as_list_mut(&arr)?;
-                arr.deref_mut().store(idx, value)?;
+                let Value::Object(o) = &arr else {
+                    return Err(VmError::PopExpectedType { expected: ValueKind::Array, found: arr.kind() });
+                };
+                let is_dict = matches!(&*o.get(), PVObjectType::Dict(_));
+                if is_dict {
+                    // Checking the key looks into it, and it may be the dict itself, so the
+                    // dict is taken out of its cell meanwhile, and looks empty from there.
+                    let mut dict = o.replace(PVObjectType::Dict(Default::default()));
+                    let r = dict.set_index(idx, value);
+                    o.replace(dict);
+                    r?;
+                } else {
+                    o.get_mut().set_index(idx, value)?;
+                }
             }
             Operation::Drop => {
-                let mut x = Value::Null;
-                self.pop_into(&mut x)?;
-                core::mem::drop(x);
+                self.need(1)?;
+                self.stack.pop();
             }
             Operation::Dup => {
-                let mut x = Value::Null;
-                self.pop_into(&mut x)?;
-                self.push(x.clone());
-                self.push(x)
+                self.need(1)?;
+                let x = self.stack[self.stack.len() - 1].clone();
+                self.stack.push(x);
             }
             Operation::Swap => {
+                self.need(2)?;
+                let len = self.stack.len();
+                self.stack.swap(len - 2, len - 1);
+            }
+            Operation::Over => self.pick(1)?,
+            Operation::Pick(n) => self.pick(n as usize)?,
+            Operation::Rot => self.roll(2)?,
+            Operation::Roll(n) => self.roll(n as usize)?,
+            Operation::MinusRot => {
+                self.need(3)?;
+                let len = self.stack.len();
+                self.stack[len - 3..].rotate_right(1);
+            }
+            Operation::Nip => {
+                self.need(2)?;
+                let len = self.stack.len();
+                self.stack.swap_remove(len - 2);
+            }
+            Operation::Tuck => {
+                self.need(2)?;
+                let len = self.stack.len();
+                let y = self.stack[len - 1].clone();
+                self.stack.insert(len - 2, y);
+            }
+            Operation::TwoDup => {
+                self.need(2)?;
+                let len = self.stack.len();
+                self.stack.try_reserve(2)?;
+                self.stack.extend_from_within(len - 2..);
+            }
+            Operation::TwoDrop => {
+                self.need(2)?;
+                self.stack.truncate(self.stack.len() - 2);
+            }
+            Operation::TwoSwap => {
+                self.need(4)?;
+                let len = self.stack.len();
+                self.stack[len - 4..].rotate_left(2);
+            }
+            Operation::Depth => self.stack.push(Slot::from_num(self.stack.len() as u64)),
+            Operation::Compare => {
+                let mut x = Value::Null;
+                let mut y = Value::Null;
+                self.pop2_into(&mut x, &mut y)?;
+                self.stack.push(Slot::from_num(x.term_cmp(&y) as i64));
+            }
+            Operation::Hash => {
+                let v = self.pop()?;
+                self.stack.push(Slot::from_num(v.structural_hash()));
+            }
+            Operation::MakeDict => self.push(Value::Object(PVObject::make_dict()?)),
+            Operation::MapKeys => self.map_to_array(PVObjectType::map_keys)?,
+            Operation::MapValues => self.map_to_array(PVObjectType::map_values)?,
+            Operation::MapEntries => self.map_to_array(PVObjectType::map_entries)?,
+            Operation::MapMerge => {
+                let mut x = Value::Null;
+                let mut y = Value::Null;
+                self.pop2_into(&mut x, &mut y)?;
+                let (x, y) = (Self::as_object(&x, ValueKind::Map)?, Self::as_object(&y, ValueKind::Map)?);
+                let merged = x.merge(&y)?;
+                self.push(Value::Object(PVObject::build_handle(merged)?));
+            }
+            Operation::MapFilter => {
+                let mut map = Value::Null;
+                let mut keys = Value::Null;
+                self.pop2_into(&mut map, &mut keys)?;
+                let keys = Self::as_object(&keys, ValueKind::Array)?;
+                let PVObjectType::Array(keys) = &*keys else {
+                    return Err(VmError::PopExpectedType { expected: ValueKind::Array, found: keys.kind() });
+                };
+                let filtered = Self::as_object(&map, ValueKind::Map)?.filter(keys)?;
+                self.push(Value::Object(PVObject::build_handle(filtered)?));
+            }
+            Operation::MakeBinary => {
+                let n = map::index(&self.pop()?)?;
+                self.push(Value::Object(PVObject::make_binary(Binary::zeroed(n)?)?));
+            }
+            Operation::Length => {
+                let v = self.pop()?;
+                let o = Self::as_object(&v, ValueKind::Array)?;
+                let n = o.length().ok_or(VmError::PopExpectedType { expected: ValueKind::Array, found: o.kind() })?;
+                self.stack.push(Slot::from_num(n as u64));
+            }
+            Operation::BinarySlice => {
+                let mut bin = Value::Null;
+                let mut start = Value::Null;
+                let mut len = Value::Null;
+                self.pop3_into(&mut bin, &mut start, &mut len)?;
+                let slice = Self::as_binary(&bin)?.slice(map::index(&start)?, map::index(&len)?)?;
+                self.push(Value::Object(PVObject::make_binary(slice)?));
+            }
+            Operation::BinaryConcat => {
                 let mut x = Value::Null;
                 let mut y = Value::Null;
                 self.pop2_into(&mut x, &mut y)?;
-                self.push(y);
-                self.push(x);
+                let joined = Self::as_binary(&x)?.concat(&*Self::as_binary(&y)?)?;
+                self.push(Value::Object(PVObject::make_binary(joined)?));
+            }
+            Operation::BinaryRead(k, endian) => {
+                let mut bin = Value::Null;
+                let mut offset = Value::Null;
+                self.pop2_into(&mut bin, &mut offset)?;
+                let v = Self::as_binary(&bin)?.read(map::index(&offset)?, k, endian)?;
+                self.push(v);
+            }
+            Operation::BinaryWrite(k, endian) => {
+                let mut bin = Value::Null;
+                let mut offset = Value::Null;
+                let mut n = Value::Null;
+                self.pop3_into(&mut bin, &mut offset, &mut n)?;
+                Self::as_binary(&bin)?.write(map::index(&offset)?, &n, k, endian)?;
+            }
+            Operation::MakePacked(k) => {
+                let n = map::index(&self.pop()?)?;
+                self.push(Value::Object(PVObject::make_packed(Packed::zeroed(k, n)?)?));
+            }
+            Operation::PackedFill => {
+                let n = self.pop()?;
+                let count = map::index(&self.pop()?)?;
+                let start = map::index(&self.pop()?)?;
+                let arr = self.pop()?;
+                Self::as_packed_mut(&arr)?.fill(start, count, &n)?;
+            }
+            Operation::PackedCopy => {
+                let count = map::index(&self.pop()?)?;
+                let to = map::index(&self.pop()?)?;
+                let dst = self.pop()?;
+                let from = map::index(&self.pop()?)?;
+                let src = self.pop()?;
+                if Self::same_object(&src, &dst) {
+                    Self::as_packed_mut(&dst)?.copy_within(from, to, count)?;
+                } else {
+                    let src = Self::as_packed(&src)?;
+                    Self::as_packed_mut(&dst)?.copy_from(to, &src, from, count)?;
+                }
+            }
+            Operation::PackedCombine(op) => {
+                let mut dst = Value::Null;
+                let mut src = Value::Null;
+                self.pop2_into(&mut dst, &mut src)?;
+                if Self::same_object(&src, &dst) {
+                    Self::as_packed_mut(&dst)?.combine_self(op);
+                } else {
+                    let src = Self::as_packed(&src)?;
+                    Self::as_packed_mut(&dst)?.combine(op, &src)?;
+                }
+            }
+            Operation::PackedReduce(op) => {
+                let arr = self.pop()?;
+                let v = Self::as_packed(&arr)?.reduce(op);
+                self.push(v);
+            }
+            Operation::Jump(target) => self.pc = target as usize,
+            Operation::JumpIfZero(target) => {
+                self.need(1)?;
+                let n = self.stack.pop().unwrap();
+                match n.as_int() {
+                    Some((_, 0)) => self.pc = target as usize,
+                    Some(_) => {}
+                    None => {
+                        return Err(VmError::PopExpectedType {
+                            expected: ValueKind::Int,
+                            found: n.to_value().kind(),
+                        })
+                    }
+                }
             }
             Operation::DebugOut => {
-                #[cfg(std)]
-                {
-                    println!("{:?}", self.pop());
+                let v = self.pop()?;
+                match &self.output {
+                    Some(device) => device.write_value(&v),
+                    #[cfg(feature = "std")]
+                    None => StdoutDevice.write_value(&v),
+                    #[cfg(not(feature = "std"))]
+                    None => {}
+                }
+            }
+            Operation::Try(handler) => {
+                self.handlers.try_reserve(1)?;
+                self.handlers.push(Handler {
+                    pc: handler as usize,
+                    depth: self.stack.len(),
+                    frames: self.frames.len(),
+                    locals: self.locals.len(),
+                });
+            }
+            Operation::EndTry(next) => {
+                self.handlers.pop().ok_or(VmError::UnbalancedTry())?;
+                self.pc = next as usize;
+            }
+            Operation::Throw => {
+                let mut x = Value::Null;
+                self.pop_into(&mut x)?;
+                return Err(VmError::Thrown(x));
+            }
+            Operation::Call(f) => {
+                let m = self.module()?;
+                self.call(m, f)?;
+            }
+            Operation::CallImport(i) => {
+                let import = self.module()?.import(i);
+                let registry = self.registry.as_ref().ok_or(VmError::UndefinedFunction {
+                    module: import.module,
+                    function: import.function,
+                    arity: import.arity,
+                })?;
+                let callee = registry.borrow().resolve(import)?;
+                match callee {
+                    Callee::Function(m, f) => self.call(m, f)?,
+                    Callee::Native(f) => {
+                        self.need(import.arity as usize)?;
+                        f(self)?;
+                    }
+                }
+            }
+            Operation::Return => self.ret(),
+            Operation::PushConst(c) => {
+                let v = self.module()?.constant(c).clone();
+                self.push(v);
+            }
+            Operation::MakeClosure(f, n) => {
+                let n = n as usize;
+                self.need(n)?;
+                let mut captured = Vec::new();
+                captured.try_reserve(n)?;
+                captured.extend(self.stack.drain(self.stack.len() - n..).map(Slot::into_value));
+                let closure = Closure::new(self.module()?, f, captured);
+                self.push(Value::Object(PVObject::make_function(closure)?));
+            }
+            Operation::CallIndirect(n) => {
+                self.need(n as usize + 1)?;
+                let fun = self.pop()?;
+                let (m, f) = self.push_captured(&fun, n)?;
+                self.call(m, f)?;
+            }
+            Operation::TailCall(f) => {
+                let m = self.module()?;
+                self.tail_call(m, f)?;
+            }
+            Operation::TailCallIndirect(n) => {
+                self.need(n as usize + 1)?;
+                let fun = self.pop()?;
+                let (m, f) = self.push_captured(&fun, n)?;
+                self.tail_call(m, f)?;
+            }
+            Operation::LoadLocal(i) => {
+                let s = self.frame_locals().get(i as usize).ok_or(VmError::NoSuchLocal(i))?.clone();
+                self.stack.push(s);
+            }
+            Operation::StoreLocal(i) => {
+                self.need(1)?;
+                if i as usize >= self.frame_locals().len() {
+                    return Err(VmError::NoSuchLocal(i));
                 }
+                let s = self.stack.pop().unwrap();
+                self.frame_locals()[i as usize] = s;
+            }
+            Operation::LoadGlobal(i) => {
+                let v = self.module()?.global(i);
+                self.push(v);
             }
-            Operation::__Final => todo!(),
+            Operation::StoreGlobal(i) => {
+                let m = self.module()?;
+                let v = self.pop()?;
+                m.set_global(i, v);
+            }
+            // Only there to count the opcodes, validation rejects it but raw code can hold it.
+            Operation::__Final => return Err(VmError::MalformedModule("__Final is not an instruction")),
+        }
+        Ok(())
+    }
+
+    /// Fails unless the stack holds at least `n` values.
+    #[inline(always)]
+    fn need(&self, n: usize) -> VmResult<()> {
+        if self.stack.len() < n {
+            return Err(VmError::StackUnderflow { needed: n, depth: self.stack.len() });
         }
         Ok(())
     }
 
-    fn as_num<T>(v: &Value) -> VmResult<T>
+    /// Pushes a copy of the value `n` below the top.
+    #[inline(always)]
+    fn pick(&mut self, n: usize) -> VmResult<()> {
+        self.need(n + 1)?;
+        let x = self.stack[self.stack.len() - 1 - n].clone();
+        self.stack.push(x);
+        Ok(())
+    }
+
+    /// Moves the value `n` below the top to the top.
+    #[inline(always)]
+    fn roll(&mut self, n: usize) -> VmResult<()> {
+        self.need(n + 1)?;
+        let len = self.stack.len();
+        self.stack[len - 1 - n..].rotate_left(1);
+        Ok(())
+    }
+
+    /// Reads an int slot as `T`, for the arithmetic ops.
+    #[inline(always)]
+    fn slot_num<T: Num>(s: &Slot) -> VmResult<T> {
+        s.as_num()
+            .ok_or_else(|| VmError::PopExpectedType { expected: ValueKind::Int, found: s.to_value().kind() })
+    }
+
+    /// Reads the top of the stack as `T`, leaving it there so the result can be written over
+    /// it. The operand is still consumed when it's the wrong type, like every other pop.
+    #[inline(always)]
+    fn top_num<T: Num>(&mut self) -> VmResult<T> {
+        let Some(top) = self.stack.last() else {
+            return Err(VmError::StackUnderflow { needed: 1, depth: 0 });
+        };
+        Self::slot_num(top).inspect_err(|_| {
+            self.stack.pop();
+        })
+    }
+
+    /// Checks the second operand of a binary op, consuming the first if it's the wrong type.
+    #[inline(always)]
+    fn second_num<T: Num>(&mut self, y: Slot) -> VmResult<T> {
+        Self::slot_num(&y).inspect_err(|_| {
+            self.stack.pop();
+        })
+    }
+
+    /// ( n -- f(n) )
+    #[inline(always)]
+    fn int_op1<T: Num>(&mut self, f: impl FnOnce(T) -> T) -> VmResult<()> {
+        let x = self.top_num::<T>()?;
+        self.stack.last_mut().unwrap().set_num(f(x));
+        Ok(())
+    }
+
+    /// ( n1 n2 -- f(n1, n2) )
+    #[inline(always)]
+    fn int_op2<T: Num>(&mut self, f: impl FnOnce(T, T) -> T) -> VmResult<()> {
+        self.need(2)?;
+        let y = self.stack.pop().unwrap();
+        let x = self.top_num::<T>()?;
+        let y = self.second_num::<T>(y)?;
+        self.stack.last_mut().unwrap().set_num(f(x, y));
+        Ok(())
+    }
+
+    /// ( n1 n2 -- quot rem ), or ( n1 -- quot rem ) with an immediate divisor.
+    /// Division rounds towards zero, and MIN / -1 wraps like the other arithmetic ops.
+    fn div_op<T>(&mut self, imm: Option<T>) -> VmResult<()>
     where
-        T: Integer + Pod + FromPrimitive,
+        T: Num + CheckedDiv + CheckedRem + WrappingNeg + Integer,
     {
-        if let Value::Int(_, _) = v {
-            Ok(v.reinterpret())
-        } else {
-            Err(error::VmError::PopExpectedType())
+        let (x, y) = match imm {
+            Some(y) => (self.top_num::<T>()?, y),
+            None => {
+                self.need(2)?;
+                let y = self.stack.pop().unwrap();
+                let x = self.top_num::<T>()?;
+                (x, self.second_num::<T>(y)?)
+            }
+        };
+        if y.is_zero() {
+            self.stack.pop();
+            return Err(VmError::DivideByZero());
         }
+        let (q, r) = match (x.checked_div(&y), x.checked_rem(&y)) {
+            (Some(q), Some(r)) => (q, r),
+            _ => (x.wrapping_neg(), T::zero()),
+        };
+        self.stack.try_reserve(1)?;
+        self.stack.last_mut().unwrap().set_num(q);
+        self.stack.push(Slot::from_num(r));
+        Ok(())
     }
 
-    fn as_list(v: &Value) -> VmResult<Ref<'_, PVObjectType>> {
+    /// Checks that a function value takes `arity` arguments and pushes its captured values
+    /// after them, returning the function to call.
+    pub(super) fn push_captured(&mut self, v: &Value, arity: u16) -> VmResult<(Rc<LoadedModule>, u32)> {
         if let Value::Object(o) = v {
-            let r = o.get();
-            if let PVObjectType::Array(_) = r.deref() {
-                return Ok(r);
+            if let PVObjectType::Function(c) = &*o.get() {
+                if c.arity() != arity {
+                    return Err(VmError::BadArity {
+                        expected: c.arity(),
+                        found: arity,
+                    });
+                }
+                self.stack.try_reserve(c.captured().len())?;
+                for v in c.captured() {
+                    self.push(v.clone());
+                }
+                return Ok(c.target());
             }
         }
-
-        Err(error::VmError::PopExpectedArray())
+        Err(VmError::PopExpectedType {
+            expected: ValueKind::Function,
+            found: v.kind(),
+        })
     }
 
-    fn as_list_mut(v: &Value) -> VmResult<RefMut<'_, PVObjectType>> {
-        if let Value::Object(o) = v {
-            let r = o.get_mut();
-            if let PVObjectType::Array(_) = r.deref() {
-                return Ok(r);
-            }
+    /// Borrows the object a value holds, failing with PopExpectedType, expecting `kind`, on
+    /// values that aren't objects.
+    fn as_object(v: &Value, kind: ValueKind) -> VmResult<Ref<'_, PVObjectType>> {
+        match v {
+            Value::Object(o) => Ok(o.get()),
+            _ => Err(VmError::PopExpectedType { expected: kind, found: v.kind() }),
         }
+    }
+
+    /// Borrows the binary a value holds, failing with PopExpectedType on anything else.
+    fn as_binary(v: &Value) -> VmResult<Ref<'_, Binary>> {
+        let o = Self::as_object(v, ValueKind::Binary)?;
+        Ref::filter_map(o, |o| match o {
+            PVObjectType::Binary(b) => Some(b),
+            _ => None,
+        })
+        .map_err(|o| VmError::PopExpectedType { expected: ValueKind::Binary, found: o.kind() })
+    }
 
-        Err(error::VmError::PopExpectedArray())
+    /// Borrows the packed array a value holds, failing with PopExpectedType on anything else.
+    fn as_packed(v: &Value) -> VmResult<Ref<'_, Packed>> {
+        let o = Self::as_object(v, ValueKind::Packed)?;
+        Ref::filter_map(o, |o| match o {
+            PVObjectType::Packed(p) => Some(p),
+            _ => None,
+        })
+        .map_err(|o| VmError::PopExpectedType { expected: ValueKind::Packed, found: o.kind() })
+    }
+
+    fn as_packed_mut(v: &Value) -> VmResult<RefMut<'_, Packed>> {
+        let Value::Object(o) = v else {
+            return Err(VmError::PopExpectedType { expected: ValueKind::Packed, found: v.kind() });
+        };
+        RefMut::filter_map(o.get_mut(), |o| match o {
+            PVObjectType::Packed(p) => Some(p),
+            _ => None,
+        })
+        .map_err(|o| VmError::PopExpectedType { expected: ValueKind::Packed, found: o.kind() })
+    }
+
+    /// Whether two values are the same object, which can't be borrowed twice if one borrow is
+    /// mutable.
+    fn same_object(x: &Value, y: &Value) -> bool {
+        matches!((x, y), (Value::Object(a), Value::Object(b)) if a.as_ptr() == b.as_ptr())
+    }
+
+    /// Replaces a map or dict on top of the stack with an array made from it.
+    fn map_to_array(&mut self, f: fn(&PVObjectType) -> VmResult<Vec<Value>>) -> VmResult<()> {
+        let map = self.pop()?;
+        let elems = f(&*Self::as_object(&map, ValueKind::Map)?)?;
+        self.push(Value::Object(PVObject::make_array_from(elems)?));
+        Ok(())
     }
 }
 
@@ -338,7 +1134,12 @@ mod tests {
 
     use crate::vm::Value;
 
-    use super::{Operation, PrimOpKind, Process, error::VmResult};
+    use alloc::rc::Rc;
+
+    use super::{
+        statics, Operation, PVObjectType, PVString, PrimOpKind, Process, SyscallTable, ValueKind, VmError,
+        VmResult,
+    };
 
     #[test]
     pub fn add() -> VmResult<()> {
@@ -357,7 +1158,252 @@ mod tests {
         assert_eq!(process.stack.len(), 1);
         let mut v = Value::Null;
         process.pop_into(&mut v)?;
-        assert_eq!(Process::as_num::<i32>(&v)?, 2);
+        assert_eq!(v.reinterpret::<i32>()?, 2);
+        Ok(())
+    }
+
+    /// Two and three operand ops take them in stack comment order, the top of the stack last.
+    #[test]
+    pub fn operand_order() {
+        let push = |n: i32| Operation::PushImm(PrimOpKind::I32, n.into());
+        let index = Operation::PushImm(PrimOpKind::U64, 0u64.into());
+        let run = |prog: &[Operation]| {
+            let mut process = Process::new(Ipv6Addr::UNSPECIFIED).unwrap();
+            process.run(prog).unwrap();
+            let mut stack = vec![];
+            while let Ok(v) = process.pop() {
+                stack.insert(0, v.as_i128());
+            }
+            stack
+        };
+
+        // ( n1 n2 -- n1-n2 ), it was n2-n1.
+        assert_eq!(run(&[push(7), push(2), Operation::Sub(PrimOpKind::I32)]), [Some(5)]);
+        // ( n1 n2 -- quot rem ), Div swapped its operands back so it's unchanged.
+        assert_eq!(
+            run(&[push(7), push(2), Operation::Div(PrimOpKind::I32)]),
+            [Some(3), Some(1)]
+        );
+        // ( x y -- y x ), it left them as they were.
+        assert_eq!(run(&[push(1), push(2), Operation::Swap]), [Some(2), Some(1)]);
+        // ( arr idx val -- ) and ( arr idx -- val ), they took the array from the top.
+        let prog = [
+            Operation::MakeArray,
+            Operation::Dup,
+            index,
+            push(9),
+            Operation::SetArray,
+            index,
+            Operation::IndexArray,
+        ];
+        assert_eq!(run(&prog), [Some(9)]);
+    }
+
+    #[test]
+    pub fn fault_context() {
+        let prog = vec![
+            Operation::PushImm(PrimOpKind::I32, 1i64.into()),
+            Operation::MakeArray,
+            Operation::Add(PrimOpKind::I32),
+        ];
+
+        let mut process = Process::new(Ipv6Addr::UNSPECIFIED).unwrap();
+        let fault = process.run(&prog).unwrap_err();
+        assert_eq!(fault.pc, 2);
+        assert_eq!(fault.op, Some(Operation::Add(PrimOpKind::I32)));
+        assert_eq!(fault.pid, process.pid());
+        assert_eq!(fault.code(), 2);
+        assert!(matches!(
+            fault.error,
+            VmError::PopExpectedType { expected: ValueKind::Int, found: ValueKind::Array }
+        ));
+
+        let fault = process.run(&[Operation::Drop]).unwrap_err();
+        assert!(matches!(fault.error, VmError::StackUnderflow { needed: 1, depth: 0 }));
+    }
+
+    #[test]
+    pub fn try_catch() -> VmResult<()> {
+        let prog = vec![
+            Operation::PushImm(PrimOpKind::I32, 7i32.into()),
+            Operation::Try(6),
+            Operation::PushImm(PrimOpKind::I32, 1i32.into()),
+            Operation::PushImm(PrimOpKind::I32, 0i32.into()),
+            Operation::Div(PrimOpKind::I32),
+            Operation::EndTry(7),
+            // Handler, the stack is back to [7] plus the exception.
+            Operation::PushImm(PrimOpKind::I32, 9i32.into()),
+        ];
+
+        let mut process = Process::new(Ipv6Addr::UNSPECIFIED).unwrap();
+        process.run(&prog).unwrap();
+        assert_eq!(process.stack.len(), 3);
+
+        let mut v = Value::Null;
+        process.pop_into(&mut v)?;
+        assert_eq!(v.reinterpret::<i32>()?, 9);
+
+        let mut e = Value::Null;
+        process.pop_into(&mut e)?;
+        let Value::Object(e) = e else { panic!() };
+        let PVObjectType::Map(m) = &*e.get() else { panic!() };
+        let tag = m.get(&PVString::Atom(statics::ERROR)).unwrap();
+        let Value::Object(tag) = tag else { panic!() };
+        assert_eq!(*tag.get(), PVObjectType::String(PVString::Atom(statics::DIVIDE_BY_ZERO)));
+
+        let mut v = Value::Null;
+        process.pop_into(&mut v)?;
+        assert_eq!(v.reinterpret::<i32>()?, 7);
+        Ok(())
+    }
+
+    #[test]
+    pub fn throw_uncaught() {
+        let prog = vec![
+            Operation::Try(4),
+            Operation::PushAtom(statics::TIMEOUT),
+            Operation::Throw,
+            Operation::EndTry(5),
+            // Handler rethrows.
+            Operation::Throw,
+        ];
+
+        let mut process = Process::new(Ipv6Addr::UNSPECIFIED).unwrap();
+        let fault = process.run(&prog).unwrap_err();
+        assert_eq!(fault.pc, 4);
+        assert!(matches!(fault.error, VmError::Thrown(_)));
+        let Some(Value::Object(reason)) = process.exit_reason() else { panic!() };
+        assert_eq!(*reason.get(), PVObjectType::String(PVString::Atom(statics::TIMEOUT)));
+        assert!(process.stack.is_empty());
+    }
+
+    #[test]
+    pub fn div() -> VmResult<()> {
+        let prog = vec![
+            Operation::PushImm(PrimOpKind::I32, (-7i32).into()),
+            Operation::PushImm(PrimOpKind::I32, 2i32.into()),
+            Operation::Div(PrimOpKind::I32),
+            Operation::PushImm(PrimOpKind::I8, i8::MIN.into()),
+            Operation::DivImm(PrimOpKind::I8, (-1i8).into()),
+        ];
+
+        let mut process = Process::new(Ipv6Addr::UNSPECIFIED).unwrap();
+        process.run(&prog).unwrap();
+        let mut v = Value::Null;
+        process.pop_into(&mut v)?;
+        assert_eq!(v.reinterpret::<i8>()?, 0);
+        let mut v = Value::Null;
+        process.pop_into(&mut v)?;
+        assert_eq!(v.reinterpret::<i8>()?, i8::MIN);
+        let mut v = Value::Null;
+        process.pop_into(&mut v)?;
+        assert_eq!(v.reinterpret::<i32>()?, -1);
+        let mut v = Value::Null;
+        process.pop_into(&mut v)?;
+        assert_eq!(v.reinterpret::<i32>()?, -3);
+        Ok(())
+    }
+
+    #[test]
+    pub fn trap_syscall() -> VmResult<()> {
+        let mut process = Process::new(Ipv6Addr::UNSPECIFIED).unwrap();
+
+        // Zeroed code traps.
+        let fault = process.run(&[Operation::PushAtom(statics::OK), Operation::Trap]).unwrap_err();
+        assert!(matches!(fault.error, VmError::Trap(1)));
+
+        let mut table = SyscallTable::new();
+        table.install(7, |p| {
+            let v = p.pop()?.reinterpret::<u32>()?;
+            p.push((v * 2).into());
+            Ok(())
+        });
+        process.set_syscalls(Rc::new(table));
+
+        let prog = vec![
+            Operation::PushImm(PrimOpKind::U32, 21u32.into()),
+            Operation::Syscall(7),
+            Operation::Try(4),
+            Operation::Syscall(8),
+        ];
+        process.run(&prog).unwrap();
+
+        let e = process.pop()?;
+        let Value::Object(e) = e else { panic!() };
+        let PVObjectType::Map(m) = &*e.get() else { panic!() };
+        let n = m.get(&PVString::Atom(statics::SYSCALL)).unwrap();
+        assert_eq!(n.reinterpret::<u16>()?, 8);
+        assert_eq!(process.pop()?.reinterpret::<u32>()?, 42);
+        Ok(())
+    }
+
+    #[test]
+    pub fn stack_ops() {
+        use alloc::vec::Vec;
+
+        // Each op runs on a stack of 1..=5, top last.
+        let cases: &[(Operation, &[u8])] = &[
+            (Operation::Drop, &[1, 2, 3, 4]),
+            (Operation::Dup, &[1, 2, 3, 4, 5, 5]),
+            (Operation::Swap, &[1, 2, 3, 5, 4]),
+            (Operation::Over, &[1, 2, 3, 4, 5, 4]),
+            (Operation::Rot, &[1, 2, 4, 5, 3]),
+            (Operation::MinusRot, &[1, 2, 5, 3, 4]),
+            (Operation::Nip, &[1, 2, 3, 5]),
+            (Operation::Tuck, &[1, 2, 3, 5, 4, 5]),
+            (Operation::Pick(0), &[1, 2, 3, 4, 5, 5]),
+            (Operation::Pick(3), &[1, 2, 3, 4, 5, 2]),
+            (Operation::Roll(0), &[1, 2, 3, 4, 5]),
+            (Operation::Roll(4), &[2, 3, 4, 5, 1]),
+            (Operation::TwoDup, &[1, 2, 3, 4, 5, 4, 5]),
+            (Operation::TwoDrop, &[1, 2, 3]),
+            (Operation::TwoSwap, &[1, 4, 5, 2, 3]),
+            (Operation::Depth, &[1, 2, 3, 4, 5, 5]),
+        ];
+        for &(op, expected) in cases {
+            let mut process = Process::new(Ipv6Addr::UNSPECIFIED).unwrap();
+            for i in 1..=5u8 {
+                process.push(i.into());
+            }
+            process.run(&[op]).unwrap();
+            let mut stack = Vec::new();
+            while process.depth() > 0 {
+                stack.insert(0, process.pop().unwrap().as_i128().unwrap() as u8);
+            }
+            assert_eq!(stack, expected, "{op:?}");
+
+            // The declared effect matches, and is all the op needs.
+            let effect = op.stack_effect().unwrap();
+            assert_eq!(5 - effect.inputs + effect.outputs, expected.len(), "{op:?}");
+            let mut process = Process::new(Ipv6Addr::UNSPECIFIED).unwrap();
+            for i in 1..effect.inputs {
+                process.push((i as u8).into());
+            }
+            let err = process.run(&[op]);
+            assert_eq!(err.is_err(), effect.inputs > 0, "{op:?}");
+        }
+        assert_eq!(Operation::Call(0).stack_effect(), None);
+    }
+
+    #[test]
+    pub fn compare_and_hash() -> VmResult<()> {
+        let mut process = Process::new(Ipv6Addr::UNSPECIFIED)?;
+        let prog = [
+            Operation::PushAtom(atom!("ok")),
+            Operation::PushImm(PrimOpKind::U8, 200u8.into()),
+            Operation::Compare,
+            Operation::PushImm(PrimOpKind::U8, 1u8.into()),
+            Operation::PushImm(PrimOpKind::I64, 1i64.into()),
+            Operation::Compare,
+            Operation::PushAtom(atom!("ok")),
+            Operation::Hash,
+        ];
+        process.run(&prog).unwrap();
+        let ok = Value::Object(super::PVObject::from(atom!("ok")));
+        assert_eq!(process.pop()?, Value::from(ok.structural_hash(), PrimOpKind::U64));
+        // Atoms sort after ints, and 1u8 before 1i64.
+        assert_eq!(process.pop()?, Value::from(-1i64, PrimOpKind::I64));
+        assert_eq!(process.pop()?, Value::from(1i64, PrimOpKind::I64));
         Ok(())
     }
 }
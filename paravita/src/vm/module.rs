@@ -0,0 +1,967 @@
+use core::hash::Hash;
+
+use alloc::{string::String, vec, vec::Vec};
+use fnv::FnvBuildHasher;
+use indexmap::IndexSet;
+
+use super::{
+    error::{VmError, VmResult},
+    statics, Atom, AtomStore, AtomTable, Combine, Endian, IntOpImmediate, Operation,
+    PVObject, PVString, PrimOpKind, Reduce, Value,
+};
+
+/// The first bytes of every encoded module.
+pub const MODULE_MAGIC: [u8; 4] = *b"PVM\0";
+/// The encoding version written by [Module::to_bytes]. Loaders reject any other.
+pub const MODULE_VERSION: u16 = 2;
+
+/// An entry of a module's constant pool, pushed by [Operation::PushConst].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    /// An int of the given kind, its bits sign or zero extended to 64.
+    Int(PrimOpKind, u64),
+    Str(String),
+    Atom(Atom),
+}
+
+impl Constant {
+    pub fn to_value(&self) -> VmResult<Value> {
+        Ok(match self {
+            Constant::Int(k, bits) => Value::int(*k, *bits),
+            Constant::Str(s) => Value::Object(PVObject::make_string(PVString::Str(s.clone()))?),
+            Constant::Atom(a) => Value::Object(PVObject::from(*a)),
+        })
+    }
+}
+
+/// A function of another module, or a native installed by the host, that a module calls with
+/// [Operation::CallImport]. Imports are resolved by name when the call runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Import {
+    pub module: Atom,
+    pub function: Atom,
+    pub arity: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: Atom,
+    /// How many values the function takes off the caller's stack.
+    pub arity: u16,
+    /// How many local slots a call of the function gets.
+    pub locals: u16,
+    pub code: Vec<Operation>,
+}
+
+/// A unit of code: named functions along with the constants and imports they use. Modules are
+/// what gets distributed and loaded into a running VM, see [Module::to_bytes] for the format.
+///
+/// Nothing in a module is trusted until [Module::validate] passes, which loading always does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub name: Atom,
+    pub constants: Vec<Constant>,
+    pub imports: Vec<Import>,
+    pub functions: Vec<Function>,
+    /// Indices of the functions other modules can call, by name and arity.
+    pub exports: Vec<u32>,
+    /// How many global slots the module has. They start out null, and are shared by every
+    /// process running the same version of the module.
+    pub globals: u16,
+}
+
+impl Module {
+    pub fn new(name: Atom) -> Module {
+        Module {
+            name,
+            constants: Vec::new(),
+            imports: Vec::new(),
+            functions: Vec::new(),
+            exports: Vec::new(),
+            globals: 0,
+        }
+    }
+
+    /// Adds a constant if not already in the pool, returning its index.
+    pub fn constant(&mut self, c: Constant) -> u32 {
+        if let Some(i) = self.constants.iter().position(|x| *x == c) {
+            return i as u32;
+        }
+        self.constants.push(c);
+        self.constants.len() as u32 - 1
+    }
+
+    /// Adds an import if not already present, returning its index.
+    pub fn import(&mut self, module: Atom, function: Atom, arity: u16) -> u32 {
+        let import = Import { module, function, arity };
+        if let Some(i) = self.imports.iter().position(|x| *x == import) {
+            return i as u32;
+        }
+        self.imports.push(import);
+        self.imports.len() as u32 - 1
+    }
+
+    /// Adds a function, returning its index. Functions are numbered in the order they're added,
+    /// so a function can call one added later by counting ahead. Calls in tail position are
+    /// turned into tail calls, see [tail_calls].
+    pub fn function(&mut self, mut f: Function) -> u32 {
+        tail_calls(&mut f.code);
+        self.functions.push(f);
+        self.functions.len() as u32 - 1
+    }
+
+    /// Exports the function at `index`.
+    pub fn export(&mut self, index: u32) {
+        if !self.exports.contains(&index) {
+            self.exports.push(index);
+        }
+    }
+
+    /// Finds a function by name and arity.
+    pub fn find(&self, name: Atom, arity: u16) -> Option<u32> {
+        self.functions
+            .iter()
+            .position(|f| f.name == name && f.arity == arity)
+            .map(|i| i as u32)
+    }
+
+    /// Checks that every index and offset in the module is in range, so running it can't
+    /// reach outside of it.
+    pub fn validate(&self) -> VmResult<()> {
+        unique_names(self.functions.iter().map(|f| (f.name, f.arity)))?;
+        self.validate_operands()
+    }
+
+    /// [Module::validate], but for the function names, which decoding checks while they're
+    /// still handles.
+    fn validate_operands(&self) -> VmResult<()> {
+        for f in &self.functions {
+            for op in &f.code {
+                let ok = match *op {
+                    Operation::Call(i) | Operation::TailCall(i) => (i as usize) < self.functions.len(),
+                    Operation::MakeClosure(i, n) => {
+                        self.functions.get(i as usize).is_some_and(|f| n <= f.arity)
+                    }
+                    Operation::CallImport(i) => (i as usize) < self.imports.len(),
+                    Operation::PushConst(i) => (i as usize) < self.constants.len(),
+                    Operation::LoadLocal(i) | Operation::StoreLocal(i) => i < f.locals,
+                    Operation::LoadGlobal(i) | Operation::StoreGlobal(i) => i < self.globals,
+                    // Jumping to the end is fine, it returns.
+                    Operation::Try(t)
+                    | Operation::EndTry(t)
+                    | Operation::Jump(t)
+                    | Operation::JumpIfZero(t) => t as usize <= f.code.len(),
+                    Operation::__Final => false,
+                    _ => true,
+                };
+                if !ok {
+                    return Err(VmError::MalformedModule("operand out of range"));
+                }
+            }
+        }
+        let mut exports = IndexSet::<u32, FnvBuildHasher>::default();
+        for &e in &self.exports {
+            if e as usize >= self.functions.len() || !exports.insert(e) {
+                return Err(VmError::MalformedModule("bad export"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates, then also checks that no function can read a local slot before writing it.
+    /// Globals aren't checked, any function of the module might have written them.
+    pub fn validate_strict(&self) -> VmResult<()> {
+        self.validate()?;
+        for f in &self.functions {
+            check_locals(f)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes the module. All numbers are little endian, atoms are u32 handles into the
+    /// module's own [AtomTable]:
+    ///
+    /// - magic `PVM\0`, u16 [MODULE_VERSION]
+    /// - the atom table, as written by [AtomTable::to_bytes]
+    /// - the module name, u16 count of globals
+    /// - u32 count of constants, each a u8 tag followed by: for `0`, a string as u32 length and
+    ///   UTF-8; for `1`, an int as u8 kind and u64 bits; for `2`, an atom
+    /// - u32 count of imports, each a module atom, function atom and u16 arity
+    /// - u32 count of functions, each a name atom, u16 arity, u16 locals, u32 count of operations
+    ///   and the operations
+    /// - u32 count of exports, each a u32 function index
+    ///
+    /// An operation is its u8 opcode, the [discriminant](Operation::discriminant), followed by
+    /// its operands in order: kinds as u8, immediates as u64, atoms as handles, the rest at
+    /// their own width.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut atoms = AtomTable::new();
+        let mut w = Vec::new();
+        put_u32(&mut w, atoms.add(self.name));
+        w.extend_from_slice(&self.globals.to_le_bytes());
+
+        put_u32(&mut w, self.constants.len() as u32);
+        for c in &self.constants {
+            match c {
+                Constant::Str(s) => {
+                    w.push(0);
+                    put_u32(&mut w, s.len() as u32);
+                    w.extend_from_slice(s.as_bytes());
+                }
+                Constant::Int(k, bits) => {
+                    w.push(1);
+                    w.push(*k as u8);
+                    w.extend_from_slice(&bits.to_le_bytes());
+                }
+                Constant::Atom(a) => {
+                    w.push(2);
+                    put_u32(&mut w, atoms.add(*a));
+                }
+            }
+        }
+
+        put_u32(&mut w, self.imports.len() as u32);
+        for i in &self.imports {
+            put_u32(&mut w, atoms.add(i.module));
+            put_u32(&mut w, atoms.add(i.function));
+            w.extend_from_slice(&i.arity.to_le_bytes());
+        }
+
+        put_u32(&mut w, self.functions.len() as u32);
+        for f in &self.functions {
+            put_u32(&mut w, atoms.add(f.name));
+            w.extend_from_slice(&f.arity.to_le_bytes());
+            w.extend_from_slice(&f.locals.to_le_bytes());
+            put_u32(&mut w, f.code.len() as u32);
+            for op in &f.code {
+                write_op(&mut w, &mut atoms, *op);
+            }
+        }
+
+        put_u32(&mut w, self.exports.len() as u32);
+        for e in &self.exports {
+            put_u32(&mut w, *e);
+        }
+
+        // The atom table goes first so a loader can translate handles as it reads.
+        let mut out = Vec::from(MODULE_MAGIC);
+        out.extend_from_slice(&MODULE_VERSION.to_le_bytes());
+        out.extend_from_slice(&atoms.to_bytes());
+        out.extend_from_slice(&w);
+        out
+    }
+
+    /// Decodes and validates a module written by [Module::to_bytes]. The atoms it uses are
+    /// interned once it validates, the rest of its table never is.
+    pub fn from_bytes(bytes: &[u8]) -> VmResult<Module> {
+        let mut r = Reader {
+            bytes,
+            at: 0,
+            handles: Vec::new(),
+        };
+        if r.take(4)? != MODULE_MAGIC {
+            return Err(VmError::MalformedModule("not a module"));
+        }
+        if r.u16()? != MODULE_VERSION {
+            return Err(VmError::MalformedModule("unsupported version"));
+        }
+        // Until the module validates its atoms are placeholders, see Reader::atom.
+        let (atoms, used) = AtomTable::from_bytes(&bytes[r.at..])?;
+        r.at += used;
+
+        let mut m = Module::new(r.atom(&atoms)?);
+        m.globals = r.u16()?;
+        for _ in 0..r.u32()? {
+            let c = match r.u8()? {
+                0 => {
+                    let len = r.u32()? as usize;
+                    let s = core::str::from_utf8(r.take(len)?)
+                        .map_err(|_| VmError::MalformedModule("string constant is not UTF-8"))?;
+                    Constant::Str(String::from(s))
+                }
+                1 => {
+                    let k = r.kind()?;
+                    Constant::Int(k, k.normalize(r.u64()?))
+                }
+                2 => Constant::Atom(r.atom(&atoms)?),
+                _ => return Err(VmError::MalformedModule("unknown constant tag")),
+            };
+            m.constants.push(c);
+        }
+
+        for _ in 0..r.u32()? {
+            let module = r.atom(&atoms)?;
+            let function = r.atom(&atoms)?;
+            let arity = r.u16()?;
+            m.imports.push(Import { module, function, arity });
+        }
+
+        let mut names = Vec::new();
+        for _ in 0..r.u32()? {
+            let name = r.atom(&atoms)?;
+            let arity = r.u16()?;
+            names.push((*r.handles.last().expect("just read"), arity));
+            let locals = r.u16()?;
+            let count = r.u32()?;
+            let mut code = Vec::new();
+            for _ in 0..count {
+                code.push(read_op(&mut r, &atoms)?);
+            }
+            m.functions.push(Function { name, arity, locals, code });
+        }
+
+        for _ in 0..r.u32()? {
+            m.exports.push(r.u32()?);
+        }
+
+        if r.at != bytes.len() {
+            return Err(VmError::MalformedModule("trailing bytes"));
+        }
+        unique_names(names.into_iter())?;
+        m.validate_operands()?;
+        m.set_atoms(&r.handles, &atoms)?;
+        Ok(m)
+    }
+
+    /// Replaces the placeholders [Module::from_bytes] decodes atoms to with the atoms their
+    /// handles name, taking the handles in the order they were read: the module's name, atom
+    /// constants, imports, then each function's name and PushAtom operands.
+    fn set_atoms(&mut self, handles: &[AtomHandle], table: &AtomTable) -> VmResult<()> {
+        let mut atoms = AtomStore::import(table);
+        let mut handles = handles.iter();
+        let mut next = || atoms.translate(handles.next().expect("a handle per atom read").0);
+        self.name = next()?;
+        for c in &mut self.constants {
+            if let Constant::Atom(a) = c {
+                *a = next()?;
+            }
+        }
+        for i in &mut self.imports {
+            i.module = next()?;
+            i.function = next()?;
+        }
+        for f in &mut self.functions {
+            f.name = next()?;
+            for op in &mut f.code {
+                if let Operation::PushAtom(a) = op {
+                    *a = next()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails unless every function name and arity is different.
+fn unique_names<T: Hash + Eq>(names: impl Iterator<Item = (T, u16)>) -> VmResult<()> {
+    let mut seen = IndexSet::<(T, u16), FnvBuildHasher>::default();
+    for name in names {
+        if !seen.insert(name) {
+            return Err(VmError::MalformedModule("function defined twice"));
+        }
+    }
+    Ok(())
+}
+
+/// An atom of a module being decoded, as a handle into the module's own atom table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct AtomHandle(u32);
+
+fn put_u32(w: &mut Vec<u8>, v: u32) {
+    w.extend_from_slice(&v.to_le_bytes());
+}
+
+fn write_op(w: &mut Vec<u8>, atoms: &mut AtomTable, op: Operation) {
+    w.push(op.discriminant());
+    match op {
+        Operation::Add(k)
+        | Operation::Sub(k)
+        | Operation::Mul(k)
+        | Operation::Div(k)
+        | Operation::MakePacked(k) => w.push(k as u8),
+        Operation::PackedCombine(c) => w.push(c as u8),
+        Operation::PackedReduce(r) => w.push(r as u8),
+        Operation::BinaryRead(k, e) | Operation::BinaryWrite(k, e) => {
+            w.push(k as u8);
+            w.push(e as u8);
+        }
+        Operation::AddImm(k, imm)
+        | Operation::SubImm(k, imm)
+        | Operation::MulImm(k, imm)
+        | Operation::DivImm(k, imm)
+        | Operation::PushImm(k, imm) => {
+            w.push(k as u8);
+            w.extend_from_slice(&imm.to_le_bytes());
+        }
+        Operation::PushAtom(a) => put_u32(w, atoms.add(a)),
+        Operation::MakeObject(n)
+        | Operation::Try(n)
+        | Operation::EndTry(n)
+        | Operation::Call(n)
+        | Operation::TailCall(n)
+        | Operation::CallImport(n)
+        | Operation::PushConst(n)
+        | Operation::Jump(n)
+        | Operation::JumpIfZero(n) => put_u32(w, n),
+        Operation::MakeClosure(f, n) => {
+            put_u32(w, f);
+            w.extend_from_slice(&n.to_le_bytes());
+        }
+        Operation::Syscall(n)
+        | Operation::CallIndirect(n)
+        | Operation::TailCallIndirect(n)
+        | Operation::Pick(n)
+        | Operation::Roll(n)
+        | Operation::LoadLocal(n)
+        | Operation::StoreLocal(n)
+        | Operation::LoadGlobal(n)
+        | Operation::StoreGlobal(n) => w.extend_from_slice(&n.to_le_bytes()),
+        _ => {}
+    }
+}
+
+fn read_op(r: &mut Reader, atoms: &AtomTable) -> VmResult<Operation> {
+    // Opcodes are the discriminants, in declaration order.
+    Ok(match r.u8()? {
+        0 => Operation::Trap,
+        1 => Operation::Add(r.kind()?),
+        2 => {
+            let (k, imm) = r.imm()?;
+            Operation::AddImm(k, imm)
+        }
+        3 => Operation::Sub(r.kind()?),
+        4 => {
+            let (k, imm) = r.imm()?;
+            Operation::SubImm(k, imm)
+        }
+        5 => Operation::Mul(r.kind()?),
+        6 => {
+            let (k, imm) = r.imm()?;
+            Operation::MulImm(k, imm)
+        }
+        7 => Operation::Div(r.kind()?),
+        8 => {
+            let (k, imm) = r.imm()?;
+            Operation::DivImm(k, imm)
+        }
+        9 => {
+            let (k, imm) = r.imm()?;
+            Operation::PushImm(k, imm)
+        }
+        10 => Operation::PushAtom(r.atom(atoms)?),
+        11 => Operation::MakeObject(r.u32()?),
+        12 => Operation::MakeArray,
+        13 => Operation::IndexArray,
+        14 => Operation::SetArray,
+        15 => Operation::Drop,
+        16 => Operation::Dup,
+        17 => Operation::Swap,
+        18 => Operation::DebugOut,
+        19 => Operation::Try(r.u32()?),
+        20 => Operation::EndTry(r.u32()?),
+        21 => Operation::Throw,
+        22 => Operation::Syscall(r.u16()?),
+        23 => Operation::Call(r.u32()?),
+        24 => Operation::CallImport(r.u32()?),
+        25 => Operation::Return,
+        26 => Operation::PushConst(r.u32()?),
+        27 => Operation::LoadLocal(r.u16()?),
+        28 => Operation::StoreLocal(r.u16()?),
+        29 => Operation::LoadGlobal(r.u16()?),
+        30 => Operation::StoreGlobal(r.u16()?),
+        31 => Operation::MakeClosure(r.u32()?, r.u16()?),
+        32 => Operation::CallIndirect(r.u16()?),
+        33 => Operation::TailCall(r.u32()?),
+        34 => Operation::TailCallIndirect(r.u16()?),
+        35 => Operation::Over,
+        36 => Operation::Rot,
+        37 => Operation::MinusRot,
+        38 => Operation::Nip,
+        39 => Operation::Tuck,
+        40 => Operation::Pick(r.u16()?),
+        41 => Operation::Roll(r.u16()?),
+        42 => Operation::TwoDup,
+        43 => Operation::TwoDrop,
+        44 => Operation::TwoSwap,
+        45 => Operation::Depth,
+        46 => Operation::Jump(r.u32()?),
+        47 => Operation::JumpIfZero(r.u32()?),
+        48 => Operation::Compare,
+        49 => Operation::Hash,
+        50 => Operation::MakeDict,
+        51 => Operation::MapKeys,
+        52 => Operation::MapValues,
+        53 => Operation::MapEntries,
+        54 => Operation::MapMerge,
+        55 => Operation::MapFilter,
+        56 => Operation::MakeBinary,
+        57 => Operation::Length,
+        58 => Operation::BinarySlice,
+        59 => Operation::BinaryConcat,
+        60 => Operation::BinaryRead(r.kind()?, r.endian()?),
+        61 => Operation::BinaryWrite(r.kind()?, r.endian()?),
+        62 => Operation::MakePacked(r.kind()?),
+        63 => Operation::PackedFill,
+        64 => Operation::PackedCopy,
+        65 => Operation::PackedCombine(
+            Combine::from_u8(r.u8()?).ok_or(VmError::MalformedModule("unknown combination"))?,
+        ),
+        66 => Operation::PackedReduce(
+            Reduce::from_u8(r.u8()?).ok_or(VmError::MalformedModule("unknown reduction"))?,
+        ),
+        _ => return Err(VmError::MalformedModule("unknown opcode")),
+    })
+}
+
+/// Turns calls that are followed by a return, or that end the code, into tail calls. The
+/// Return left behind is never reached, but stays so no offsets move. A Jump to a return
+/// counts as one.
+///
+/// Calls inside a Try region are left alone, since a tail call would drop the handler.
+pub fn tail_calls(code: &mut [Operation]) {
+    let returns = |pc: usize| matches!(code.get(pc), None | Some(Operation::Return));
+    let mut tails = Vec::new();
+    let mut trying = 0usize;
+    for pc in 0..code.len() {
+        let tail = match code.get(pc + 1) {
+            Some(Operation::Jump(t)) => returns(*t as usize),
+            _ => returns(pc + 1),
+        };
+        match code[pc] {
+            Operation::Try(_) => trying += 1,
+            Operation::EndTry(_) => trying = trying.saturating_sub(1),
+            Operation::Call(_) | Operation::CallIndirect(_) if tail && trying == 0 => tails.push(pc),
+            _ => {}
+        }
+    }
+    for pc in tails {
+        code[pc] = match code[pc] {
+            Operation::Call(f) => Operation::TailCall(f),
+            Operation::CallIndirect(n) => Operation::TailCallIndirect(n),
+            op => op,
+        };
+    }
+}
+
+/// Where control can go after the operation at `pc`.
+fn successors(op: Operation, pc: usize) -> [Option<usize>; 2] {
+    match op {
+        Operation::Return
+        | Operation::Throw
+        | Operation::Trap
+        | Operation::TailCall(_)
+        | Operation::TailCallIndirect(_) => [None, None],
+        Operation::EndTry(next) | Operation::Jump(next) => [Some(next as usize), None],
+        Operation::JumpIfZero(target) => [Some(pc + 1), Some(target as usize)],
+        // The handler can be reached from anywhere in the region, but locals are only ever
+        // added to along a path, so the state at the Try is the one to assume there.
+        Operation::Try(handler) => [Some(pc + 1), Some(handler as usize)],
+        _ => [Some(pc + 1), None],
+    }
+}
+
+/// Finds the locals written on every path to each operation, failing at the first read of
+/// one that might not be.
+fn check_locals(f: &Function) -> VmResult<()> {
+    let words = (f.locals as usize).div_ceil(64);
+    let mut written: Vec<Option<Vec<u64>>> = vec![None; f.code.len()];
+    let mut work = Vec::new();
+    if !f.code.is_empty() {
+        written[0] = Some(vec![0; words]);
+        work.push(0);
+    }
+    while let Some(pc) = work.pop() {
+        let mut state = written[pc].clone().unwrap();
+        let op = f.code[pc];
+        match op {
+            Operation::LoadLocal(i) if state[i as usize / 64] & (1 << (i % 64)) == 0 => {
+                return Err(VmError::UnassignedLocal {
+                    function: f.name,
+                    pc,
+                    local: i,
+                });
+            }
+            Operation::StoreLocal(i) => state[i as usize / 64] |= 1 << (i % 64),
+            _ => {}
+        }
+        for next in successors(op, pc).into_iter().flatten() {
+            // Running off the end returns.
+            let Some(seen) = written.get_mut(next) else {
+                continue;
+            };
+            match seen {
+                None => *seen = Some(state.clone()),
+                Some(seen) => {
+                    let merged: Vec<u64> = seen.iter().zip(&state).map(|(a, b)| a & b).collect();
+                    if merged == *seen {
+                        continue;
+                    }
+                    *seen = merged;
+                }
+            }
+            work.push(next);
+        }
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    /// Every atom read so far, in order.
+    handles: Vec<AtomHandle>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> VmResult<&'a [u8]> {
+        let end = self.at.checked_add(n).filter(|&e| e <= self.bytes.len());
+        let end = end.ok_or(VmError::MalformedModule("truncated"))?;
+        let b = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> VmResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> VmResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> VmResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> VmResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn kind(&mut self) -> VmResult<PrimOpKind> {
+        PrimOpKind::from_u8(self.u8()?).ok_or(VmError::MalformedModule("unknown int kind"))
+    }
+
+    fn endian(&mut self) -> VmResult<Endian> {
+        Endian::from_u8(self.u8()?).ok_or(VmError::MalformedModule("unknown byte order"))
+    }
+
+    /// Reads a kind and an immediate of that kind, normalized like [Value::int] does.
+    fn imm(&mut self) -> VmResult<(PrimOpKind, IntOpImmediate)> {
+        let k = self.kind()?;
+        let bits = k.normalize(self.u64()?);
+        Ok((k, IntOpImmediate::from_le_bytes(bits.to_le_bytes())))
+    }
+
+    /// Reads an atom's handle into [Reader::handles], returning a placeholder for it. Nothing
+    /// is interned until the module validates, see [Module::set_atoms].
+    fn atom(&mut self, atoms: &AtomTable) -> VmResult<Atom> {
+        let handle = self.u32()?;
+        if atoms.get(handle).is_none() {
+            return Err(VmError::UnknownAtom(handle));
+        }
+        self.handles.try_reserve(1)?;
+        self.handles.push(AtomHandle(handle));
+        Ok(statics::UNDEFINED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec, vec::Vec};
+
+    use super::{tail_calls, Constant, Function, Module};
+    use crate::vm::{AtomStore, AtomTable};
+    use crate::vm::{Combine, Endian, Operation, PrimOpKind, Reduce, VmError};
+
+    fn sample() -> Module {
+        let mut m = Module::new(atom!("sample"));
+        let greeting = m.constant(Constant::Str(String::from("hello")));
+        let big = m.constant(Constant::Int(PrimOpKind::I64, i64::MIN as u64));
+        let print = m.import(atom!("io"), atom!("print"), 1);
+        let helper = m.function(Function {
+            name: atom!("helper"),
+            arity: 0,
+            locals: 0,
+            code: vec![Operation::PushConst(big), Operation::Return],
+        });
+        let main = m.function(Function {
+            name: atom!("main"),
+            arity: 1,
+            locals: 2,
+            code: vec![
+                Operation::Try(5),
+                Operation::PushConst(greeting),
+                Operation::CallImport(print),
+                Operation::Call(helper),
+                Operation::EndTry(6),
+                Operation::PushAtom(atom!("caught")),
+                Operation::AddImm(PrimOpKind::I8, (-3i8).into()),
+                Operation::Syscall(7),
+            ],
+        });
+        m.export(main);
+        m
+    }
+
+    #[test]
+    pub fn round_trip() {
+        let m = sample();
+        m.validate().unwrap();
+        let bytes = m.to_bytes();
+        assert_eq!(Module::from_bytes(&bytes).unwrap(), m);
+
+        // Every truncation is caught.
+        for len in 0..bytes.len() {
+            assert!(Module::from_bytes(&bytes[..len]).is_err());
+        }
+        let mut long = bytes.clone();
+        long.push(0);
+        assert!(matches!(
+            Module::from_bytes(&long),
+            Err(VmError::MalformedModule("trailing bytes"))
+        ));
+    }
+
+    #[test]
+    pub fn rejects_bad_modules() {
+        let bad = |f: fn(&mut Module)| {
+            let mut m = sample();
+            f(&mut m);
+            assert!(matches!(m.validate(), Err(VmError::MalformedModule(_))));
+            assert!(Module::from_bytes(&m.to_bytes()).is_err());
+        };
+        bad(|m| m.functions[1].code.push(Operation::Call(2)));
+        bad(|m| m.functions[1].code.push(Operation::CallImport(1)));
+        bad(|m| m.functions[1].code.push(Operation::PushConst(9)));
+        bad(|m| m.functions[1].code.push(Operation::Try(100)));
+        bad(|m| m.exports.push(5));
+        bad(|m| {
+            let f = m.functions[0].clone();
+            m.function(f);
+        });
+
+        let mut bytes = sample().to_bytes();
+        bytes[4] = 9;
+        assert!(matches!(
+            Module::from_bytes(&bytes),
+            Err(VmError::MalformedModule("unsupported version"))
+        ));
+        let junk: Vec<u8> = b"PVX\0".to_vec();
+        assert!(Module::from_bytes(&junk).is_err());
+    }
+
+    /// Encodes a module with a function running `code`, renamed to `name` in the bytes so that
+    /// it's never interned here, and with an atom nothing uses added to its table.
+    fn foreign(name: &[u8; 6], code: Vec<Operation>) -> Vec<u8> {
+        let mut m = Module::new(atom!("caught"));
+        m.function(Function {
+            name: atom!("f"),
+            arity: 0,
+            locals: 0,
+            code,
+        });
+        let mut bytes = m.to_bytes();
+        let at = bytes.windows(6).position(|w| w == b"caught").unwrap();
+        bytes[at..at + 6].copy_from_slice(name);
+        let (table, used) = AtomTable::from_bytes(&bytes[6..]).unwrap();
+        bytes[6..10].copy_from_slice(&(table.len() as u32 + 1).to_le_bytes());
+        let extra = [&9u32.to_le_bytes()[..], b"zq_unused"].concat();
+        bytes.splice(6 + used..6 + used, extra);
+        bytes
+    }
+
+    #[test]
+    pub fn interns_once_valid() {
+        let interned = |s: &str| AtomStore::snapshot().iter().any(|a| a == s);
+
+        let bad = foreign(b"zq_bad", vec![Operation::PushAtom(atom!("ok")), Operation::Call(1)]);
+        assert!(matches!(
+            Module::from_bytes(&bad),
+            Err(VmError::MalformedModule("operand out of range"))
+        ));
+        assert!(!interned("zq_bad"));
+
+        let good = foreign(b"zq_mod", vec![Operation::PushAtom(atom!("ok"))]);
+        let m = Module::from_bytes(&good).unwrap();
+        assert_eq!(<&str>::from(m.name), "zq_mod");
+        assert_eq!(m.functions[0].name, atom!("f"));
+        assert_eq!(m.functions[0].code, [Operation::PushAtom(atom!("ok"))]);
+        assert!(!interned("zq_unused"));
+    }
+
+    #[test]
+    pub fn strict_locals() {
+        let check = |code: Vec<Operation>| {
+            let mut m = Module::new(atom!("strict"));
+            m.function(Function {
+                name: atom!("f"),
+                arity: 0,
+                locals: 1,
+                code,
+            });
+            m.validate_strict()
+        };
+        let one = Operation::PushImm(PrimOpKind::U8, 1u8.into());
+
+        assert!(matches!(
+            check(vec![Operation::LoadLocal(0)]),
+            Err(VmError::UnassignedLocal { pc: 0, local: 0, .. })
+        ));
+        check(vec![one, Operation::StoreLocal(0), Operation::LoadLocal(0)]).unwrap();
+
+        // The handler can run before the store in the Try region does.
+        let mut code = vec![
+            Operation::Try(4),
+            one,
+            Operation::StoreLocal(0),
+            Operation::EndTry(5),
+            Operation::Drop,
+            Operation::LoadLocal(0),
+        ];
+        assert!(matches!(
+            check(code.clone()),
+            Err(VmError::UnassignedLocal { pc: 5, .. })
+        ));
+        code[4] = Operation::StoreLocal(0);
+        check(code).unwrap();
+
+        // Out of range slots are rejected even without strict checks.
+        let mut m = Module::new(atom!("strict"));
+        m.function(Function {
+            name: atom!("f"),
+            arity: 0,
+            locals: 1,
+            code: vec![Operation::LoadGlobal(0)],
+        });
+        assert!(m.validate().is_err());
+        m.globals = 1;
+        m.validate().unwrap();
+        m.functions[0].code.push(Operation::StoreLocal(1));
+        assert!(m.validate().is_err());
+    }
+
+    #[test]
+    pub fn tail_positions() {
+        let mut code = vec![
+            Operation::Call(0),
+            Operation::Call(1),
+            Operation::Return,
+            Operation::Try(6),
+            Operation::Call(2),
+            Operation::Return,
+            Operation::EndTry(7),
+            Operation::CallIndirect(1),
+        ];
+        tail_calls(&mut code);
+        assert_eq!(code[0], Operation::Call(0));
+        assert_eq!(code[1], Operation::TailCall(1));
+        // A tail call would leave the Try region early.
+        assert_eq!(code[4], Operation::Call(2));
+        assert_eq!(code[7], Operation::TailCallIndirect(1));
+
+        // Jumping to a return, or to the end, is returning.
+        let mut code = vec![
+            Operation::Call(0),
+            Operation::Jump(4),
+            Operation::Call(1),
+            Operation::Jump(2),
+            Operation::Return,
+        ];
+        tail_calls(&mut code);
+        assert_eq!(code[0], Operation::TailCall(0));
+        assert_eq!(code[2], Operation::Call(1));
+    }
+
+    #[test]
+    pub fn every_op() {
+        let k = PrimOpKind::I16;
+        let imm = (-2i16).into();
+        let code = vec![
+            Operation::Trap,
+            Operation::Add(k),
+            Operation::AddImm(k, imm),
+            Operation::Sub(k),
+            Operation::SubImm(k, imm),
+            Operation::Mul(k),
+            Operation::MulImm(k, imm),
+            Operation::Div(k),
+            Operation::DivImm(k, imm),
+            Operation::PushImm(k, imm),
+            Operation::PushAtom(atom!("ok")),
+            Operation::MakeObject(3),
+            Operation::MakeArray,
+            Operation::IndexArray,
+            Operation::SetArray,
+            Operation::Drop,
+            Operation::Dup,
+            Operation::Swap,
+            Operation::DebugOut,
+            Operation::Try(1),
+            Operation::EndTry(2),
+            Operation::Throw,
+            Operation::Syscall(9),
+            Operation::Call(0),
+            Operation::CallImport(0),
+            Operation::Return,
+            Operation::PushConst(0),
+            Operation::LoadLocal(0),
+            Operation::StoreLocal(0),
+            Operation::LoadGlobal(0),
+            Operation::StoreGlobal(0),
+            Operation::MakeClosure(0, 1),
+            Operation::CallIndirect(2),
+            Operation::TailCall(0),
+            Operation::TailCallIndirect(3),
+            Operation::Over,
+            Operation::Rot,
+            Operation::MinusRot,
+            Operation::Nip,
+            Operation::Tuck,
+            Operation::Pick(4),
+            Operation::Roll(5),
+            Operation::TwoDup,
+            Operation::TwoDrop,
+            Operation::TwoSwap,
+            Operation::Depth,
+            Operation::Jump(3),
+            Operation::JumpIfZero(4),
+            Operation::Compare,
+            Operation::Hash,
+            Operation::MakeDict,
+            Operation::MapKeys,
+            Operation::MapValues,
+            Operation::MapEntries,
+            Operation::MapMerge,
+            Operation::MapFilter,
+            Operation::MakeBinary,
+            Operation::Length,
+            Operation::BinarySlice,
+            Operation::BinaryConcat,
+            Operation::BinaryRead(PrimOpKind::U32, Endian::Big),
+            Operation::BinaryWrite(PrimOpKind::I16, Endian::Little),
+            Operation::MakePacked(PrimOpKind::U32),
+            Operation::PackedFill,
+            Operation::PackedCopy,
+            Operation::PackedCombine(Combine::Mul),
+            Operation::PackedReduce(Reduce::Max),
+        ];
+        // One of each, in opcode order.
+        assert_eq!(code.len(), Operation::__Final.discriminant() as usize);
+        for (i, op) in code.iter().enumerate() {
+            assert_eq!(op.discriminant() as usize, i);
+        }
+
+        let mut m = Module::new(atom!("ops"));
+        m.globals = 1;
+        m.constant(Constant::Atom(atom!("ok")));
+        m.import(atom!("ops"), atom!("f"), 1);
+        // Added directly, so the builder doesn't rewrite calls.
+        m.functions.push(Function {
+            name: atom!("f"),
+            arity: 1,
+            locals: 1,
+            code,
+        });
+        assert_eq!(Module::from_bytes(&m.to_bytes()).unwrap(), m);
+    }
+}
@@ -0,0 +1,241 @@
+use super::PrimOpKind;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The Rust integer types matching each [PrimOpKind].
+///
+/// Ints are stored as 64 bit patterns, the value sign or zero extended as fits its kind, so
+/// every conversion here is plain integer arithmetic and means the same on any target.
+pub trait Num: sealed::Sealed + Copy {
+    const KIND: PrimOpKind;
+
+    /// The value sign or zero extended to 64 bits.
+    fn to_bits(self) -> u64;
+
+    /// Truncates a 64 bit pattern to this type, like an `as` cast.
+    fn from_bits(bits: u64) -> Self;
+}
+
+macro_rules! nums {
+    ($($t:ty => $k:ident),* $(,)?) => {
+        $(
+            impl sealed::Sealed for $t {}
+
+            impl Num for $t {
+                const KIND: PrimOpKind = PrimOpKind::$k;
+
+                #[inline(always)]
+                fn to_bits(self) -> u64 {
+                    self as u64
+                }
+
+                #[inline(always)]
+                fn from_bits(bits: u64) -> Self {
+                    bits as $t
+                }
+            }
+        )*
+    };
+}
+
+nums! {
+    u8 => U8,
+    i8 => I8,
+    u16 => U16,
+    i16 => I16,
+    u32 => U32,
+    i32 => I32,
+    u64 => U64,
+    i64 => I64,
+}
+
+impl PrimOpKind {
+    /// Width of the kind in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            PrimOpKind::U8 | PrimOpKind::I8 => 1,
+            PrimOpKind::U16 | PrimOpKind::I16 => 2,
+            PrimOpKind::U32 | PrimOpKind::I32 => 4,
+            PrimOpKind::U64 | PrimOpKind::I64 => 8,
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(
+            self,
+            PrimOpKind::I8 | PrimOpKind::I16 | PrimOpKind::I32 | PrimOpKind::I64
+        )
+    }
+
+    /// The smallest and largest value of the kind, as i128.
+    pub fn bounds(&self) -> (i128, i128) {
+        let bits = self.size() as u32 * 8;
+        if self.is_signed() {
+            (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+        } else {
+            (0, (1 << bits) - 1)
+        }
+    }
+
+    /// Truncates `bits` to the kind's width, then sign or zero extends it back to 64 bits.
+    #[inline(always)]
+    pub fn normalize(&self, bits: u64) -> u64 {
+        match self {
+            PrimOpKind::U8 => bits as u8 as u64,
+            PrimOpKind::I8 => bits as i8 as u64,
+            PrimOpKind::U16 => bits as u16 as u64,
+            PrimOpKind::I16 => bits as i16 as u64,
+            PrimOpKind::U32 => bits as u32 as u64,
+            PrimOpKind::I32 => bits as i32 as u64,
+            PrimOpKind::U64 | PrimOpKind::I64 => bits,
+        }
+    }
+
+    /// Writes the low `size()` bytes of `bits`, little endian.
+    pub fn write_le(&self, bits: u64, out: &mut [u8]) {
+        out[..self.size()].copy_from_slice(&bits.to_le_bytes()[..self.size()]);
+    }
+
+    /// Reads `size()` little endian bytes as a value of this kind, or None if there aren't
+    /// enough.
+    pub fn read_le(&self, bytes: &[u8]) -> Option<u64> {
+        let mut buf = [0; 8];
+        buf[..self.size()].copy_from_slice(bytes.get(..self.size())?);
+        Some(self.normalize(u64::from_le_bytes(buf)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::Num;
+    use crate::vm::{Aligned, IntOpImmediate, Operation, PrimOpKind, Process, Value, VmError};
+
+    const KINDS: [PrimOpKind; 8] = [
+        PrimOpKind::U8,
+        PrimOpKind::I8,
+        PrimOpKind::U16,
+        PrimOpKind::I16,
+        PrimOpKind::U32,
+        PrimOpKind::I32,
+        PrimOpKind::U64,
+        PrimOpKind::I64,
+    ];
+
+    #[test]
+    pub fn values() {
+        for k in KINDS {
+            let (min, max) = k.bounds();
+            for v in [min, max, 0, 1, min + 1, max - 1] {
+                let val = Value::int(k, v as u64);
+                assert_eq!(val.as_i128(), Some(v));
+                assert_eq!(val.int_bits(), Some((k, v as u64)));
+                // Out of range bits wrap like a cast.
+                assert_eq!(
+                    Value::int(k, (v + (max - min + 1)) as u64).as_i128(),
+                    Some(v)
+                );
+            }
+        }
+
+        assert_eq!(
+            Value::from(-1i8, PrimOpKind::I8)
+                .reinterpret::<u8>()
+                .unwrap(),
+            255
+        );
+        assert_eq!(
+            Value::from(-1i8, PrimOpKind::I8)
+                .reinterpret::<i64>()
+                .unwrap(),
+            -1
+        );
+        assert_eq!(Value::from(300u16, PrimOpKind::U8).as_i128(), Some(44));
+        assert_eq!(
+            Value::from(u64::MAX, PrimOpKind::U64).as_i128(),
+            Some(u64::MAX as i128)
+        );
+        assert_eq!(
+            Value::from(5u8, PrimOpKind::U8),
+            <Value as From<u8>>::from(5)
+        );
+        // A hand built value with stray high bits still reads as its kind.
+        assert_eq!(
+            Value::Int(PrimOpKind::I8, Aligned(0x1ff)).as_i128(),
+            Some(-1)
+        );
+
+        assert!(matches!(
+            Value::Null.reinterpret::<u8>(),
+            Err(VmError::PopExpectedType { .. })
+        ));
+        assert!(matches!(
+            Value::Object(crate::vm::PVObject::from(crate::vm::statics::OK)).reinterpret::<i32>(),
+            Err(VmError::PopExpectedType { .. })
+        ));
+    }
+
+    #[test]
+    pub fn bytes() {
+        for k in KINDS {
+            let (min, max) = k.bounds();
+            for v in [min, max, (-1i128).clamp(min, max)] {
+                let mut buf = [0xaa; 8];
+                k.write_le(v as u64, &mut buf);
+                assert!(buf[k.size()..].iter().all(|&b| b == 0xaa));
+                assert_eq!(k.read_le(&buf), Some(k.normalize(v as u64)));
+            }
+            assert_eq!(k.read_le(&[0; 7][..k.size() - 1]), None);
+        }
+        assert_eq!(PrimOpKind::U32.read_le(&[1, 2, 3, 4]), Some(0x04030201));
+        assert_eq!(PrimOpKind::I16.read_le(&[0xfe, 0xff]), Some(-2i64 as u64));
+
+        let a = Aligned::from_le_bytes([1, 0, 0, 0, 0, 0, 0, 0x80]);
+        assert_eq!(a.0, 0x8000_0000_0000_0001);
+        assert_eq!(a.to_le_bytes()[7], 0x80);
+
+        let imm = IntOpImmediate::from(-2i16);
+        assert_eq!(imm.read_i16(PrimOpKind::I16), -2);
+        assert_eq!(IntOpImmediate::from_le_bytes(imm.to_le_bytes()), imm);
+        assert_eq!(IntOpImmediate::from(200u8).read_u8(PrimOpKind::U8), 200);
+        assert_eq!(<u16 as Num>::from_bits(0x1_2345), 0x2345);
+    }
+
+    #[test]
+    pub fn arithmetic() {
+        fn run(code: &[Operation]) -> Value {
+            let mut p = Process::new(core::net::Ipv6Addr::UNSPECIFIED).unwrap();
+            p.run(code).unwrap();
+            p.pop().unwrap()
+        }
+
+        for k in KINDS {
+            let (min, max) = k.bounds();
+            let push = |v: i128| Operation::PushImm(k, IntOpImmediate::from(v as u64));
+            // Wrapping at both ends.
+            assert_eq!(
+                run(&[push(max), Operation::AddImm(k, 1u8.into())]).as_i128(),
+                Some(min)
+            );
+            assert_eq!(
+                run(&[push(min), push(1), Operation::Sub(k)]).as_i128(),
+                Some(max)
+            );
+            let doubled = if k.is_signed() { -2 } else { max - 1 };
+            assert_eq!(
+                run(&[push(max), push(2), Operation::Mul(k)]).as_i128(),
+                Some(doubled)
+            );
+            // Division truncates, MIN / -1 wraps.
+            let q = run(&[push(min), push(-1), Operation::Div(k), Operation::Drop]);
+            let expected = if k.is_signed() { min } else { 0 };
+            assert_eq!(q.as_i128(), Some(expected));
+        }
+
+        let stack = vec![Operation::PushImm(PrimOpKind::I64, i64::MIN.into())];
+        assert_eq!(run(&stack).as_i128(), Some(i64::MIN as i128));
+    }
+}
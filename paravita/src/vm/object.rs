@@ -1,4 +1,5 @@
 use core::{
+    alloc::AllocError,
     any::Any,
     cell::{Ref, RefCell, RefMut},
     fmt::Debug,
@@ -11,7 +12,7 @@ use indexmap::IndexMap;
 
 use super::{
     error::{VmError, VmResult},
-    Atom, Value,
+    Atom, Binary, Closure, Packed, Value,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -20,22 +21,62 @@ pub enum PVString {
     Str(String),
 }
 
+impl PVString {
+    /// The bytes of text the string owns, none for an atom.
+    fn size(&self) -> usize {
+        match self {
+            PVString::Atom(_) => 0,
+            PVString::Str(s) => s.capacity(),
+        }
+    }
+}
+
 /// A reference to a Paravita object. To clone the inner object, call [duplicate()]
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub struct PVObject {
     cell: Rc<RefCell<PVObjectType>>,
 }
 
 impl PVObject {
-    pub fn get(&self) -> Ref<PVObjectType> {
+    pub fn get(&self) -> Ref<'_, PVObjectType> {
         self.cell.borrow()
     }
 
-    pub fn get_mut(&self) -> RefMut<PVObjectType> {
+    pub fn get_mut(&self) -> RefMut<'_, PVObjectType> {
         self.cell.borrow_mut()
     }
 
-    fn build_handle(h: PVObjectType) -> VmResult<Self> {
+    /// Identifies the object, for spotting shared and cyclic references.
+    pub(crate) fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.cell) as *const ()
+    }
+
+    /// Puts `inner` in the object's place, returning what was there.
+    pub(super) fn replace(&self, inner: PVObjectType) -> PVObjectType {
+        self.cell.replace(inner)
+    }
+
+    /// Gives up the handle as a raw pointer, see [Rc::into_raw].
+    pub(super) fn into_raw(self) -> *const RefCell<PVObjectType> {
+        Rc::into_raw(self.cell)
+    }
+
+    /// Takes back a handle given up by [PVObject::into_raw].
+    ///
+    /// # Safety
+    /// `ptr` must come from [PVObject::into_raw], and each pointer is only taken back once.
+    pub(super) unsafe fn from_raw(ptr: *const RefCell<PVObjectType>) -> Self {
+        Self {
+            cell: Rc::from_raw(ptr),
+        }
+    }
+
+    #[cfg(test)]
+    pub(super) fn ref_count(&self) -> usize {
+        Rc::strong_count(&self.cell)
+    }
+
+    pub(super) fn build_handle(h: PVObjectType) -> VmResult<Self> {
         Ok(Self {
             
             cell: Rc::try_new(RefCell::new(h))?,
@@ -48,12 +89,38 @@ impl PVObject {
         Self::build_handle(inner)
     }
 
+    pub fn make_dict() -> VmResult<Self> {
+        //MEMSAFETY: IndexMap default is infalliable.
+        let inner = PVObjectType::Dict(IndexMap::default());
+        Self::build_handle(inner)
+    }
+
     pub fn make_array() -> VmResult<Self> {
         //MEMSAFETY: Vec::new() is infalliable.
         let inner = PVObjectType::Array(Vec::new());
         Self::build_handle(inner)
     }
 
+    pub fn make_array_from(elems: Vec<Value>) -> VmResult<Self> {
+        Self::build_handle(PVObjectType::Array(elems))
+    }
+
+    pub fn make_function(c: Closure) -> VmResult<Self> {
+        Self::build_handle(PVObjectType::Function(c))
+    }
+
+    pub fn make_binary(b: Binary) -> VmResult<Self> {
+        Self::build_handle(PVObjectType::Binary(b))
+    }
+
+    pub fn make_packed(p: Packed) -> VmResult<Self> {
+        Self::build_handle(PVObjectType::Packed(p))
+    }
+
+    pub fn make_string(s: PVString) -> VmResult<Self> {
+        Self::build_handle(PVObjectType::String(s))
+    }
+
     pub fn duplicate(&self) -> VmResult<Self> {
         // Clone the interior object
         //MEMSAFETY: We need a better API for this, clone is falliable by panic.
@@ -65,48 +132,113 @@ impl PVObject {
 
 #[derive(Debug, Clone)]
 pub enum PVObjectType {
+    /// A map keyed by atoms and strings, the shape records take. Atom keys hash by handle.
     Map(IndexMap<PVString, Value, FnvBuildHasher>),
+    /// A map keyed by values that never change, compared and hashed by structure, see
+    /// [Value::term_cmp]. Objects VM code can mutate can't be keys, see
+    /// [set_index](PVObjectType::set_index).
+    Dict(IndexMap<Value, Value, FnvBuildHasher>),
     Array(Vec<Value>),
+    Binary(Binary),
+    Packed(Packed),
     String(PVString),
     UserData(Rc<dyn PVUserData>),
+    Function(Closure),
 }
-impl PartialEq for PVObjectType {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Self::Map(l0), Self::Map(r0)) => l0 == r0,
-            (Self::Array(l0), Self::Array(r0)) => l0 == r0,
-            (Self::String(l0), Self::String(r0)) => l0 == r0,
-            /* never equal. */
-            (Self::UserData(_), Self::UserData(_)) => false,
-            _ => false,
+impl PVObjectType {
+    /// Roughly how many bytes the object takes, counting what it owns but not the objects
+    /// it refers to.
+    pub fn size(&self) -> usize {
+        let contents = match self {
+            PVObjectType::Map(m) => {
+                m.capacity() * (size_of::<PVString>() + size_of::<Value>() + size_of::<usize>())
+                    + m.keys().map(PVString::size).sum::<usize>()
+            }
+            PVObjectType::Dict(d) => d.capacity() * (2 * size_of::<Value>() + size_of::<usize>()),
+            PVObjectType::Array(v) => v.capacity() * size_of::<Value>(),
+            // A slice counts the bytes it shows, though they may be shared.
+            PVObjectType::Binary(b) => b.len(),
+            PVObjectType::Packed(p) => p.capacity_bytes(),
+            PVObjectType::String(s) => s.size(),
+            PVObjectType::UserData(u) => size_of_val(&**u),
+            PVObjectType::Function(c) => size_of_val(c.captured()),
+        };
+        // The Rc's counts and the RefCell's flag.
+        size_of::<RefCell<PVObjectType>>() + 2 * size_of::<usize>() + contents
+    }
+
+    /// Calls `f` on each value the object refers to.
+    pub fn for_each_child(&self, mut f: impl FnMut(&Value)) {
+        match self {
+            PVObjectType::Map(m) => m.values().for_each(f),
+            PVObjectType::Dict(d) => d.iter().for_each(|(k, v)| {
+                f(k);
+                f(v);
+            }),
+            PVObjectType::Array(v) => v.iter().for_each(f),
+            PVObjectType::Function(c) => c.captured().iter().for_each(f),
+            PVObjectType::Binary(_)
+            | PVObjectType::Packed(_)
+            | PVObjectType::String(_)
+            | PVObjectType::UserData(_) => {}
+        }
+    }
+
+    /// How many elements an array or packed array, entries a map or dict, or bytes a binary, string or atom
+    /// has. None for other objects.
+    pub fn length(&self) -> Option<usize> {
+        match self {
+            PVObjectType::Map(m) => Some(m.len()),
+            PVObjectType::Dict(d) => Some(d.len()),
+            PVObjectType::Array(v) => Some(v.len()),
+            PVObjectType::Binary(b) => Some(b.len()),
+            PVObjectType::Packed(p) => Some(p.len()),
+            PVObjectType::String(PVString::Atom(a)) => Some(<&str>::from(*a).len()),
+            PVObjectType::String(PVString::Str(s)) => Some(s.len()),
+            PVObjectType::UserData(_) | PVObjectType::Function(_) => None,
         }
     }
-}
 
-impl PVObjectType {
     pub fn load(&self, idx: usize) -> Option<Value> {
         match self {
             PVObjectType::Map(_) => None,
+            PVObjectType::Dict(_) => None,
             PVObjectType::String(_) => None,
-            PVObjectType::Array(v) => v.get(idx).map(|x| x.clone()),
+            PVObjectType::Array(v) => v.get(idx).cloned(),
+            PVObjectType::Binary(_) => None,
+            PVObjectType::Packed(_) => None,
             PVObjectType::UserData(_) => None,
+            PVObjectType::Function(_) => None,
+        }
+    }
+
+    /// Sets an atom-keyed field of a map. Does nothing to other object types.
+    pub fn set_field(&mut self, key: Atom, value: Value) -> VmResult<()> {
+        if let PVObjectType::Map(m) = self {
+            m.try_reserve(1).map_err(|_| VmError::MemoryAllocFailed(AllocError))?;
+            m.insert(PVString::Atom(key), value);
         }
+        Ok(())
     }
 
     pub fn store(&mut self, idx: usize, value: Value) -> VmResult<()> {
         match self {
             PVObjectType::Map(_) => Ok(()),
+            PVObjectType::Dict(_) => Ok(()),
             PVObjectType::String(_) => Ok(()),
             PVObjectType::Array(v) => {
-                if v.len() >= idx {
-                    let elems = idx - v.len() + 1;
-                    v.try_reserve(elems)?;
-                    v.extend([Value::Null; 1].iter().cloned().cycle().take(elems));
+                // Storing past the end grows the array, filling the gap with nulls.
+                if idx >= v.len() {
+                    v.try_reserve(idx - v.len() + 1)?;
+                    v.resize(idx + 1, Value::Null);
                 }
                 v[idx] = value;
                 Ok(())
             }
+            PVObjectType::Binary(_) => Ok(()),
+            PVObjectType::Packed(_) => Ok(()),
             PVObjectType::UserData(_) => Ok(()),
+            PVObjectType::Function(_) => Ok(()),
         }
     }
 }
@@ -1,12 +1,13 @@
-use core::mem::{variant_count, size_of, MaybeUninit};
+use core::mem::variant_count;
 
-use bytemuck::{self, Pod};
-
-use super::{Aligned, Atom};
+use super::{Aligned, Atom, Num};
 
 #[repr(u8)]
 #[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Operation {
+    /// ( -- )
+    /// Raises a trap. Opcode 0, so running zeroed code traps instead of doing something.
     Trap = 0, // because null is bad.
     /// ( n1 n2 -- sum )
     Add(PrimOpKind),
@@ -33,8 +34,12 @@ pub enum Operation {
     /// ( -- arr)
     MakeArray,
     /// ( arr idx -- val )
+    /// Also reads an element of a packed array, or an entry of a map or dict by key. Missing
+    /// elements and entries read as null.
     IndexArray,
-    /// ( arr idx -- val )
+    /// ( arr idx val -- )
+    /// Also sets an element of a packed array, or an entry of a map or dict by key. Storing
+    /// past the end of an array or packed array grows it.
     SetArray,
     /// ( v -- )
     Drop,
@@ -45,6 +50,164 @@ pub enum Operation {
     /// ( val -- )
     /// Output to device debug.
     DebugOut,
+    /// ( -- )
+    /// Installs an exception handler at the given offset, until the matching EndTry.
+    /// When anything raises inside the region, the stack is cut back to its depth at the Try,
+    /// the exception value is pushed and execution continues at the handler.
+    Try(u32),
+    /// ( -- )
+    /// Removes the innermost exception handler and continues at the given offset.
+    EndTry(u32),
+    /// ( val -- )
+    /// Raises val as an exception.
+    Throw,
+    /// ( ... -- ... )
+    /// Calls the host's handler for this syscall number, see [SyscallTable](super::SyscallTable).
+    /// The stack effect is up to the handler.
+    Syscall(u16),
+    /// ( args -- results )
+    /// Calls a function of the running module by its index in the function table. The callee
+    /// works on the caller's stack, taking its arguments from the top.
+    Call(u32),
+    /// ( args -- results )
+    /// Calls the function an entry of the running module's import table names, looked up when
+    /// the call runs. Raises UndefinedFunction if nothing by that name and arity is loaded.
+    CallImport(u32),
+    /// ( -- )
+    /// Returns to the caller. Running off the end of a function does the same.
+    Return,
+    /// ( -- const )
+    /// Pushes an entry of the running module's constant pool.
+    PushConst(u32),
+    /// ( -- val )
+    /// Pushes a local slot of the running function.
+    LoadLocal(u16),
+    /// ( val -- )
+    /// Stores into a local slot of the running function.
+    StoreLocal(u16),
+    /// ( -- val )
+    /// Pushes a global of the running module.
+    LoadGlobal(u16),
+    /// ( val -- )
+    /// Stores into a global of the running module.
+    StoreGlobal(u16),
+    /// ( captured -- fun )
+    /// Makes a function value from a function of the running module by index, capturing the
+    /// given number of values. When called, the captured values are passed after the
+    /// arguments, so the function value takes that many fewer.
+    MakeClosure(u32, u16),
+    /// ( args fun -- results )
+    /// Calls a function value, raising BadArity unless it takes this many arguments.
+    CallIndirect(u16),
+    /// ( args -- results )
+    /// Like Call followed by Return, but the callee takes over the running frame, so calls in
+    /// tail position don't grow the frame stack.
+    TailCall(u32),
+    /// ( args fun -- results )
+    /// Like CallIndirect followed by Return, taking over the running frame like TailCall.
+    TailCallIndirect(u16),
+    /// ( x y -- x y x )
+    Over,
+    /// ( x y z -- y z x )
+    Rot,
+    /// ( x y z -- z x y )
+    /// Forth's `-rot`.
+    MinusRot,
+    /// ( x y -- y )
+    Nip,
+    /// ( x y -- y x y )
+    Tuck,
+    /// ( xn ... x0 -- xn ... x0 xn )
+    /// `Pick(0)` is Dup, `Pick(1)` is Over.
+    Pick(u16),
+    /// ( xn ... x0 -- xn-1 ... x0 xn )
+    /// `Roll(1)` is Swap, `Roll(2)` is Rot.
+    Roll(u16),
+    /// ( x y -- x y x y )
+    TwoDup,
+    /// ( x y -- )
+    TwoDrop,
+    /// ( w x y z -- y z w x )
+    TwoSwap,
+    /// ( -- n )
+    /// Pushes the number of values on the stack before it, as a u64.
+    Depth,
+    /// ( -- )
+    /// Continues at the given offset.
+    Jump(u32),
+    /// ( n -- )
+    /// Continues at the given offset if n is zero, of any kind.
+    JumpIfZero(u32),
+    /// ( x y -- n )
+    /// Pushes -1, 0 or 1 as an i64 as x is less than, equal to or greater than y in the term
+    /// order, see [Value::term_cmp](super::Value::term_cmp).
+    Compare,
+    /// ( x -- h )
+    /// Pushes the structural hash of x as a u64, see
+    /// [Value::structural_hash](super::Value::structural_hash).
+    Hash,
+    /// ( -- dict )
+    /// Makes an empty dict, a map keyed by any value. IndexArray and SetArray read and write
+    /// its entries, as they do those of maps.
+    MakeDict,
+    /// ( map -- keys )
+    /// Pushes an array of the keys of a map or dict, in insertion order.
+    MapKeys,
+    /// ( map -- values )
+    /// Pushes an array of the values of a map or dict, in insertion order.
+    MapValues,
+    /// ( map -- entries )
+    /// Pushes an array of the entries of a map or dict as `[key, value]` arrays, in insertion
+    /// order.
+    MapEntries,
+    /// ( m1 m2 -- m )
+    /// Pushes a new map with the entries of m1 then m2, m2's winning where both have a key.
+    /// Two maps merge into a map, anything else into a dict.
+    MapMerge,
+    /// ( map keys -- map' )
+    /// Pushes a new map or dict with only the entries whose keys are in the array keys. To
+    /// filter by a predicate, collect the keys to keep from MapEntries.
+    MapFilter,
+    /// ( n -- bin )
+    /// Makes a binary of n zero bytes. IndexArray and SetArray read and write its bytes as u8.
+    MakeBinary,
+    /// ( obj -- n )
+    /// Pushes the number of elements of an array or packed array, entries of a map or dict, or
+    /// bytes of a binary, string or atom, as a u64.
+    Length,
+    /// ( bin start len -- bin' )
+    /// Pushes a binary of len bytes of bin from start. It shares them rather than copying
+    /// them, so writes through either show in both.
+    BinarySlice,
+    /// ( b1 b2 -- b )
+    /// Pushes a new binary of the bytes of b1 then b2.
+    BinaryConcat,
+    /// ( bin offset -- n )
+    /// Reads an int of the given kind and byte order at a byte offset of a binary.
+    BinaryRead(PrimOpKind, Endian),
+    /// ( bin offset n -- )
+    /// Writes n, truncated to the given kind, in the given byte order at a byte offset of a
+    /// binary.
+    BinaryWrite(PrimOpKind, Endian),
+    /// ( n -- arr )
+    /// Makes a packed array of n zeros of the given kind. It holds its ints densely, and
+    /// stores ints of any kind truncated to its own.
+    MakePacked(PrimOpKind),
+    /// ( arr start count n -- )
+    /// Sets count elements of a packed array from start to n.
+    PackedFill,
+    /// ( src from dst to count -- )
+    /// Copies count elements of a packed array from `from` to another, or the same one, at
+    /// `to`, truncating them to its kind.
+    PackedCopy,
+    /// ( dst src -- )
+    /// Combines each element of a packed array with the same element of another as long,
+    /// wrapping in the first's kind.
+    PackedCombine(Combine),
+    /// ( arr -- n )
+    /// Reduces a packed array to one int. Sums wrap, in u64 for unsigned kinds and i64 for
+    /// signed. The min and max of an empty array are null.
+    PackedReduce(Reduce),
     // the final op, used for discriminant
     __Final,
 }
@@ -58,85 +221,268 @@ impl Operation {
         unsafe { *<*const _>::from(self).cast::<u8>() }
     }
 
+    /// How the operation changes the stack, None when that depends on more than the operation
+    /// itself, like a function's arity or a syscall's handler.
+    pub fn stack_effect(&self) -> Option<StackEffect> {
+        let (inputs, outputs) = match *self {
+            Operation::Trap
+            | Operation::Try(_)
+            | Operation::EndTry(_)
+            | Operation::Return
+            | Operation::Jump(_) => (0, 0),
+            Operation::Add(_) | Operation::Sub(_) | Operation::Mul(_) => (2, 1),
+            Operation::AddImm(..) | Operation::SubImm(..) | Operation::MulImm(..) => (1, 1),
+            Operation::Div(_) => (2, 2),
+            Operation::DivImm(..) => (1, 2),
+            Operation::PushImm(..)
+            | Operation::PushAtom(_)
+            | Operation::PushConst(_)
+            | Operation::MakeObject(_)
+            | Operation::MakeArray
+            | Operation::LoadLocal(_)
+            | Operation::LoadGlobal(_)
+            | Operation::Depth => (0, 1),
+            Operation::Compare => (2, 1),
+            Operation::Hash => (1, 1),
+            Operation::MakeDict => (0, 1),
+            Operation::MapKeys | Operation::MapValues | Operation::MapEntries => (1, 1),
+            Operation::MapMerge | Operation::MapFilter => (2, 1),
+            Operation::MakeBinary | Operation::Length => (1, 1),
+            Operation::BinarySlice => (3, 1),
+            Operation::BinaryConcat | Operation::BinaryRead(..) => (2, 1),
+            Operation::BinaryWrite(..) => (3, 0),
+            Operation::MakePacked(_) | Operation::PackedReduce(_) => (1, 1),
+            Operation::PackedFill => (4, 0),
+            Operation::PackedCopy => (5, 0),
+            Operation::PackedCombine(_) => (2, 0),
+            Operation::IndexArray => (2, 1),
+            Operation::SetArray => (3, 0),
+            Operation::Drop
+            | Operation::DebugOut
+            | Operation::Throw
+            | Operation::JumpIfZero(_)
+            | Operation::StoreLocal(_)
+            | Operation::StoreGlobal(_) => (1, 0),
+            Operation::Dup => (1, 2),
+            Operation::Swap => (2, 2),
+            Operation::MakeClosure(_, n) => (n as usize, 1),
+            Operation::Over => (2, 3),
+            Operation::Rot | Operation::MinusRot => (3, 3),
+            Operation::Nip => (2, 1),
+            Operation::Tuck => (2, 3),
+            Operation::Pick(n) => (n as usize + 1, n as usize + 2),
+            Operation::Roll(n) => (n as usize + 1, n as usize + 1),
+            Operation::TwoDup => (2, 4),
+            Operation::TwoDrop => (2, 0),
+            Operation::TwoSwap => (4, 4),
+            Operation::Syscall(_)
+            | Operation::Call(_)
+            | Operation::CallImport(_)
+            | Operation::CallIndirect(_)
+            | Operation::TailCall(_)
+            | Operation::TailCallIndirect(_)
+            | Operation::__Final => return None,
+        };
+        Some(StackEffect { inputs, outputs })
+    }
+
     /// Returns the number of kinds of operations implemented. Useful for en/de coding.
     pub fn kinds(&self) -> u8 {
-        return variant_count::<Self>() as u8;
+        variant_count::<Self>() as u8
     }
 }
 
+/// What an operation does to the stack, as in its `( inputs -- outputs )` comment: it needs
+/// `inputs` values on top of the stack and leaves `outputs` in their place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+/// An immediate operand, holding its value sign or zero extended to 64 bits. Each op reads it
+/// back truncated to the op's kind.
 #[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct IntOpImmediate(u64);
 
 impl IntOpImmediate {
     pub fn read_u8(&self, kind: PrimOpKind) -> u8 {
         debug_assert!(kind == PrimOpKind::U8);
-        let ptr = &self.0 as *const u64 as *const u8;
-        u8::from_le(unsafe { ptr.read() })
+        u8::from_bits(self.0)
     }
 
     pub fn read_i8(&self, kind: PrimOpKind) -> i8 {
         debug_assert!(kind == PrimOpKind::I8);
-        let ptr = &self.0 as *const u64 as *const i8;
-        i8::from_le(unsafe { ptr.read() })
+        i8::from_bits(self.0)
     }
 
     pub fn read_u16(&self, kind: PrimOpKind) -> u16 {
         debug_assert!(kind == PrimOpKind::U16);
-        let ptr = &self.0 as *const u64 as *const u16;
-        u16::from_le(unsafe { ptr.read() })
+        u16::from_bits(self.0)
     }
 
     pub fn read_i16(&self, kind: PrimOpKind) -> i16 {
         debug_assert!(kind == PrimOpKind::I16);
-        let ptr = &self.0 as *const u64 as *const i16;
-        i16::from_le(unsafe { ptr.read() })
+        i16::from_bits(self.0)
     }
 
     pub fn read_u32(&self, kind: PrimOpKind) -> u32 {
         debug_assert!(kind == PrimOpKind::U32);
-        let ptr = &self.0 as *const u64 as *const u32;
-        u32::from_le(unsafe { ptr.read() })
+        u32::from_bits(self.0)
     }
 
     pub fn read_i32(&self, kind: PrimOpKind) -> i32 {
         debug_assert!(kind == PrimOpKind::I32);
-        let ptr = &self.0 as *const u64 as *const i32;
-        i32::from_le(unsafe { ptr.read() })
+        i32::from_bits(self.0)
     }
 
     pub fn read_u64(&self, kind: PrimOpKind) -> u64 {
         debug_assert!(kind == PrimOpKind::U64);
-        let ptr = &self.0 as *const u64;
-        u64::from_le(unsafe { ptr.read() })
+        u64::from_bits(self.0)
     }
 
     pub fn read_i64(&self, kind: PrimOpKind) -> i64 {
         debug_assert!(kind == PrimOpKind::I64);
-        let ptr = &self.0 as *const u64 as *const i64;
-        i64::from_le(unsafe { ptr.read() })
+        i64::from_bits(self.0)
+    }
+
+    /// The value as an int of the given kind, in an i128 that holds all of them.
+    pub fn to_i128(&self, kind: PrimOpKind) -> i128 {
+        let bits = kind.normalize(self.0);
+        if kind.is_signed() {
+            bits as i64 as i128
+        } else {
+            bits as i128
+        }
     }
 
     pub fn as_aligned(&self) -> Aligned {
-        Aligned(bytemuck::cast(self.0.clone()))
+        Aligned(self.0)
     }
 
-    fn slice_as_array<const N: usize>(s: &[u8]) -> [u8; N] {
-        <&[u8; N]>::try_from(s).unwrap().clone()
+    pub fn to_le_bytes(&self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    pub fn from_le_bytes(bytes: [u8; 8]) -> IntOpImmediate {
+        IntOpImmediate(u64::from_le_bytes(bytes))
     }
 }
 
-impl<T> From<T> for IntOpImmediate
-where
-    T: Pod,
-{
+impl<T: Num> From<T> for IntOpImmediate {
     fn from(value: T) -> Self {
-        let mut a = MaybeUninit::uninit();
-        let ptr = a.as_mut_ptr() as *mut T;
-        //SAFETY: Guaranteed to fit.
-        unsafe {
-            ptr.write(value);
+        IntOpImmediate(value.to_bits())
+    }
+}
+
+/// The byte order of a [BinaryRead](Operation::BinaryRead) or
+/// [BinaryWrite](Operation::BinaryWrite).
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// The byte order with the given `repr(u8)` discriminant.
+    pub fn from_u8(v: u8) -> Option<Endian> {
+        match v {
+            0 => Some(Endian::Little),
+            1 => Some(Endian::Big),
+            _ => None,
+        }
+    }
+
+    /// The byte order with the given [name](Endian::name).
+    pub fn from_name(name: &str) -> Option<Endian> {
+        by_name(name, Endian::from_u8, Endian::name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Endian::Little => "le",
+            Endian::Big => "be",
+        }
+    }
+}
+
+/// Looks up an operand enum by name, trying its `u8` discriminants from 0 until one isn't
+/// valid.
+fn by_name<T>(
+    name: &str,
+    from_u8: fn(u8) -> Option<T>,
+    name_of: fn(&T) -> &'static str,
+) -> Option<T> {
+    (0..=u8::MAX).map_while(from_u8).find(|v| name_of(v) == name)
+}
+
+/// How a [PackedCombine](Operation::PackedCombine) combines elements.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Combine {
+    Add,
+    Sub,
+    Mul,
+}
+
+impl Combine {
+    /// The combination with the given `repr(u8)` discriminant.
+    pub fn from_u8(v: u8) -> Option<Combine> {
+        match v {
+            0 => Some(Combine::Add),
+            1 => Some(Combine::Sub),
+            2 => Some(Combine::Mul),
+            _ => None,
+        }
+    }
+
+    /// The combination with the given [name](Combine::name).
+    pub fn from_name(name: &str) -> Option<Combine> {
+        by_name(name, Combine::from_u8, Combine::name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Combine::Add => "add",
+            Combine::Sub => "sub",
+            Combine::Mul => "mul",
+        }
+    }
+}
+
+/// What a [PackedReduce](Operation::PackedReduce) reduces an array to.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reduce {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Reduce {
+    /// The reduction with the given `repr(u8)` discriminant.
+    pub fn from_u8(v: u8) -> Option<Reduce> {
+        match v {
+            0 => Some(Reduce::Sum),
+            1 => Some(Reduce::Min),
+            2 => Some(Reduce::Max),
+            _ => None,
+        }
+    }
+
+    /// The reduction with the given [name](Reduce::name).
+    pub fn from_name(name: &str) -> Option<Reduce> {
+        by_name(name, Reduce::from_u8, Reduce::name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Reduce::Sum => "sum",
+            Reduce::Min => "min",
+            Reduce::Max => "max",
         }
-        return IntOpImmediate(unsafe { a.assume_init() });
     }
 }
 
@@ -152,3 +498,38 @@ pub enum PrimOpKind {
     U64,
     I64,
 }
+
+impl PrimOpKind {
+    /// The kind with the given `repr(u8)` discriminant.
+    pub fn from_u8(v: u8) -> Option<PrimOpKind> {
+        Some(match v {
+            0 => PrimOpKind::U8,
+            1 => PrimOpKind::I8,
+            2 => PrimOpKind::U16,
+            3 => PrimOpKind::I16,
+            4 => PrimOpKind::U32,
+            5 => PrimOpKind::I32,
+            6 => PrimOpKind::U64,
+            7 => PrimOpKind::I64,
+            _ => return None,
+        })
+    }
+
+    /// The kind with the given [name](PrimOpKind::name).
+    pub fn from_name(name: &str) -> Option<PrimOpKind> {
+        by_name(name, PrimOpKind::from_u8, PrimOpKind::name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PrimOpKind::U8 => "u8",
+            PrimOpKind::I8 => "i8",
+            PrimOpKind::U16 => "u16",
+            PrimOpKind::I16 => "i16",
+            PrimOpKind::U32 => "u32",
+            PrimOpKind::I32 => "i32",
+            PrimOpKind::U64 => "u64",
+            PrimOpKind::I64 => "i64",
+        }
+    }
+}
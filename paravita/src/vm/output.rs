@@ -0,0 +1,113 @@
+use core::cell::RefCell;
+
+use alloc::{format, string::String};
+
+use super::Value;
+
+/// Where [Operation::DebugOut](super::Operation::DebugOut) writes. Install one on a
+/// [Vm](super::Vm) or a single [Process](super::Process).
+///
+/// Devices are shared by the processes they're installed on, so they take `&self` and keep
+/// any state behind a cell.
+pub trait OutputDevice {
+    /// Writes text as is.
+    fn write_str(&self, s: &str);
+
+    /// Writes a value on a line of its own, as DebugOut does. See [Value::pretty] for how it
+    /// looks.
+    fn write_value(&self, v: &Value) {
+        self.write_str(&format!("{v}\n"));
+    }
+}
+
+/// Writes to the host's standard output.
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdoutDevice;
+
+#[cfg(feature = "std")]
+impl OutputDevice for StdoutDevice {
+    fn write_str(&self, s: &str) {
+        use std::io::Write;
+        // Like print!, but a closed stdout isn't the VM's problem.
+        let _ = std::io::stdout().write_all(s.as_bytes());
+    }
+}
+
+/// Collects output in memory, for tests and for hosts that show it their own way.
+#[derive(Debug, Default)]
+pub struct BufferDevice {
+    buffer: RefCell<String>,
+}
+
+impl BufferDevice {
+    pub fn new() -> BufferDevice {
+        BufferDevice::default()
+    }
+
+    /// Everything written so far.
+    pub fn contents(&self) -> String {
+        self.buffer.borrow().clone()
+    }
+
+    /// Takes everything written so far, leaving the buffer empty.
+    pub fn take(&self) -> String {
+        self.buffer.take()
+    }
+}
+
+impl OutputDevice for BufferDevice {
+    fn write_str(&self, s: &str) {
+        self.buffer.borrow_mut().push_str(s);
+    }
+}
+
+/// Writes to a 16550 UART by port I/O, for the kernel. Newlines go out as CR LF.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug)]
+pub struct SerialDevice {
+    port: u16,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SerialDevice {
+    /// The base port of the first serial port on PCs.
+    pub const COM1: u16 = 0x3f8;
+
+    /// A device writing to the UART at `port`, which the firmware or kernel has set up.
+    ///
+    /// # Safety
+    /// The code must be allowed port I/O, as the kernel is, and nothing else may be using the
+    /// port.
+    pub unsafe fn new(port: u16) -> SerialDevice {
+        SerialDevice { port }
+    }
+
+    fn write_byte(&self, byte: u8) {
+        use core::arch::asm;
+        // Wait for the transmit holding register to empty, bit 5 of the line status register.
+        loop {
+            let status: u8;
+            // SAFETY: new's contract allows port I/O on this UART.
+            unsafe { asm!("in al, dx", out("al") status, in("dx") self.port + 5, options(nomem, nostack)) };
+            if status & 0x20 != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        // SAFETY: as above.
+        unsafe { asm!("out dx, al", in("dx") self.port, in("al") byte, options(nomem, nostack)) };
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl OutputDevice for SerialDevice {
+    fn write_str(&self, s: &str) {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use core::cmp::Ordering;
+
+use alloc::vec::Vec;
+use num::traits::{WrappingAdd, WrappingMul, WrappingSub};
+
+use super::{
+    error::{VmError, VmResult},
+    Combine, Num, PrimOpKind, Reduce, Value, ValueKind,
+};
+
+/// An array of ints of one kind, stored densely rather than as [Value]s. Ints of any kind
+/// can be stored in it, and are truncated to its kind like an `as` cast.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packed {
+    U8(Vec<u8>),
+    I8(Vec<i8>),
+    U16(Vec<u16>),
+    I16(Vec<i16>),
+    U32(Vec<u32>),
+    I32(Vec<i32>),
+    U64(Vec<u64>),
+    I64(Vec<i64>),
+}
+
+/// Runs `$body` with `$v` bound to the elements of a packed array, whatever their type.
+macro_rules! each {
+    ($p:expr, $v:ident => $body:expr) => {
+        match $p {
+            Packed::U8($v) => $body,
+            Packed::I8($v) => $body,
+            Packed::U16($v) => $body,
+            Packed::I16($v) => $body,
+            Packed::U32($v) => $body,
+            Packed::I32($v) => $body,
+            Packed::U64($v) => $body,
+            Packed::I64($v) => $body,
+        }
+    };
+}
+
+fn zeros<T: Num>(len: usize) -> VmResult<Vec<T>> {
+    let mut v = Vec::new();
+    v.try_reserve_exact(len)?;
+    v.resize(len, T::from_bits(0));
+    Ok(v)
+}
+
+fn bits(v: &Value) -> VmResult<u64> {
+    let (_, bits) = v.int_bits().ok_or(VmError::PopExpectedType {
+        expected: ValueKind::Int,
+        found: v.kind(),
+    })?;
+    Ok(bits)
+}
+
+/// Copies `src` into `dst`, truncating each element to `dst`'s type.
+fn convert<T: Num, U: Num>(dst: &mut [T], src: &[U]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d = T::from_bits(s.to_bits());
+    }
+}
+
+fn combine<T, U>(op: Combine, dst: &mut [T], src: &[U])
+where
+    T: Num + WrappingAdd + WrappingSub + WrappingMul,
+    U: Num,
+{
+    for (d, s) in dst.iter_mut().zip(src) {
+        let s = T::from_bits(s.to_bits());
+        *d = match op {
+            Combine::Add => d.wrapping_add(&s),
+            Combine::Sub => d.wrapping_sub(&s),
+            Combine::Mul => d.wrapping_mul(&s),
+        };
+    }
+}
+
+fn reduce<T: Num + Ord>(op: Reduce, v: &[T]) -> Option<u64> {
+    match op {
+        // Sign extended bits add up to the right i64 as well as u64.
+        Reduce::Sum => Some(v.iter().fold(0u64, |acc, x| acc.wrapping_add(x.to_bits()))),
+        Reduce::Min => v.iter().min().map(|x| x.to_bits()),
+        Reduce::Max => v.iter().max().map(|x| x.to_bits()),
+    }
+}
+
+impl Packed {
+    /// A packed array of `len` zeros of kind `k`.
+    pub fn zeroed(k: PrimOpKind, len: usize) -> VmResult<Packed> {
+        Ok(match k {
+            PrimOpKind::U8 => Packed::U8(zeros(len)?),
+            PrimOpKind::I8 => Packed::I8(zeros(len)?),
+            PrimOpKind::U16 => Packed::U16(zeros(len)?),
+            PrimOpKind::I16 => Packed::I16(zeros(len)?),
+            PrimOpKind::U32 => Packed::U32(zeros(len)?),
+            PrimOpKind::I32 => Packed::I32(zeros(len)?),
+            PrimOpKind::U64 => Packed::U64(zeros(len)?),
+            PrimOpKind::I64 => Packed::I64(zeros(len)?),
+        })
+    }
+
+    /// A packed array of kind `k` holding `elems`, which must fit it.
+    pub fn from_ints(k: PrimOpKind, elems: &[i128]) -> VmResult<Packed> {
+        let mut p = Packed::zeroed(k, elems.len())?;
+        let (min, max) = k.bounds();
+        for (i, &e) in elems.iter().enumerate() {
+            if !(min..=max).contains(&e) {
+                return Err(VmError::IntOutOfRange {
+                    value: e,
+                    target: k,
+                });
+            }
+            p.set(i, &Value::int(k, e as u64))?;
+        }
+        Ok(p)
+    }
+
+    pub fn kind(&self) -> PrimOpKind {
+        match self {
+            Packed::U8(_) => PrimOpKind::U8,
+            Packed::I8(_) => PrimOpKind::I8,
+            Packed::U16(_) => PrimOpKind::U16,
+            Packed::I16(_) => PrimOpKind::I16,
+            Packed::U32(_) => PrimOpKind::U32,
+            Packed::I32(_) => PrimOpKind::I32,
+            Packed::U64(_) => PrimOpKind::U64,
+            Packed::I64(_) => PrimOpKind::I64,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        each!(self, v => v.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes the elements take, counting spare capacity.
+    pub fn capacity_bytes(&self) -> usize {
+        each!(self, v => v.capacity() * self.kind().size())
+    }
+
+    /// The element at `i`, or None past the end.
+    pub fn get(&self, i: usize) -> Option<Value> {
+        let k = self.kind();
+        each!(self, v => v.get(i).map(|x| Value::int(k, x.to_bits())))
+    }
+
+    /// Sets the element at `i` to an int of any kind, growing the array with zeros if `i` is
+    /// past the end.
+    pub fn set(&mut self, i: usize, value: &Value) -> VmResult<()> {
+        let bits = bits(value)?;
+        each!(self, v => {
+            if i >= v.len() {
+                v.try_reserve(i - v.len() + 1)?;
+                v.resize(i + 1, Num::from_bits(0));
+            }
+            v[i] = Num::from_bits(bits);
+        });
+        Ok(())
+    }
+
+    /// Checks that `count` elements from `start` are in the array.
+    fn check(&self, start: usize, count: usize) -> VmResult<()> {
+        match start.checked_add(count) {
+            Some(end) if end <= self.len() => Ok(()),
+            _ => Err(VmError::OutOfBounds {
+                start,
+                count,
+                length: self.len(),
+            }),
+        }
+    }
+
+    /// Sets `count` elements from `start` to an int of any kind.
+    pub fn fill(&mut self, start: usize, count: usize, value: &Value) -> VmResult<()> {
+        let bits = bits(value)?;
+        self.check(start, count)?;
+        each!(self, v => v[start..start + count].fill(Num::from_bits(bits)));
+        Ok(())
+    }
+
+    /// Copies `count` elements of `src` from `from` into this array at `to`.
+    pub fn copy_from(
+        &mut self,
+        to: usize,
+        src: &Packed,
+        from: usize,
+        count: usize,
+    ) -> VmResult<()> {
+        src.check(from, count)?;
+        self.check(to, count)?;
+        let src_range = from..from + count;
+        each!(src, s => each!(self, d => convert(&mut d[to..to + count], &s[src_range.clone()])));
+        Ok(())
+    }
+
+    /// Copies `count` elements from `from` to `to` within this array. The ranges may overlap.
+    pub fn copy_within(&mut self, from: usize, to: usize, count: usize) -> VmResult<()> {
+        self.check(from, count)?;
+        self.check(to, count)?;
+        each!(self, v => v.copy_within(from..from + count, to));
+        Ok(())
+    }
+
+    /// Combines each element with the same element of `src`, which must be as long.
+    pub fn combine(&mut self, op: Combine, src: &Packed) -> VmResult<()> {
+        if src.len() != self.len() {
+            return Err(VmError::OutOfBounds {
+                start: 0,
+                count: src.len(),
+                length: self.len(),
+            });
+        }
+        each!(src, s => each!(self, d => combine(op, d, s)));
+        Ok(())
+    }
+
+    /// Combines each element with itself.
+    pub fn combine_self(&mut self, op: Combine) {
+        each!(self, v => for x in v.iter_mut() {
+            let y = *x;
+            combine(op, core::slice::from_mut(x), &[y]);
+        });
+    }
+
+    /// Reduces the array to one int, or null for the min and max of an empty array.
+    pub fn reduce(&self, op: Reduce) -> Value {
+        let k = match (op, self.kind().is_signed()) {
+            (Reduce::Sum, false) => PrimOpKind::U64,
+            (Reduce::Sum, true) => PrimOpKind::I64,
+            _ => self.kind(),
+        };
+        match each!(self, v => reduce(op, v)) {
+            Some(bits) => Value::int(k, bits),
+            None => Value::Null,
+        }
+    }
+
+    /// The elements as i128, which holds all of them exactly.
+    pub fn ints(&self) -> impl ExactSizeIterator<Item = i128> + '_ {
+        (0..self.len()).map(|i| self.get(i).and_then(|v| v.as_i128()).expect("in bounds"))
+    }
+
+    /// Compares element by element by value, then by length, then by kind, the way ints
+    /// compare.
+    pub(super) fn term_cmp(&self, other: &Packed) -> Ordering {
+        self.ints()
+            .cmp(other.ints())
+            .then(self.kind().cmp(&other.kind()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv6Addr;
+
+    use alloc::{format, string::String, vec::Vec};
+
+    use super::Packed;
+    use crate::{
+        forth,
+        vm::{Combine, PrimOpKind, Reduce, Value, Vm, VmError},
+    };
+
+    #[test]
+    pub fn elements() {
+        let mut p = Packed::zeroed(PrimOpKind::U8, 2).unwrap();
+        p.set(1, &Value::from(300i64, PrimOpKind::I64)).unwrap();
+        p.set(3, &Value::from(7u8, PrimOpKind::U8)).unwrap();
+        assert_eq!(p.ints().collect::<Vec<_>>(), [0, 44, 0, 7]);
+        assert_eq!(p.get(1), Some(Value::from(44u8, PrimOpKind::U8)));
+        assert_eq!(p.get(4), None);
+        assert!(p.set(0, &Value::Null).is_err());
+
+        p.fill(1, 2, &Value::from(-1i64, PrimOpKind::I64)).unwrap();
+        assert_eq!(p.ints().collect::<Vec<_>>(), [0, 255, 255, 7]);
+        assert!(matches!(
+            p.fill(3, 2, &Value::from(0u8, PrimOpKind::U8)),
+            Err(VmError::OutOfBounds {
+                start: 3,
+                count: 2,
+                length: 4
+            })
+        ));
+
+        let mut wide = Packed::from_ints(PrimOpKind::I16, &[-1, 2, 3]).unwrap();
+        wide.copy_from(1, &p, 1, 2).unwrap();
+        assert_eq!(wide.ints().collect::<Vec<_>>(), [-1, 255, 255]);
+        wide.copy_within(1, 0, 2).unwrap();
+        assert_eq!(wide.ints().collect::<Vec<_>>(), [255, 255, 255]);
+        assert!(Packed::from_ints(PrimOpKind::U8, &[256]).is_err());
+    }
+
+    #[test]
+    pub fn combine_and_reduce() {
+        let mut a = Packed::from_ints(PrimOpKind::I8, &[100, -100, 3]).unwrap();
+        let b = Packed::from_ints(PrimOpKind::U8, &[100, 1, 255]).unwrap();
+        a.combine(Combine::Add, &b).unwrap();
+        // 200 and 255 wrap in i8.
+        assert_eq!(a.ints().collect::<Vec<_>>(), [-56, -99, 2]);
+        a.combine_self(Combine::Mul);
+        assert_eq!(a.ints().collect::<Vec<_>>(), [64, 73, 4]);
+        assert!(a
+            .combine(Combine::Sub, &Packed::zeroed(PrimOpKind::I8, 1).unwrap())
+            .is_err());
+
+        assert_eq!(a.reduce(Reduce::Sum), Value::from(141i64, PrimOpKind::I64));
+        assert_eq!(a.reduce(Reduce::Min), Value::from(4i8, PrimOpKind::I8));
+        assert_eq!(b.reduce(Reduce::Max), Value::from(255u8, PrimOpKind::U8));
+        assert_eq!(b.reduce(Reduce::Sum), Value::from(356u64, PrimOpKind::U64));
+        let neg = Packed::from_ints(PrimOpKind::I32, &[-5, 2]).unwrap();
+        assert_eq!(neg.reduce(Reduce::Sum), Value::from(-3i64, PrimOpKind::I64));
+        let empty = Packed::zeroed(PrimOpKind::U32, 0).unwrap();
+        assert_eq!(empty.reduce(Reduce::Max), Value::Null);
+        assert_eq!(
+            empty.reduce(Reduce::Sum),
+            Value::from(0u64, PrimOpKind::U64)
+        );
+    }
+
+    #[test]
+    pub fn packed_ops() {
+        // A histogram of some bytes, then its total and fullest bucket.
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        let name = vm
+            .load(
+                forth::compile_module(
+                    atom!("packed"),
+                    r#"4 u32array  6 u8array dup 0 6 3 fill dup 1 1 !
+                    6 0 do dup i @ 2 pick swap 2dup @ 1u32 +u32 ! loop drop
+                    dup vsum over vmax 2 pick dup v+ 2 pick length
+                    3 i16array dup 5 pick 1 rot 0 3 copy"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let pid = vm.spawn().unwrap();
+        vm.start(pid, name, atom!("main"), ()).unwrap();
+        vm.resume(pid, usize::MAX).unwrap();
+        let stack: Vec<String> = vm
+            .process(pid)
+            .unwrap()
+            .stack()
+            .map(|v| format!("{v}"))
+            .collect();
+        assert_eq!(
+            stack,
+            [
+                "#u32[0, 2, 0, 10]",
+                "6u64",
+                "5u32",
+                "4u64",
+                "#i16[2, 0, 10]"
+            ]
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use core::fmt::{self, Display, Formatter, Write};
+
+use alloc::vec::Vec;
+
+use super::{Binary, PVObjectType, PVString, Packed, PrimOpKind, Value};
+
+/// Formats a [Value] for people, see [Value::pretty]. `{}` writes it on one line, `{:#}` one
+/// element per line, indented by two spaces a level.
+///
+/// Ints are written with their kind, `7u8`, unless they're i64. Atoms are written `:name`,
+/// strings quoted, functions `#Fun<module:name/arity>`. Maps are written `{key: value}`, dicts
+/// `#{key => value}`, binaries `<<1, 2, 255>>` and packed arrays `#u16[1, 2]`. An object met
+/// again inside itself is written `^n`, n being how many levels up it is, so cyclic values
+/// print fine.
+#[derive(Debug, Clone, Copy)]
+pub struct Pretty<'a> {
+    value: &'a Value,
+    depth: usize,
+    items: usize,
+}
+
+impl Pretty<'_> {
+    /// How deep arrays, maps and dicts are written unless set otherwise.
+    pub const DEFAULT_DEPTH: usize = 8;
+    /// How many elements of an array, map or dict are written unless set otherwise.
+    pub const DEFAULT_ITEMS: usize = 32;
+
+    /// Writes arrays, maps and dicts nested deeper than `depth` as `[...]`, `{...}` and `#{...}`.
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Writes the first `items` elements of an array, map or dict, then how many were left out.
+    pub fn items(mut self, items: usize) -> Self {
+        self.items = items;
+        self
+    }
+}
+
+impl Value {
+    /// Formats the value for people, see [Pretty]. [Display] for values does the same with
+    /// the default limits.
+    pub fn pretty(&self) -> Pretty<'_> {
+        Pretty {
+            value: self,
+            depth: Pretty::DEFAULT_DEPTH,
+            items: Pretty::DEFAULT_ITEMS,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.pretty().fmt(f)
+    }
+}
+
+impl Display for Pretty<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Printer {
+            multiline: f.alternate(),
+            out: f,
+            limits: self,
+            path: Vec::new(),
+        }
+        .value(self.value)
+    }
+}
+
+/// The key an element is written with.
+enum Key<'v> {
+    None,
+    String(&'v PVString),
+    Value(&'v Value),
+}
+
+struct Printer<'p, 'f, 'a> {
+    out: &'f mut Formatter<'a>,
+    limits: &'p Pretty<'p>,
+    multiline: bool,
+    /// The objects being written, outermost first.
+    path: Vec<*const ()>,
+}
+
+impl Printer<'_, '_, '_> {
+    fn value(&mut self, v: &Value) -> fmt::Result {
+        let o = match v {
+            Value::Null => return self.out.write_str("null"),
+            Value::Int(..) => {
+                let (kind, _) = v.int_bits().expect("an int");
+                let n = v.as_i128().expect("an int");
+                return match kind {
+                    PrimOpKind::I64 => write!(self.out, "{n}"),
+                    _ => write!(self.out, "{n}{}", kind.name()),
+                };
+            }
+            Value::Object(o) => o,
+        };
+        let ptr = o.as_ptr();
+        if let Some(i) = self.path.iter().rposition(|p| *p == ptr) {
+            return write!(self.out, "^{}", self.path.len() - i);
+        }
+        match &*o.get() {
+            PVObjectType::String(s) => self.string(s),
+            PVObjectType::Function(c) => write!(self.out, "{c:?}"),
+            PVObjectType::UserData(u) => write!(self.out, "#userdata<{u:?}>"),
+            PVObjectType::Array(elems) => {
+                self.elements(ptr, "[", ']', elems.iter().map(|v| (Key::None, v)))
+            }
+            PVObjectType::Map(map) => {
+                self.elements(ptr, "{", '}', map.iter().map(|(k, v)| (Key::String(k), v)))
+            }
+            PVObjectType::Dict(dict) => {
+                self.elements(ptr, "#{", '}', dict.iter().map(|(k, v)| (Key::Value(k), v)))
+            }
+            PVObjectType::Binary(b) => self.bytes(b),
+            PVObjectType::Packed(p) => self.packed(p),
+        }
+    }
+
+    /// Writes a binary's bytes on one line, whatever the mode, as they hold no values.
+    fn bytes(&mut self, b: &Binary) -> fmt::Result {
+        self.out.write_str("<<")?;
+        self.ints(b.bytes().map(i128::from))?;
+        self.out.write_str(">>")
+    }
+
+    /// Writes a packed array's elements on one line, like a binary's bytes.
+    fn packed(&mut self, p: &Packed) -> fmt::Result {
+        write!(self.out, "#{}[", p.kind().name())?;
+        self.ints(p.ints())?;
+        self.out.write_char(']')
+    }
+
+    fn ints(&mut self, ints: impl ExactSizeIterator<Item = i128>) -> fmt::Result {
+        let len = ints.len();
+        for (i, n) in ints.take(self.limits.items).enumerate() {
+            if i > 0 {
+                self.out.write_str(", ")?;
+            }
+            write!(self.out, "{n}")?;
+        }
+        if len > self.limits.items {
+            write!(self.out, ", ...{} more", len - self.limits.items)?;
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, s: &PVString) -> fmt::Result {
+        match s {
+            PVString::Atom(a) => write!(self.out, ":{a}"),
+            PVString::Str(s) => write!(self.out, "{s:?}"),
+        }
+    }
+
+    fn elements<'v>(
+        &mut self,
+        ptr: *const (),
+        open: &str,
+        close: char,
+        elems: impl ExactSizeIterator<Item = (Key<'v>, &'v Value)>,
+    ) -> fmt::Result {
+        let len = elems.len();
+        self.out.write_str(open)?;
+        if len == 0 {
+            return self.out.write_char(close);
+        }
+        if self.path.len() >= self.limits.depth {
+            self.out.write_str("...")?;
+            return self.out.write_char(close);
+        }
+        self.path.push(ptr);
+        for (i, (key, v)) in elems.take(self.limits.items).enumerate() {
+            self.separator(i)?;
+            match key {
+                // Atom keys are written bare, like record fields.
+                Key::String(PVString::Atom(a)) => write!(self.out, "{a}: ")?,
+                Key::String(s) => {
+                    self.string(s)?;
+                    self.out.write_str(": ")?;
+                }
+                Key::Value(k) => {
+                    self.value(k)?;
+                    self.out.write_str(" => ")?;
+                }
+                Key::None => {}
+            }
+            self.value(v)?;
+        }
+        if len > self.limits.items {
+            self.separator(self.limits.items)?;
+            write!(self.out, "...{} more", len - self.limits.items)?;
+        }
+        self.path.pop();
+        if self.multiline {
+            self.newline()?;
+        }
+        self.out.write_char(close)
+    }
+
+    /// Goes before the i-th element.
+    fn separator(&mut self, i: usize) -> fmt::Result {
+        if i > 0 {
+            self.out.write_char(',')?;
+        }
+        if self.multiline {
+            self.newline()
+        } else if i > 0 {
+            self.out.write_char(' ')
+        } else {
+            Ok(())
+        }
+    }
+
+    fn newline(&mut self) -> fmt::Result {
+        self.out.write_char('\n')?;
+        for _ in 0..self.path.len() {
+            self.out.write_str("  ")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, string::String, vec, vec::Vec};
+
+    use crate::vm::{PVObject, PVObjectType, PVString, PrimOpKind, Value};
+
+    fn array(elems: Vec<Value>) -> Value {
+        Value::Object(PVObject::make_array_from(elems).unwrap())
+    }
+
+    fn int(n: impl Into<Value>) -> Value {
+        n.into()
+    }
+
+    fn str(s: &str) -> Value {
+        Value::Object(PVObject::make_string(PVString::Str(String::from(s))).unwrap())
+    }
+
+    #[test]
+    pub fn scalars() {
+        assert_eq!(format!("{}", Value::Null), "null");
+        assert_eq!(format!("{}", int(-3i64)), "-3");
+        assert_eq!(format!("{}", int(7u8)), "7u8");
+        assert_eq!(format!("{}", int(-2i16)), "-2i16");
+        assert_eq!(format!("{}", int(u64::MAX)), "18446744073709551615u64");
+        assert_eq!(
+            format!("{}", Value::int(PrimOpKind::I32, u64::MAX)),
+            "-1i32"
+        );
+        assert_eq!(
+            format!("{}", Value::Object(PVObject::from(atom!("ok")))),
+            ":ok"
+        );
+        assert_eq!(format!("{}", str("say \"hi\"\n")), r#""say \"hi\"\n""#);
+    }
+
+    #[test]
+    pub fn nested_and_limits() {
+        let map = PVObject::make_map().unwrap();
+        if let PVObjectType::Map(m) = &mut *map.get_mut() {
+            m.insert(PVString::Atom(atom!("ok")), int(1u8));
+            m.insert(PVString::Str(String::from("a b")), array(vec![]));
+        }
+        let v = array(vec![int(1i64), Value::Object(map), str("x")]);
+        assert_eq!(format!("{v}"), r#"[1, {ok: 1u8, "a b": []}, "x"]"#);
+        assert_eq!(
+            format!("{v:#}"),
+            "[\n  1,\n  {\n    ok: 1u8,\n    \"a b\": []\n  },\n  \"x\"\n]"
+        );
+        assert_eq!(format!("{}", v.pretty().depth(1)), r#"[1, {...}, "x"]"#);
+        assert_eq!(format!("{}", v.pretty().items(1)), "[1, ...2 more]");
+    }
+
+    #[test]
+    pub fn cycles() {
+        let outer = PVObject::make_array().unwrap();
+        let inner = PVObject::make_array_from(vec![Value::Object(outer.clone())]).unwrap();
+        if let PVObjectType::Array(a) = &mut *outer.get_mut() {
+            a.push(Value::Object(outer.clone()));
+            a.push(Value::Object(inner.clone()));
+        }
+        let v = Value::Object(outer.clone());
+        assert_eq!(format!("{v}"), "[^1, [^2]]");
+
+        // Shared but not cyclic isn't a back-reference.
+        let shared = array(vec![int(1i64)]);
+        let v = array(vec![shared.clone(), shared]);
+        assert_eq!(format!("{v}"), "[[1], [1]]");
+
+        // Break the cycle so the test doesn't leak.
+        if let PVObjectType::Array(a) = &mut *outer.get_mut() {
+            a.clear();
+        };
+    }
+}
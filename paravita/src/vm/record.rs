@@ -0,0 +1,213 @@
+use core::cell::Ref;
+
+use alloc::boxed::Box;
+
+use super::{
+    error::{VmError, VmResult},
+    Atom, FromValue, IntoValue, PVObject, PVObjectType, PVString, Value, ValueKind,
+};
+
+/// Builds a map object with atom keys, the shape Rust structs take inside the VM.
+/// Used by `#[derive(PvObject)]`, and by hand-written conversions.
+pub struct RecordBuilder {
+    obj: PVObject,
+}
+
+impl RecordBuilder {
+    pub fn new() -> VmResult<RecordBuilder> {
+        Ok(RecordBuilder {
+            obj: PVObject::make_map()?,
+        })
+    }
+
+    /// Converts and sets a field. `name` is only used to label conversion errors.
+    pub fn field<T: IntoValue>(self, key: Atom, name: &'static str, v: T) -> VmResult<Self> {
+        let v = v.into_value().map_err(|e| VmError::field(name, e))?;
+        self.obj.get_mut().set_field(key, v)?;
+        Ok(self)
+    }
+
+    pub fn finish(self) -> Value {
+        Value::Object(self.obj)
+    }
+}
+
+/// Reads the fields of a map object built by [RecordBuilder].
+pub struct RecordReader<'a> {
+    map: Ref<'a, PVObjectType>,
+}
+
+impl<'a> RecordReader<'a> {
+    pub fn new(v: &'a Value) -> VmResult<RecordReader<'a>> {
+        if let Value::Object(o) = v {
+            let map = o.get();
+            if let PVObjectType::Map(_) = &*map {
+                return Ok(RecordReader { map });
+            }
+        }
+        Err(VmError::PopExpectedType {
+            expected: ValueKind::Map,
+            found: v.kind(),
+        })
+    }
+
+    fn get(&self, key: Atom) -> Option<&Value> {
+        match &*self.map {
+            PVObjectType::Map(m) => m.get(&PVString::Atom(key)),
+            _ => None,
+        }
+    }
+
+    /// Converts a required field, failing with [VmError::MissingField] if it isn't there.
+    pub fn field<T: FromValue>(&self, key: Atom, name: &'static str) -> VmResult<T> {
+        let v = self.get(key).ok_or(VmError::MissingField(name))?;
+        T::from_value(v).map_err(|e| VmError::field(name, e))
+    }
+
+    /// Converts a field that may be missing.
+    pub fn optional<T: FromValue>(&self, key: Atom, name: &'static str) -> VmResult<Option<T>> {
+        match self.get(key) {
+            Some(v) => T::from_value(v)
+                .map(Some)
+                .map_err(|e| VmError::field(name, e)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl VmError {
+    /// Wraps an error in the name of the field it happened in.
+    pub fn field(name: &'static str, error: VmError) -> VmError {
+        VmError::BadField {
+            field: name,
+            error: Box::new(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec, vec::Vec};
+    use paravita_derive::PvObject;
+
+    use crate::vm::{FromValue, IntoValue, PVObjectType, PVString, Value, VmError};
+
+    #[derive(Debug, PartialEq, PvObject)]
+    struct Endpoint {
+        host: String,
+        #[pv(rename = "port_number")]
+        port: u16,
+    }
+
+    #[derive(Debug, PartialEq, PvObject)]
+    struct Config {
+        name: String,
+        endpoints: Vec<Endpoint>,
+        retries: Option<u8>,
+        #[pv(default)]
+        verbose: bool,
+        mode: Mode,
+    }
+
+    #[derive(Debug, Clone, PartialEq, PvObject)]
+    #[pv(tag = "kind")]
+    enum Mode {
+        Idle,
+        Backoff {
+            base: u32,
+            max: u32,
+        },
+        #[pv(rename = "fixed")]
+        Fixed(u32),
+    }
+
+    #[test]
+    pub fn derive_round_trip() {
+        let config = Config {
+            name: String::from("svc"),
+            endpoints: vec![Endpoint {
+                host: String::from("::1"),
+                port: 80,
+            }],
+            retries: None,
+            verbose: true,
+            mode: Mode::Backoff {
+                base: 10,
+                max: 1000,
+            },
+        };
+        let v = config.into_value().unwrap();
+        let back = Config::from_value(&v).unwrap();
+        assert_eq!(
+            back.mode,
+            Mode::Backoff {
+                base: 10,
+                max: 1000
+            }
+        );
+        assert_eq!(back.endpoints[0].port, 80);
+
+        for mode in [Mode::Idle, Mode::Fixed(5)] {
+            let v = Mode::from_value(&mode.clone().into_value().unwrap()).unwrap();
+            assert_eq!(v, mode);
+        }
+
+        // Renamed keys are what end up in the map.
+        let v = Endpoint {
+            host: String::from("h"),
+            port: 1,
+        }
+        .into_value()
+        .unwrap();
+        let Value::Object(o) = &v else { panic!() };
+        let PVObjectType::Map(m) = &*o.get() else {
+            panic!()
+        };
+        assert!(m.contains_key(&PVString::Atom(atom!("port_number"))));
+    }
+
+    #[test]
+    pub fn derive_errors() {
+        let v = Endpoint {
+            host: String::from("h"),
+            port: 1,
+        }
+        .into_value()
+        .unwrap();
+
+        // Missing fields are named, missing optional/default fields aren't errors.
+        let err = Config::from_value(&(String::from("x"), 1u8).into_value().unwrap()).unwrap_err();
+        assert!(matches!(err, VmError::PopExpectedType { .. }));
+        let partial = crate::vm::RecordBuilder::new()
+            .unwrap()
+            .field(atom!("name"), "name", "svc")
+            .unwrap()
+            .field(atom!("endpoints"), "endpoints", vec![v])
+            .unwrap()
+            .finish();
+        assert!(matches!(
+            Config::from_value(&partial),
+            Err(VmError::MissingField("mode"))
+        ));
+
+        // Mistyped fields are named too, all the way down.
+        let bad = crate::vm::RecordBuilder::new()
+            .unwrap()
+            .field(atom!("name"), "name", "svc")
+            .unwrap()
+            .field(atom!("endpoints"), "endpoints", vec![(1u8, 2u8)])
+            .unwrap()
+            .field(atom!("mode"), "mode", Mode::Idle)
+            .unwrap()
+            .finish();
+        let err = Config::from_value(&bad).unwrap_err();
+        let VmError::BadField {
+            field: "endpoints",
+            error,
+        } = err
+        else {
+            panic!()
+        };
+        assert!(matches!(*error, VmError::PopExpectedType { .. }));
+    }
+}
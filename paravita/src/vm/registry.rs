@@ -0,0 +1,354 @@
+use core::{alloc::AllocError, cell::RefCell, fmt::Debug};
+
+use alloc::{
+    collections::BTreeSet,
+    rc::{Rc, Weak},
+    vec::Vec,
+};
+use fnv::FnvBuildHasher;
+use indexmap::IndexMap;
+
+use super::{
+    error::{VmError, VmResult},
+    Atom, Import, Module, Operation, PVObjectType, Process, Value,
+};
+
+/// A function implemented by the host, reachable from VM code through an import. Like a
+/// [SyscallHandler](super::SyscallHandler) it works on the calling process's stack: it should
+/// take `arity` arguments off the top and push its results.
+pub type NativeFn = Rc<dyn Fn(&mut Process) -> VmResult<()>>;
+
+/// A module as it sits in a running VM, validated and with its constants built.
+#[derive(Debug)]
+pub struct LoadedModule {
+    name: Atom,
+    /// Counts up from 1 each time a module of this name is loaded.
+    version: u32,
+    constants: Vec<Value>,
+    imports: Vec<Import>,
+    functions: Vec<LoadedFunction>,
+    exports: IndexMap<(Atom, u16), u32, FnvBuildHasher>,
+    globals: RefCell<Vec<Value>>,
+}
+
+#[derive(Debug)]
+pub(super) struct LoadedFunction {
+    pub(super) name: Atom,
+    pub(super) arity: u16,
+    pub(super) locals: u16,
+    pub(super) code: Rc<[Operation]>,
+}
+
+impl LoadedModule {
+    /// Validates a module and prepares it to run.
+    pub fn new(m: Module) -> VmResult<LoadedModule> {
+        m.validate()?;
+        let mut globals = Vec::new();
+        globals.try_reserve(m.globals as usize)?;
+        globals.resize(m.globals as usize, Value::Null);
+        let mut constants = Vec::new();
+        constants.try_reserve(m.constants.len())?;
+        for c in &m.constants {
+            constants.push(c.to_value()?);
+        }
+        let exports = m
+            .exports
+            .iter()
+            .map(|&i| {
+                let f = &m.functions[i as usize];
+                ((f.name, f.arity), i)
+            })
+            .collect();
+        let functions = m
+            .functions
+            .into_iter()
+            .map(|f| LoadedFunction {
+                name: f.name,
+                arity: f.arity,
+                locals: f.locals,
+                code: Rc::from(f.code),
+            })
+            .collect();
+        Ok(LoadedModule {
+            name: m.name,
+            version: 1,
+            constants,
+            imports: m.imports,
+            functions,
+            exports,
+            globals: RefCell::new(globals),
+        })
+    }
+
+    pub fn name(&self) -> Atom {
+        self.name
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The index of an exported function.
+    pub fn export(&self, function: Atom, arity: u16) -> Option<u32> {
+        self.exports.get(&(function, arity)).copied()
+    }
+
+    // Indices below come from validated code, so they're in range.
+
+    pub(super) fn function(&self, i: u32) -> &LoadedFunction {
+        &self.functions[i as usize]
+    }
+
+    pub(super) fn constant(&self, i: u32) -> &Value {
+        &self.constants[i as usize]
+    }
+
+    pub(super) fn import(&self, i: u32) -> Import {
+        self.imports[i as usize]
+    }
+
+    pub(super) fn global(&self, i: u16) -> Value {
+        self.globals.borrow()[i as usize].clone()
+    }
+
+    pub(super) fn set_global(&self, i: u16, v: Value) {
+        self.globals.borrow_mut()[i as usize] = v;
+    }
+}
+
+/// A function value, made by [Operation::MakeClosure]: a function of a loaded module along
+/// with the values it captured. It keeps the module version it was made in, so it runs the
+/// same code even after an upgrade, until that version is [purged](super::Vm::purge).
+#[derive(Clone)]
+pub struct Closure {
+    module: Rc<LoadedModule>,
+    function: u32,
+    captured: Vec<Value>,
+}
+
+impl Closure {
+    pub(super) fn new(module: Rc<LoadedModule>, function: u32, captured: Vec<Value>) -> Closure {
+        Closure {
+            module,
+            function,
+            captured,
+        }
+    }
+
+    pub fn module(&self) -> Atom {
+        self.module.name
+    }
+
+    pub fn name(&self) -> Atom {
+        self.module.function(self.function).name
+    }
+
+    /// How many arguments a call takes, not counting the captured values.
+    pub fn arity(&self) -> u16 {
+        self.module.function(self.function).arity - self.captured.len() as u16
+    }
+
+    pub fn captured(&self) -> &[Value] {
+        &self.captured
+    }
+
+    /// The function to call, once the arguments and then the captured values are on the stack.
+    pub(super) fn target(&self) -> (Rc<LoadedModule>, u32) {
+        (self.module.clone(), self.function)
+    }
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.module, &other.module)
+            && self.function == other.function
+            && self.captured == other.captured
+    }
+}
+
+impl Debug for Closure {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "#Fun<{}:{}/{}>",
+            <&str>::from(self.module()),
+            <&str>::from(self.name()),
+            self.arity()
+        )
+    }
+}
+
+/// Whether a closure of one of `versions` is among `values`, the objects they reach or the
+/// globals of `modules`. With `through_globals`, a closure reached also reaches the globals of
+/// its module, since calling it can load them.
+pub(super) fn reaches_closure<'a>(
+    values: impl IntoIterator<Item = Value>,
+    modules: impl IntoIterator<Item = &'a Rc<LoadedModule>>,
+    through_globals: bool,
+    versions: &[Rc<LoadedModule>],
+) -> bool {
+    let mut pending: Vec<Value> = values.into_iter().collect();
+    let mut scanned: Vec<*const LoadedModule> = Vec::new();
+    let mut scan = |m: &Rc<LoadedModule>, pending: &mut Vec<Value>| {
+        if !scanned.contains(&Rc::as_ptr(m)) {
+            scanned.push(Rc::as_ptr(m));
+            pending.extend(m.globals.borrow().iter().cloned());
+        }
+    };
+    for m in modules {
+        scan(m, &mut pending);
+    }
+    let mut seen = BTreeSet::new();
+    while let Some(v) = pending.pop() {
+        let Value::Object(o) = v else { continue };
+        if !seen.insert(o.as_ptr()) {
+            continue;
+        }
+        let o = o.get();
+        if let PVObjectType::Function(c) = &*o {
+            if versions.iter().any(|m| Rc::ptr_eq(m, &c.module)) {
+                return true;
+            }
+            if through_globals {
+                scan(&c.module, &mut pending);
+            }
+        }
+        o.for_each_child(|c| pending.push(c.clone()));
+    }
+    false
+}
+
+/// What a call resolved to.
+#[derive(Clone)]
+pub(crate) enum Callee {
+    Function(Rc<LoadedModule>, u32),
+    Native(NativeFn),
+}
+
+/// The modules and natives loaded into a VM, shared by all of its processes.
+///
+/// Modules can be upgraded while processes run them. Loading a module again makes the new
+/// version current: calls by import and from the host go to it from then on, while frames
+/// already running the old version carry on in it, calls within the module included, until
+/// they return. Closures made by the old version keep running it too. So an old version stays
+/// alive while frames run it or closures of it are reachable, from a process or from the
+/// globals of any module version, its own included. Frames returning let it go on their own;
+/// closures don't, so [Vm::soft_purge](super::Vm::soft_purge) and
+/// [Vm::purge](super::Vm::purge) reset the globals holding them.
+#[derive(Default)]
+pub struct Registry {
+    modules: IndexMap<Atom, Rc<LoadedModule>, FnvBuildHasher>,
+    /// Versions replaced by a newer load, for as long as something still uses them.
+    old: Vec<Weak<LoadedModule>>,
+    natives: IndexMap<Import, NativeFn, FnvBuildHasher>,
+    /// Whether loading runs [Module::validate_strict].
+    strict: bool,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Makes loading reject modules that might read a local before writing it.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Validates and loads a module. If a module of the same name is loaded, this is its next
+    /// version and the current one becomes old.
+    pub fn load(&mut self, m: Module) -> VmResult<Rc<LoadedModule>> {
+        if self.strict {
+            m.validate_strict()?;
+        }
+        let mut m = LoadedModule::new(m)?;
+        self.old.retain(|o| o.strong_count() > 0);
+        self.old.try_reserve(1)?;
+        self.modules
+            .try_reserve(1)
+            .map_err(|_| VmError::MemoryAllocFailed(AllocError))?;
+        if let Some(prev) = self.modules.get(&m.name) {
+            m.version = prev.version + 1;
+            self.old.push(Rc::downgrade(prev));
+        }
+        let m = Rc::try_new(m)?;
+        self.modules.insert(m.name, m.clone());
+        Ok(m)
+    }
+
+    /// The current version of a module.
+    pub fn module(&self, name: Atom) -> Option<&Rc<LoadedModule>> {
+        self.modules.get(&name)
+    }
+
+    /// The old versions of a module still in use, oldest first.
+    pub fn old_versions(&self, name: Atom) -> Vec<Rc<LoadedModule>> {
+        self.old
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|m| m.name == name)
+            .collect()
+    }
+
+    /// Every version but `versions`, current ones first, then old ones.
+    fn others<'a>(&'a self, versions: &'a [Rc<LoadedModule>]) -> impl Iterator<Item = Rc<LoadedModule>> + 'a {
+        self.modules
+            .values()
+            .cloned()
+            .chain(self.old.iter().filter_map(Weak::upgrade))
+            .filter(|m| !versions.iter().any(|v| Rc::ptr_eq(v, m)))
+    }
+
+    /// Whether the globals of other versions reach a closure of one of `versions`.
+    pub(super) fn globals_reach(&self, versions: &[Rc<LoadedModule>]) -> bool {
+        let others: Vec<_> = self.others(versions).collect();
+        reaches_closure(core::iter::empty(), &others, true, versions)
+    }
+
+    /// Lets `versions` go, once no process uses them: resets their own globals, and every global
+    /// of another version that reaches a closure of them, to null.
+    pub(super) fn release(&self, versions: &[Rc<LoadedModule>]) {
+        for m in versions {
+            m.globals.borrow_mut().fill(Value::Null);
+        }
+        for m in self.others(versions) {
+            let n = m.globals.borrow().len();
+            for i in 0..n {
+                let v = m.globals.borrow()[i].clone();
+                // Globals reached through other closures get reset on their own.
+                if reaches_closure([v], core::iter::empty(), false, versions) {
+                    m.globals.borrow_mut()[i] = Value::Null;
+                }
+            }
+        }
+    }
+
+    /// The names of the loaded modules, in load order.
+    pub fn modules(&self) -> impl Iterator<Item = Atom> + '_ {
+        self.modules.keys().copied()
+    }
+
+    /// Installs a native as `module:function/arity`, returning the one it replaced. Natives take
+    /// precedence over module functions of the same name.
+    pub fn install_native<F>(&mut self, module: Atom, function: Atom, arity: u16, f: F) -> Option<NativeFn>
+    where
+        F: Fn(&mut Process) -> VmResult<()> + 'static,
+    {
+        self.natives.insert(Import { module, function, arity }, Rc::new(f))
+    }
+
+    /// Finds what a call to `module:function/arity` runs.
+    pub(super) fn resolve(&self, i: Import) -> VmResult<Callee> {
+        if let Some(f) = self.natives.get(&i) {
+            return Ok(Callee::Native(f.clone()));
+        }
+        self.modules
+            .get(&i.module)
+            .and_then(|m| Some(Callee::Function(m.clone(), m.export(i.function, i.arity)?)))
+            .ok_or(VmError::UndefinedFunction {
+                module: i.module,
+                function: i.function,
+                arity: i.arity,
+            })
+    }
+}
@@ -0,0 +1,284 @@
+use core::net::Ipv6Addr;
+
+use alloc::vec::Vec;
+use rand::{seq::SliceRandom, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+use super::{
+    error::{VmError, VmFault},
+    statics, CallSite, Operation, PVObject, Process, RunStatus, Value, Vm,
+};
+
+/// How a process left a [Scheduler].
+#[derive(Debug)]
+pub struct Exit {
+    pub pid: Ipv6Addr,
+    /// `normal` if the process ran to the end, otherwise the value a handler would have caught.
+    pub reason: Value,
+    /// The fault that ended the process, None if it exited normally.
+    pub fault: Option<VmFault>,
+}
+
+impl Exit {
+    pub fn is_normal(&self) -> bool {
+        self.fault.is_none()
+    }
+}
+
+/// What a [Scheduler] reports as it runs.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// A process is about to run an op. Only reported when tracing.
+    Step {
+        pid: Ipv6Addr,
+        op: Operation,
+        /// The function the op is in, None for code run directly.
+        at: Option<CallSite>,
+    },
+    /// A process exited and was removed from the VM.
+    Exit(&'a Exit),
+}
+
+/// Runs the processes of a [Vm] in turns until all of them exit. Each turn runs a process for
+/// a budget of operations, see [Process::resume].
+///
+/// Processes take turns in spawn order unless the scheduler is seeded, then the order is
+/// shuffled each round. The same seed gives the same interleaving, so a run that went wrong
+/// can be repeated.
+pub struct Scheduler {
+    budget: usize,
+    heap_quota: Option<usize>,
+    trace: bool,
+    /// A fixed algorithm rather than `SmallRng`, which differs between platforms,
+    /// so a seed gives the same order on any host.
+    rng: Option<Xoshiro256PlusPlus>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    /// Operations per turn unless set otherwise.
+    pub const DEFAULT_BUDGET: usize = 4000;
+
+    pub fn new() -> Scheduler {
+        Scheduler {
+            budget: Scheduler::DEFAULT_BUDGET,
+            heap_quota: None,
+            trace: false,
+            rng: None,
+        }
+    }
+
+    /// Sets how many operations a process runs per turn.
+    pub fn budget(mut self, budget: usize) -> Scheduler {
+        self.budget = budget.max(1);
+        self
+    }
+
+    /// Kills a process with [VmError::HeapQuotaExceeded] when it holds more than `bytes` at the
+    /// end of a turn, as measured by [Process::heap_size]. Measuring walks everything the
+    /// process reaches, so each turn then costs time in proportion to its heap as well as its
+    /// budget.
+    pub fn heap_quota(mut self, bytes: usize) -> Scheduler {
+        self.heap_quota = Some(bytes);
+        self
+    }
+
+    /// Reports every operation as an [Event::Step]. Processes then run one operation at a time,
+    /// which is a lot slower.
+    pub fn trace(mut self, trace: bool) -> Scheduler {
+        self.trace = trace;
+        self
+    }
+
+    /// Shuffles the turn order with a generator seeded by `seed`.
+    pub fn seed(mut self, seed: u64) -> Scheduler {
+        self.rng = Some(Xoshiro256PlusPlus::seed_from_u64(seed));
+        self
+    }
+
+    /// Runs until every process in the VM has exited, removing each as it does. Processes
+    /// that aren't running anything exit normally on their first turn. Returns the exits in
+    /// the order they happened.
+    pub fn run(&mut self, vm: &mut Vm, mut on: impl FnMut(Event)) -> Vec<Exit> {
+        let mut exits = Vec::new();
+        loop {
+            let mut pids: Vec<_> = vm.pids().collect();
+            if pids.is_empty() {
+                return exits;
+            }
+            if let Some(rng) = &mut self.rng {
+                pids.shuffle(rng);
+            }
+            for pid in pids {
+                let Some(p) = vm.process_mut(pid) else {
+                    continue;
+                };
+                let exit = match self.turn(p, &mut on) {
+                    Ok(RunStatus::Suspended) => match self.check_quota(p) {
+                        Ok(()) => continue,
+                        Err(error) => Exit {
+                            pid,
+                            reason: error.to_value().unwrap_or(Value::Null),
+                            fault: Some(VmFault::host(pid, error)),
+                        },
+                    },
+                    Ok(RunStatus::Finished) => Exit {
+                        pid,
+                        reason: Value::Object(PVObject::from(statics::NORMAL)),
+                        fault: None,
+                    },
+                    Err(fault) => Exit {
+                        pid,
+                        reason: p.exit_reason().cloned().unwrap_or(Value::Null),
+                        fault: Some(fault),
+                    },
+                };
+                vm.kill(pid);
+                on(Event::Exit(&exit));
+                exits.push(exit);
+            }
+        }
+    }
+
+    fn turn(&self, p: &mut Process, on: &mut impl FnMut(Event)) -> Result<RunStatus, VmFault> {
+        if !self.trace {
+            return p.resume(self.budget);
+        }
+        for _ in 0..self.budget {
+            if let Some((op, at)) = p.next_op() {
+                on(Event::Step { pid: p.pid(), op, at });
+            }
+            if p.resume(1)? == RunStatus::Finished {
+                return Ok(RunStatus::Finished);
+            }
+        }
+        Ok(RunStatus::Suspended)
+    }
+
+    fn check_quota(&self, p: &Process) -> Result<(), VmError> {
+        let Some(quota) = self.heap_quota else {
+            return Ok(());
+        };
+        match p.heap_size() {
+            used if used > quota => Err(VmError::HeapQuotaExceeded { used, quota }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv6Addr;
+
+    use alloc::{vec, vec::Vec};
+
+    use super::{Event, Scheduler};
+    use crate::vm::{Function, Module, Operation, PrimOpKind, Vm, VmError};
+
+    /// spin/1 counts down from n to zero, then returns.
+    fn spinner() -> Module {
+        let mut m = Module::new(atom!("sched"));
+        let spin = m.function(Function {
+            name: atom!("spin"),
+            arity: 1,
+            locals: 0,
+            code: vec![
+                Operation::SubImm(PrimOpKind::U32, 1u32.into()),
+                Operation::Dup,
+                Operation::JumpIfZero(4),
+                Operation::Jump(0),
+                Operation::Drop,
+            ],
+        });
+        // hog/0 appends to an array forever.
+        let hog = m.function(Function {
+            name: atom!("hog"),
+            arity: 0,
+            locals: 0,
+            code: vec![
+                Operation::MakeArray,
+                Operation::PushImm(PrimOpKind::U64, 0u64.into()),
+                Operation::AddImm(PrimOpKind::U64, 1u64.into()),
+                Operation::TwoDup,
+                Operation::PushImm(PrimOpKind::U64, 1u64.into()),
+                Operation::SetArray,
+                Operation::Jump(2),
+            ],
+        });
+        m.export(spin);
+        m.export(hog);
+        m
+    }
+
+    #[test]
+    pub fn run_to_exit() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        vm.load(spinner()).unwrap();
+        let slow = vm.spawn().unwrap();
+        let fast = vm.spawn().unwrap();
+        vm.start(slow, atom!("sched"), atom!("spin"), (1000u32,)).unwrap();
+        vm.start(fast, atom!("sched"), atom!("spin"), (10u32,)).unwrap();
+        let mut steps = 0;
+        let exits = Scheduler::new().budget(50).run(&mut vm, |e| {
+            if let Event::Step { .. } = e {
+                steps += 1
+            }
+        });
+        assert_eq!(steps, 0);
+        let pids: Vec<_> = exits.iter().map(|e| e.pid).collect();
+        assert_eq!(pids, [fast, slow]);
+        assert!(exits.iter().all(|e| e.is_normal()));
+        assert_eq!(vm.pids().count(), 0);
+
+        // Tracing reports each op once.
+        let pid = vm.spawn().unwrap();
+        vm.start(pid, atom!("sched"), atom!("spin"), (3u32,)).unwrap();
+        let mut ops = Vec::new();
+        Scheduler::new().trace(true).run(&mut vm, |e| {
+            if let Event::Step { op, at, .. } = e {
+                assert_eq!(at.unwrap().function, atom!("spin"));
+                ops.push(op);
+            }
+        });
+        // Two rounds of the loop, a third that exits instead of jumping back, and the drop.
+        assert_eq!(ops.len(), 2 * 4 + 3 + 1);
+        assert_eq!(ops.last(), Some(&Operation::Drop));
+    }
+
+    #[test]
+    pub fn quota_and_seed() {
+        let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+        vm.load(spinner()).unwrap();
+        let hog = vm.spawn().unwrap();
+        vm.start(hog, atom!("sched"), atom!("hog"), ()).unwrap();
+        let exits = Scheduler::new().budget(100).heap_quota(4096).run(&mut vm, |_| {});
+        let fault = exits[0].fault.as_ref().unwrap();
+        assert!(matches!(
+            fault.error,
+            VmError::HeapQuotaExceeded { used, quota: 4096 } if used > 4096
+        ));
+
+        // The same seed gives the same order.
+        let order = |seed| {
+            let mut vm = Vm::new(Ipv6Addr::UNSPECIFIED);
+            vm.load(spinner()).unwrap();
+            let pids: Vec<_> = (0..8).map(|_| vm.spawn().unwrap()).collect();
+            for pid in &pids {
+                vm.start(*pid, atom!("sched"), atom!("spin"), (10u32,)).unwrap();
+            }
+            let exits = Scheduler::new().seed(seed).run(&mut vm, |_| {});
+            exits
+                .iter()
+                .map(|e| pids.iter().position(|p| *p == e.pid).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order(7), order(7));
+        // And on any host, the generator being portable.
+        assert_eq!(order(7), [3, 5, 7, 6, 2, 4, 1, 0]);
+    }
+}
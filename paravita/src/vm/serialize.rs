@@ -0,0 +1,454 @@
+//! Serde support, behind the `serde` feature.
+//!
+//! Values are written as an externally tagged enum so they survive a round trip through any
+//! self-describing format. Each int carries its kind, `{"u8": 5}`, atoms and strings are kept
+//! apart as `{"atom": "ok"}` and `{"str": "ok"}`, arrays are `{"array": [...]}` and maps are a
+//! list of pairs, `{"map": [[key, value], ...]}`, since keys aren't always strings. Null is just
+//! `"null"`. Binaries are a list of bytes, `{"binary": [1, 2]}`, and packed arrays their kind
+//! and elements, `{"packed": ["u16", [1, 2]]}`. Atoms on their own serialize as their name.
+
+use core::{cell::RefCell, fmt};
+
+use alloc::{string::String, vec::Vec};
+use fnv::FnvBuildHasher;
+use indexmap::IndexMap;
+use serde::{
+    de::{self, EnumAccess, VariantAccess, Visitor},
+    ser::{self, SerializeSeq},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::{map::check_key, Atom, Binary, Num, PVObject, Packed, PVObjectType, PVString, PrimOpKind, Value};
+
+const VARIANTS: &[&str] = &[
+    "null", "u8", "i8", "u16", "i16", "u32", "i32", "u64", "i64", "atom", "str", "array", "map",
+    "dict", "binary", "packed",
+];
+
+/// Objects currently being written, to catch cycles before they blow the stack.
+type Path = RefCell<Vec<*const ()>>;
+
+struct Tracked<'a, T: ?Sized> {
+    inner: &'a T,
+    path: &'a Path,
+}
+
+impl<'a, T: ?Sized> Tracked<'a, T> {
+    fn with<U: ?Sized>(&self, inner: &'a U) -> Tracked<'a, U> {
+        Tracked {
+            inner,
+            path: self.path,
+        }
+    }
+}
+
+fn variant_index(name: &str) -> u32 {
+    VARIANTS.iter().position(|v| *v == name).unwrap() as u32
+}
+
+impl Serialize for Tracked<'_, Value> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self.inner {
+            Value::Null => s.serialize_unit_variant("Value", 0, "null"),
+            Value::Int(_, _) => {
+                let (k, bits) = self.inner.int_bits().unwrap();
+                let (name, idx) = (k.name(), variant_index(k.name()));
+                match k {
+                    PrimOpKind::U8 => {
+                        s.serialize_newtype_variant("Value", idx, name, &u8::from_bits(bits))
+                    }
+                    PrimOpKind::I8 => {
+                        s.serialize_newtype_variant("Value", idx, name, &i8::from_bits(bits))
+                    }
+                    PrimOpKind::U16 => {
+                        s.serialize_newtype_variant("Value", idx, name, &u16::from_bits(bits))
+                    }
+                    PrimOpKind::I16 => {
+                        s.serialize_newtype_variant("Value", idx, name, &i16::from_bits(bits))
+                    }
+                    PrimOpKind::U32 => {
+                        s.serialize_newtype_variant("Value", idx, name, &u32::from_bits(bits))
+                    }
+                    PrimOpKind::I32 => {
+                        s.serialize_newtype_variant("Value", idx, name, &i32::from_bits(bits))
+                    }
+                    PrimOpKind::U64 => {
+                        s.serialize_newtype_variant("Value", idx, name, &u64::from_bits(bits))
+                    }
+                    PrimOpKind::I64 => {
+                        s.serialize_newtype_variant("Value", idx, name, &i64::from_bits(bits))
+                    }
+                }
+            }
+            Value::Object(o) => self.with(o).serialize(s),
+        }
+    }
+}
+
+impl Serialize for Tracked<'_, PVObject> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let ptr = self.inner.as_ptr();
+        if self.path.borrow().contains(&ptr) {
+            return Err(ser::Error::custom("can't serialize a cyclic value"));
+        }
+        self.path.borrow_mut().push(ptr);
+        let r = match &*self.inner.get() {
+            PVObjectType::String(PVString::Atom(a)) => {
+                s.serialize_newtype_variant("Value", 9, "atom", a)
+            }
+            PVObjectType::String(PVString::Str(st)) => {
+                s.serialize_newtype_variant("Value", 10, "str", st)
+            }
+            PVObjectType::Array(elems) => {
+                s.serialize_newtype_variant("Value", 11, "array", &self.with(elems.as_slice()))
+            }
+            PVObjectType::Map(m) => s.serialize_newtype_variant("Value", 12, "map", &self.with(m)),
+            PVObjectType::Dict(d) => s.serialize_newtype_variant("Value", 13, "dict", &self.with(d)),
+            PVObjectType::Binary(b) => {
+                let bytes = b.to_vec().map_err(ser::Error::custom)?;
+                s.serialize_newtype_variant("Value", 14, "binary", &bytes)
+            }
+            PVObjectType::Packed(p) => {
+                let ints: Vec<i128> = p.ints().collect();
+                s.serialize_newtype_variant("Value", 15, "packed", &(p.kind().name(), ints))
+            }
+            PVObjectType::UserData(_) => Err(ser::Error::custom("can't serialize userdata")),
+            PVObjectType::Function(_) => Err(ser::Error::custom("can't serialize a function")),
+        };
+        self.path.borrow_mut().pop();
+        r
+    }
+}
+
+impl Serialize for Tracked<'_, [Value]> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut seq = s.serialize_seq(Some(self.inner.len()))?;
+        for v in self.inner {
+            seq.serialize_element(&self.with(v))?;
+        }
+        seq.end()
+    }
+}
+
+impl Serialize for Tracked<'_, IndexMap<PVString, Value, FnvBuildHasher>> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut seq = s.serialize_seq(Some(self.inner.len()))?;
+        for (k, v) in self.inner {
+            seq.serialize_element(&(k, self.with(v)))?;
+        }
+        seq.end()
+    }
+}
+
+impl Serialize for Tracked<'_, IndexMap<Value, Value, FnvBuildHasher>> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut seq = s.serialize_seq(Some(self.inner.len()))?;
+        for (k, v) in self.inner {
+            seq.serialize_element(&(self.with(k), self.with(v)))?;
+        }
+        seq.end()
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let path = RefCell::new(Vec::new());
+        Tracked {
+            inner: self,
+            path: &path,
+        }
+        .serialize(s)
+    }
+}
+
+impl Serialize for PVObject {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let path = RefCell::new(Vec::new());
+        Tracked {
+            inner: self,
+            path: &path,
+        }
+        .serialize(s)
+    }
+}
+
+/// Written the same way as a string [Value], so either reads the other.
+impl Serialize for PVString {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            PVString::Atom(a) => s.serialize_newtype_variant("PVString", 9, "atom", a),
+            PVString::Str(st) => s.serialize_newtype_variant("PVString", 10, "str", st),
+        }
+    }
+}
+
+impl Serialize for Atom {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str((*self).into())
+    }
+}
+
+/// A variant name, or its index for formats that write those instead.
+struct Tag(usize);
+
+impl<'de> Deserialize<'de> for Tag {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct TagVisitor;
+
+        impl Visitor<'_> for TagVisitor {
+            type Value = Tag;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a value kind")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Tag, E> {
+                match usize::try_from(v) {
+                    Ok(i) if i < VARIANTS.len() => Ok(Tag(i)),
+                    _ => Err(E::invalid_value(de::Unexpected::Unsigned(v), &self)),
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Tag, E> {
+                VARIANTS
+                    .iter()
+                    .position(|n| *n == v)
+                    .map(Tag)
+                    .ok_or_else(|| E::unknown_variant(v, VARIANTS))
+            }
+        }
+
+        d.deserialize_identifier(TagVisitor)
+    }
+}
+
+fn object<E: de::Error>(r: super::VmResult<PVObject>) -> Result<Value, E> {
+    r.map(Value::Object).map_err(E::custom)
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a paravita value")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Value, A::Error> {
+        let (Tag(tag), v) = data.variant::<Tag>()?;
+        Ok(match VARIANTS[tag] {
+            "null" => {
+                v.unit_variant()?;
+                Value::Null
+            }
+            "u8" => Value::from(v.newtype_variant::<u8>()?, PrimOpKind::U8),
+            "i8" => Value::from(v.newtype_variant::<i8>()?, PrimOpKind::I8),
+            "u16" => Value::from(v.newtype_variant::<u16>()?, PrimOpKind::U16),
+            "i16" => Value::from(v.newtype_variant::<i16>()?, PrimOpKind::I16),
+            "u32" => Value::from(v.newtype_variant::<u32>()?, PrimOpKind::U32),
+            "i32" => Value::from(v.newtype_variant::<i32>()?, PrimOpKind::I32),
+            "u64" => Value::from(v.newtype_variant::<u64>()?, PrimOpKind::U64),
+            "i64" => Value::from(v.newtype_variant::<i64>()?, PrimOpKind::I64),
+            "atom" => object(PVObject::make_string(PVString::Atom(v.newtype_variant()?)))?,
+            "str" => object(PVObject::make_string(PVString::Str(v.newtype_variant()?)))?,
+            "array" => object(PVObject::make_array_from(v.newtype_variant()?))?,
+            "map" => {
+                let pairs: Vec<(PVString, Value)> = v.newtype_variant()?;
+                let obj = PVObject::make_map().map_err(de::Error::custom)?;
+                if let PVObjectType::Map(m) = &mut *obj.get_mut() {
+                    m.try_reserve(pairs.len())
+                        .map_err(|_| de::Error::custom("out of memory"))?;
+                    m.extend(pairs);
+                }
+                Value::Object(obj)
+            }
+            "dict" => {
+                let pairs: Vec<(Value, Value)> = v.newtype_variant()?;
+                for (k, _) in &pairs {
+                    check_key(k).map_err(de::Error::custom)?;
+                }
+                let obj = PVObject::make_dict().map_err(de::Error::custom)?;
+                if let PVObjectType::Dict(d) = &mut *obj.get_mut() {
+                    d.try_reserve(pairs.len())
+                        .map_err(|_| de::Error::custom("out of memory"))?;
+                    d.extend(pairs);
+                }
+                Value::Object(obj)
+            }
+            "binary" => {
+                let bytes: Vec<u8> = v.newtype_variant()?;
+                let b = Binary::from_bytes(&bytes).map_err(de::Error::custom)?;
+                object(PVObject::make_binary(b))?
+            }
+            _ => {
+                let (kind, ints): (String, Vec<i128>) = v.newtype_variant()?;
+                let kind = PrimOpKind::from_name(&kind)
+                    .ok_or_else(|| de::Error::custom("unknown int kind"))?;
+                let p = Packed::from_ints(kind, &ints).map_err(de::Error::custom)?;
+                object(PVObject::make_packed(p))?
+            }
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_enum("Value", VARIANTS, ValueVisitor)
+    }
+}
+
+/// Accepts anything that deserializes to an object, so not `null` or ints.
+impl<'de> Deserialize<'de> for PVObject {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        match Value::deserialize(d)? {
+            Value::Object(o) => Ok(o),
+            _ => Err(de::Error::custom("expected an object")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PVString {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct StringVisitor;
+
+        impl<'de> Visitor<'de> for StringVisitor {
+            type Value = PVString;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an atom or string")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<PVString, A::Error> {
+                let (Tag(tag), v) = data.variant::<Tag>()?;
+                match VARIANTS[tag] {
+                    "atom" => Ok(PVString::Atom(v.newtype_variant()?)),
+                    "str" => Ok(PVString::Str(v.newtype_variant::<String>()?)),
+                    other => Err(de::Error::unknown_variant(other, &["atom", "str"])),
+                }
+            }
+        }
+
+        d.deserialize_enum("PVString", &["atom", "str"], StringVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for Atom {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct AtomVisitor;
+
+        impl Visitor<'_> for AtomVisitor {
+            type Value = Atom;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an atom name")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Atom, E> {
+                Ok(Atom::from(v))
+            }
+        }
+
+        d.deserialize_str(AtomVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        string::{String, ToString},
+        vec,
+        vec::Vec,
+    };
+
+    use crate::vm::{
+        statics, Atom, Binary, FromValue, IntoValue, PVObject, PVObjectType, PVString, Packed,
+        PrimOpKind, Value,
+    };
+
+    #[test]
+    pub fn json_round_trip() {
+        let v = (1u8, -2i64, "three", vec![Some(statics::OK), None])
+            .into_value()
+            .unwrap();
+        let json = serde_json::to_string(&v).unwrap();
+        assert_eq!(
+            json,
+            r#"{"array":[{"u8":1},{"i64":-2},{"str":"three"},{"array":[{"atom":"ok"},"null"]}]}"#
+        );
+        let back: Value = serde_json::from_str(&json).unwrap();
+        // Kinds survive, so the typed conversion still fits exactly.
+        let Value::Object(o) = &back else { panic!() };
+        let PVObjectType::Array(elems) = &*o.get() else {
+            panic!()
+        };
+        assert_eq!(elems[0].kind(), crate::vm::ValueKind::Int);
+        assert!(matches!(
+            elems[1],
+            Value::Int(crate::vm::PrimOpKind::I64, _)
+        ));
+        assert_eq!(
+            <(u8, i64, String, Vec<Option<Atom>>)>::from_value(&back).unwrap(),
+            (1, -2, String::from("three"), vec![Some(statics::OK), None])
+        );
+
+        let mut m = alloc::collections::BTreeMap::new();
+        m.insert(statics::TRUE, 7u32);
+        let v = m.into_value().unwrap();
+        let json = serde_json::to_string(&v).unwrap();
+        assert_eq!(json, r#"{"map":[[{"atom":"true"},{"u32":7}]]}"#);
+        let back: PVObject = serde_json::from_str(&json).unwrap();
+        let PVObjectType::Map(m) = &*back.get() else {
+            panic!()
+        };
+        assert_eq!(
+            m.get(&PVString::Atom(statics::TRUE)).unwrap().as_i128(),
+            Some(7)
+        );
+
+        assert_eq!(serde_json::to_string(&statics::OK).unwrap(), r#""ok""#);
+        assert!(serde_json::from_str::<Value>(r#"{"u8":256}"#).is_err());
+        assert!(serde_json::from_str::<Value>(r#"{"f32":1}"#).is_err());
+
+        let p = Packed::from_ints(PrimOpKind::I16, &[-1, 2]).unwrap();
+        let v = Value::Object(PVObject::make_packed(p).unwrap());
+        let json = serde_json::to_string(&v).unwrap();
+        assert_eq!(json, r#"{"packed":["i16",[-1,2]]}"#);
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), v);
+        assert!(serde_json::from_str::<Value>(r#"{"packed":["u8",[256]]}"#).is_err());
+    }
+
+    #[test]
+    pub fn cbor_round_trip() {
+        let v = vec![u64::MAX].into_value().unwrap();
+        let mut buf = Vec::new();
+        ciborium::into_writer(&v, &mut buf).unwrap();
+        let back: Value = ciborium::from_reader(buf.as_slice()).unwrap();
+        assert_eq!(<Vec<u64>>::from_value(&back).unwrap(), vec![u64::MAX]);
+
+        let v = Value::Object(PVObject::make_binary(Binary::from_bytes(&[0, 255]).unwrap()).unwrap());
+        let mut buf = Vec::new();
+        ciborium::into_writer(&v, &mut buf).unwrap();
+        let back: Value = ciborium::from_reader(buf.as_slice()).unwrap();
+        assert_eq!(back, v);
+    }
+
+    #[test]
+    pub fn cycles() {
+        // Sharing isn't a cycle.
+        let shared = "x".into_value().unwrap();
+        let v = vec![shared.clone(), shared].into_value().unwrap();
+        assert!(serde_json::to_string(&v).is_ok());
+
+        let arr = PVObject::make_array().unwrap();
+        if let PVObjectType::Array(elems) = &mut *arr.get_mut() {
+            elems.push(Value::Object(arr.clone()));
+        }
+        let err = serde_json::to_string(&arr).unwrap_err();
+        assert!(err.to_string().contains("cyclic"));
+        // Break the cycle so the test doesn't leak.
+        if let PVObjectType::Array(elems) = &mut *arr.get_mut() {
+            elems.clear();
+        };
+    }
+}
@@ -0,0 +1,262 @@
+use core::{cell::RefCell, mem::ManuallyDrop, ptr};
+
+use alloc::boxed::Box;
+
+use super::{Num, PVObject, PVObjectType, PrimOpKind, Value};
+
+/// Low bits of a slot word. Objects are at least word aligned, so pointers leave them clear.
+const TAG_MASK: usize = 0b11;
+const TAG_OBJECT: usize = 0b00;
+const TAG_SMALL: usize = 0b01;
+const TAG_BOXED: usize = 0b10;
+const KIND_SHIFT: u32 = 2;
+const KIND_MASK: usize = 0b111;
+/// Small ints keep their payload above the tag and kind.
+const PAYLOAD_SHIFT: u32 = 8;
+
+/// One word on a process's stack. This is how the interpreter stores a [Value] internally.
+///
+/// The low two bits say what the word holds:
+/// - `00`: a [PVObject], as the pointer from `Rc::into_raw`. All zeroes is [Value::Null].
+/// - `01`: an int stored inline, with its kind in bits 2..5 and its value in the bits above 8.
+///   This is every 8, 16 and 32 bit int on 64 bit targets, and any 64 bit one that fits in 56.
+/// - `10`: a pointer to a [BoxedInt], for the ints that don't fit.
+///
+/// Words are kept as pointers so objects keep their provenance.
+pub(super) struct Slot {
+    word: *const (),
+}
+
+/// An int too wide to be stored inline.
+struct BoxedInt {
+    kind: PrimOpKind,
+    bits: u64,
+}
+
+fn kind_from_bits(k: usize) -> PrimOpKind {
+    match k {
+        0 => PrimOpKind::U8,
+        1 => PrimOpKind::I8,
+        2 => PrimOpKind::U16,
+        3 => PrimOpKind::I16,
+        4 => PrimOpKind::U32,
+        5 => PrimOpKind::I32,
+        6 => PrimOpKind::U64,
+        _ => PrimOpKind::I64,
+    }
+}
+
+impl Slot {
+    pub(super) const NULL: Slot = Slot { word: ptr::null() };
+
+    #[inline(always)]
+    fn tag(&self) -> usize {
+        self.word.addr() & TAG_MASK
+    }
+
+    /// Stores an int, `bits` holding it sign or zero extended to 64 bits.
+    #[inline(always)]
+    pub(super) fn int(kind: PrimOpKind, bits: u64) -> Slot {
+        let payload = (usize::BITS - PAYLOAD_SHIFT) as u64;
+        let fits = if kind.is_signed() {
+            let v = bits as i64;
+            v >= -(1i64 << (payload - 1)) && v < (1i64 << (payload - 1))
+        } else {
+            bits < (1u64 << payload)
+        };
+        if fits {
+            let word =
+                ((bits as usize) << PAYLOAD_SHIFT) | ((kind as usize) << KIND_SHIFT) | TAG_SMALL;
+            Slot {
+                word: ptr::without_provenance(word),
+            }
+        } else {
+            let boxed = Box::into_raw(Box::new(BoxedInt { kind, bits }));
+            Slot {
+                word: boxed.cast::<()>().map_addr(|a| a | TAG_BOXED),
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub(super) fn from_num<T: Num>(v: T) -> Slot {
+        Slot::int(T::KIND, v.to_bits())
+    }
+
+    /// Overwrites the slot with an int. A boxed int is updated in place, so wide ints that
+    /// stay wide don't allocate on every op.
+    #[inline(always)]
+    pub(super) fn set_num<T: Num>(&mut self, v: T) {
+        if self.tag() == TAG_BOXED {
+            // SAFETY: Boxed slots own their BoxedInt, and we have the slot mutably.
+            let b = unsafe { &mut *self.boxed_ptr() };
+            b.kind = T::KIND;
+            b.bits = v.to_bits();
+        } else {
+            *self = Slot::from_num(v);
+        }
+    }
+
+    fn object(o: PVObject) -> Slot {
+        Slot {
+            word: o.into_raw().cast::<()>(),
+        }
+    }
+
+    fn boxed_ptr(&self) -> *mut BoxedInt {
+        self.word
+            .map_addr(|a| a & !TAG_MASK)
+            .cast::<BoxedInt>()
+            .cast_mut()
+    }
+
+    fn boxed(&self) -> &BoxedInt {
+        // SAFETY: Boxed slots own a live BoxedInt.
+        unsafe { &*self.boxed_ptr() }
+    }
+
+    /// Borrows the object without touching its reference count.
+    fn borrow_object(&self) -> ManuallyDrop<PVObject> {
+        // SAFETY: Non-null object slots own one reference, which ManuallyDrop keeps from being
+        // released twice.
+        ManuallyDrop::new(unsafe { PVObject::from_raw(self.word.cast::<RefCell<PVObjectType>>()) })
+    }
+
+    /// The kind and 64 bit value of an int slot.
+    #[inline(always)]
+    pub(super) fn as_int(&self) -> Option<(PrimOpKind, u64)> {
+        match self.tag() {
+            TAG_SMALL => {
+                let kind = kind_from_bits((self.word.addr() >> KIND_SHIFT) & KIND_MASK);
+                let bits = if kind.is_signed() {
+                    ((self.word.addr() as isize) >> PAYLOAD_SHIFT) as i64 as u64
+                } else {
+                    (self.word.addr() >> PAYLOAD_SHIFT) as u64
+                };
+                Some((kind, bits))
+            }
+            TAG_BOXED => {
+                let b = self.boxed();
+                Some((b.kind, b.bits))
+            }
+            _ => None,
+        }
+    }
+
+    /// Reads an int of any kind as `T`, truncating like a cast.
+    #[inline(always)]
+    pub(super) fn as_num<T: Num>(&self) -> Option<T> {
+        self.as_int().map(|(_, bits)| T::from_bits(bits))
+    }
+
+    pub(super) fn to_value(&self) -> Value {
+        match self.tag() {
+            TAG_OBJECT if self.word.is_null() => Value::Null,
+            TAG_OBJECT => Value::Object(PVObject::clone(&self.borrow_object())),
+            _ => {
+                let (kind, bits) = self.as_int().unwrap();
+                Value::int(kind, bits)
+            }
+        }
+    }
+
+    pub(super) fn into_value(self) -> Value {
+        if self.tag() == TAG_OBJECT && !self.word.is_null() {
+            let this = ManuallyDrop::new(self);
+            return Value::Object(ManuallyDrop::into_inner(this.borrow_object()));
+        }
+        self.to_value()
+    }
+}
+
+impl From<Value> for Slot {
+    fn from(v: Value) -> Slot {
+        match v {
+            Value::Null => Slot::NULL,
+            Value::Int(_, _) => {
+                let (k, bits) = v.int_bits().unwrap();
+                Slot::int(k, bits)
+            }
+            Value::Object(o) => Slot::object(o),
+        }
+    }
+}
+
+impl Clone for Slot {
+    fn clone(&self) -> Slot {
+        match self.tag() {
+            TAG_BOXED => {
+                let b = self.boxed();
+                Slot::int(b.kind, b.bits)
+            }
+            TAG_OBJECT if !self.word.is_null() => {
+                Slot::object(PVObject::clone(&self.borrow_object()))
+            }
+            _ => Slot { word: self.word },
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        match self.tag() {
+            TAG_BOXED => {
+                // SAFETY: Boxed slots own their BoxedInt.
+                drop(unsafe { Box::from_raw(self.boxed_ptr()) });
+            }
+            TAG_OBJECT if !self.word.is_null() => {
+                drop(ManuallyDrop::into_inner(self.borrow_object()));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem::size_of;
+
+    use super::Slot;
+    use crate::vm::{statics, PVObject, PrimOpKind, Value};
+
+    #[test]
+    pub fn encoding() {
+        assert_eq!(size_of::<Slot>(), size_of::<usize>());
+
+        let cases: &[(PrimOpKind, i128)] = &[
+            (PrimOpKind::U8, 255),
+            (PrimOpKind::I8, -128),
+            (PrimOpKind::I32, i32::MIN as i128),
+            (PrimOpKind::U32, u32::MAX as i128),
+            (PrimOpKind::U64, u64::MAX as i128),
+            (PrimOpKind::I64, i64::MIN as i128),
+            (PrimOpKind::I64, -1),
+            (PrimOpKind::U64, 1 << 55),
+        ];
+        for &(kind, v) in cases {
+            let s = Slot::int(kind, v as u64);
+            let back = s.clone().into_value();
+            assert!(matches!(back, Value::Int(k, _) if k == kind));
+            assert_eq!(back.as_i128(), Some(v));
+            assert_eq!(Slot::from(back).as_int(), Some((kind, v as u64)));
+        }
+        assert_eq!(Slot::from_num(-2i16).as_num::<i64>(), Some(-2));
+        assert!(Slot::NULL.into_value().is_null());
+    }
+
+    #[test]
+    pub fn objects() {
+        let o = PVObject::from(statics::OK);
+        let s = Slot::from(Value::Object(o.clone()));
+        assert_eq!(s.as_int(), None);
+        let s2 = s.clone();
+        let Value::Object(back) = s.into_value() else {
+            panic!()
+        };
+        assert_eq!(back, o);
+        drop(s2);
+        drop(back);
+        // Only the original reference is left.
+        assert_eq!(o.ref_count(), 1);
+    }
+}
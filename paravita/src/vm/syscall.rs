@@ -0,0 +1,43 @@
+use alloc::boxed::Box;
+use fnv::FnvBuildHasher;
+use indexmap::IndexMap;
+
+use super::{error::VmResult, Process};
+
+/// A host service, run by [Operation::Syscall](super::Operation::Syscall). It works on the
+/// calling process's stack, and may raise an exception by returning an error.
+pub type SyscallHandler = Box<dyn Fn(&mut Process) -> VmResult<()>>;
+
+/// The host-installed handlers a process can reach through `Syscall(n)`. This is the only way
+/// for VM code to call out of the VM, so everything a process can do to the host is listed here.
+#[derive(Default)]
+pub struct SyscallTable {
+    handlers: IndexMap<u16, SyscallHandler, FnvBuildHasher>,
+}
+
+impl SyscallTable {
+    pub fn new() -> SyscallTable {
+        SyscallTable::default()
+    }
+
+    /// Installs a handler for syscall `n`, returning the handler it replaced.
+    pub fn install<F>(&mut self, n: u16, handler: F) -> Option<SyscallHandler>
+    where
+        F: Fn(&mut Process) -> VmResult<()> + 'static,
+    {
+        self.handlers.insert(n, Box::new(handler))
+    }
+
+    pub fn remove(&mut self, n: u16) -> Option<SyscallHandler> {
+        self.handlers.shift_remove(&n)
+    }
+
+    pub fn get(&self, n: u16) -> Option<&SyscallHandler> {
+        self.handlers.get(&n)
+    }
+
+    /// The installed syscall numbers, in installation order.
+    pub fn numbers(&self) -> impl Iterator<Item = u16> + '_ {
+        self.handlers.keys().copied()
+    }
+}
@@ -1,56 +1,150 @@
-use core::{fmt::Debug, mem::{size_of, align_of, MaybeUninit, discriminant}, any::TypeId};
+use core::{fmt::{Debug, Display}, mem::discriminant};
 
-use bytemuck::Pod;
 use bytemuck_derive::{Pod, Zeroable};
 
-use super::{PVObject, PrimOpKind};
+use super::{
+    error::{VmError, VmResult},
+    Num, PVObject, PVObjectType, PVString, PrimOpKind,
+};
 
-#[derive(Debug, Clone, PartialEq)]
+/// A value on the stack or in an object. Equality, ordering and hashing are structural, see
+/// [Value::term_cmp].
+#[derive(Debug, Clone, Default)]
 pub enum Value {
+    #[default]
     Null,
-    Int(PrimOpKind, Aligned), // Due to both BE and LE platforms, and non-64bit using this type, just upcasting to u64 isn't acceptable as it'd imply the need to extend or sign extend all numeric values.
+    Int(PrimOpKind, Aligned),
     Object(PVObject),
 }
 
+/// The bits of an int value, sign or zero extended to 64 bits as fits its kind. Being a plain
+/// u64 rather than a piece of memory, it reads the same on big and little endian targets.
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C, align(8))]
 #[derive(Pod, Zeroable)]
 pub struct Aligned(pub u64);
 
+impl Aligned {
+    pub fn to_le_bytes(self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    pub fn from_le_bytes(bytes: [u8; 8]) -> Aligned {
+        Aligned(u64::from_le_bytes(bytes))
+    }
+}
+
 impl Value {
-    pub fn reinterpret<T: Pod>(&self) -> T {
-        if (size_of::<T>() > size_of::<Aligned>()) || (align_of::<T>() > align_of::<Aligned>()) { panic!("Tried to read a value too large or improperly aligned to be contained in Value! {:?}", TypeId::of::<T>())}
+    /// An int of kind `k`. `bits` is truncated to the kind like an `as` cast.
+    pub fn int(k: PrimOpKind, bits: u64) -> Value {
+        Value::Int(k, Aligned(k.normalize(bits)))
+    }
+
+    /// The kind and bits of an int, or None for anything else.
+    pub fn int_bits(&self) -> Option<(PrimOpKind, u64)> {
         match self {
-            Value::Int(_, v) => {
-                let ptr = v as *const Aligned as *const T;
-                return unsafe { ptr.read() }
-            }
-            Value::Object(_) => T::zeroed(), // hbdxjwhsgcvyexwhuxwh no
-            Value::Null => T::zeroed(),
+            Value::Int(k, a) => Some((*k, k.normalize(a.0))),
+            _ => None,
         }
     }
 
-    pub fn from<T: Pod>(v: T, k: PrimOpKind) -> Value {
-        if (size_of::<T>() > size_of::<Aligned>()) || (align_of::<T>() > align_of::<Aligned>()) { panic!("Tried to create a value too large or improperly aligned to be contained in Value! {:?}", TypeId::of::<T>())}
-
-        let mut a = MaybeUninit::uninit();
-        let ptr = a.as_mut_ptr() as *mut T;
-        //SAFETY: Guaranteed to fit.
-        unsafe {
-            ptr.write(v);
+    /// Reads an int of any kind as `T`, truncating like an `as` cast.
+    pub fn reinterpret<T: Num>(&self) -> VmResult<T> {
+        match self.int_bits() {
+            Some((_, bits)) => Ok(T::from_bits(bits)),
+            None => Err(VmError::PopExpectedType {
+                expected: ValueKind::Int,
+                found: self.kind(),
+            }),
         }
-        //SAFETY: Minor UB, may leak parts of stack. But safe as far as I'm concerned :^)
-        return Value::Int(k, unsafe { a.assume_init() });
+    }
+
+    /// An int of kind `k` holding `v`, truncated to the kind like an `as` cast.
+    pub fn from<T: Num>(v: T, k: PrimOpKind) -> Value {
+        Value::int(k, v.to_bits())
     }
 
     pub fn is_null(&self) -> bool {
         discriminant(self) == discriminant(&Value::Null)
     }
+
+    /// Reads an int of any kind as an i128, which holds all of them exactly.
+    pub fn as_i128(&self) -> Option<i128> {
+        let (k, bits) = self.int_bits()?;
+        Some(if k.is_signed() {
+            bits as i64 as i128
+        } else {
+            bits as i128
+        })
+    }
+
+    /// What kind of value this is, for error reporting.
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            Value::Null => ValueKind::Null,
+            Value::Int(_, _) => ValueKind::Int,
+            Value::Object(o) => o.get().kind(),
+        }
+    }
+}
+
+/// The kinds of value a [Value] can hold, used to describe values in errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ValueKind {
+    Null,
+    Int,
+    Atom,
+    String,
+    Map,
+    Dict,
+    Array,
+    Binary,
+    Packed,
+    UserData,
+    Function,
+    /// Any of the object kinds, only ever expected, never found.
+    Object,
+}
+
+impl PVObjectType {
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            PVObjectType::Map(_) => ValueKind::Map,
+            PVObjectType::Dict(_) => ValueKind::Dict,
+            PVObjectType::Array(_) => ValueKind::Array,
+            PVObjectType::Binary(_) => ValueKind::Binary,
+            PVObjectType::Packed(_) => ValueKind::Packed,
+            PVObjectType::String(PVString::Atom(_)) => ValueKind::Atom,
+            PVObjectType::String(PVString::Str(_)) => ValueKind::String,
+            PVObjectType::UserData(_) => ValueKind::UserData,
+            PVObjectType::Function(_) => ValueKind::Function,
+        }
+    }
+}
+
+impl ValueKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValueKind::Null => "null",
+            ValueKind::Int => "int",
+            ValueKind::Atom => "atom",
+            ValueKind::String => "string",
+            ValueKind::Map => "map",
+            ValueKind::Dict => "dict",
+            ValueKind::Array => "array",
+            ValueKind::Binary => "binary",
+            ValueKind::Packed => "packed",
+            ValueKind::UserData => "userdata",
+            ValueKind::Function => "function",
+            ValueKind::Object => "object",
+        }
+    }
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        Value::Null
+impl Display for ValueKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.name())
     }
 }
 
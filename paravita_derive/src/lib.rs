@@ -0,0 +1,318 @@
+//! `#[derive(PvObject)]`, mapping Rust types to Paravita map objects.
+//!
+//! Structs become maps keyed by their field names as atoms. Enums become maps with a `tag`
+//! field naming the variant, plus the variant's fields. Tuple fields are keyed `0`, `1`, ...
+//!
+//! Attributes, all under `#[pv(...)]`:
+//! - `rename = "name"` on a field or variant, to use a different atom.
+//! - `default` on a field, to use `Default::default()` when it's missing.
+//! - `tag = "name"` on an enum, to use a different tag field.
+//!
+//! A variant field keyed like the tag is rejected, it would overwrite the tag.
+//!
+//! Fields of type `Option<T>` may be missing, and read back as `None`. Keys are interned
+//! once per type, on first use.
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, spanned::Spanned, Attribute, Data, DeriveInput, Fields,
+    GenericParam, LitStr, Type,
+};
+
+#[proc_macro_derive(PvObject, attributes(pv))]
+pub fn derive_pv_object(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(ts) => ts.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct PvAttrs {
+    rename: Option<String>,
+    default: bool,
+    tag: Option<String>,
+}
+
+fn pv_attrs(attrs: &[Attribute]) -> syn::Result<PvAttrs> {
+    let mut out = PvAttrs::default();
+    for attr in attrs.iter().filter(|a| a.path().is_ident("pv")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                out.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("default") {
+                out.default = true;
+            } else if meta.path.is_ident("tag") {
+                out.tag = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else {
+                return Err(
+                    meta.error("unknown pv attribute, expected `rename`, `default` or `tag`")
+                );
+            }
+            Ok(())
+        })?;
+    }
+    Ok(out)
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(p) => {
+            p.qself.is_none() && p.path.segments.last().is_some_and(|s| s.ident == "Option")
+        }
+        _ => false,
+    }
+}
+
+/// A field as it's stored in the map.
+struct Field {
+    /// How to refer to the field in Rust, `self.x` or a binding in a pattern.
+    binding: TokenStream,
+    /// Name used for the atom key.
+    key: String,
+    /// Name used in error messages.
+    label: String,
+    ty: Type,
+    default: bool,
+    span: Span,
+}
+
+/// The atoms a derive uses, interned once per type into a static table.
+#[derive(Default)]
+struct Keys {
+    names: Vec<String>,
+}
+
+impl Keys {
+    fn get(&mut self, name: &str) -> TokenStream {
+        let i = match self.names.iter().position(|n| n == name) {
+            Some(i) => i,
+            None => {
+                self.names.push(name.to_string());
+                self.names.len() - 1
+            }
+        };
+        quote! { __PV_KEYS[#i].get() }
+    }
+
+    fn table(&self) -> TokenStream {
+        let n = self.names.len();
+        let names = &self.names;
+        quote! {
+            static __PV_KEYS: [::paravita::vm::LazyAtom; #n] =
+                [#(::paravita::vm::LazyAtom::new(#names)),*];
+        }
+    }
+}
+
+fn fields_of(
+    fields: &Fields,
+    bind: impl Fn(usize, Option<&syn::Ident>) -> TokenStream,
+) -> syn::Result<Vec<Field>> {
+    let mut out = Vec::new();
+    for (i, f) in fields.iter().enumerate() {
+        let attrs = pv_attrs(&f.attrs)?;
+        if attrs.tag.is_some() {
+            return Err(syn::Error::new(f.span(), "`tag` only applies to enums"));
+        }
+        let name = match &f.ident {
+            Some(id) => id.to_string(),
+            None => i.to_string(),
+        };
+        out.push(Field {
+            binding: bind(i, f.ident.as_ref()),
+            key: attrs.rename.unwrap_or_else(|| name.clone()),
+            label: name,
+            ty: f.ty.clone(),
+            default: attrs.default,
+            span: f.span(),
+        });
+    }
+    Ok(out)
+}
+
+fn write_fields(fields: &[Field], keys: &mut Keys) -> TokenStream {
+    let writes = fields.iter().map(|f| {
+        let Field { binding, label, .. } = f;
+        let key = keys.get(&f.key);
+        quote! { .field(#key, #label, #binding)? }
+    });
+    quote! { #(#writes)* }
+}
+
+/// Reads each field into a local named after its binding.
+fn read_fields(fields: &[Field], reader: &TokenStream, keys: &mut Keys) -> Vec<TokenStream> {
+    fields
+        .iter()
+        .map(|f| {
+            let Field { label, ty, .. } = f;
+            let key = keys.get(&f.key);
+            if is_option(ty) {
+                quote! { #reader.optional::<#ty>(#key, #label)?.flatten() }
+            } else if f.default {
+                quote! { #reader.optional::<#ty>(#key, #label)?.unwrap_or_default() }
+            } else {
+                quote! { #reader.field::<#ty>(#key, #label)? }
+            }
+        })
+        .collect()
+}
+
+fn construct(path: TokenStream, fields: &Fields, values: Vec<TokenStream>) -> TokenStream {
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|f| f.ident.as_ref().unwrap());
+            quote! { #path { #(#names: #values),* } }
+        }
+        Fields::Unnamed(_) => quote! { #path ( #(#values),* ) },
+        Fields::Unit => quote! { #path },
+    }
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream> {
+    let top = pv_attrs(&input.attrs)?;
+    if top.rename.is_some() || top.default {
+        return Err(syn::Error::new(
+            input.span(),
+            "`rename` and `default` apply to fields and variants",
+        ));
+    }
+
+    let name = input.ident.clone();
+    let reader = quote! { __reader };
+    let mut keys = Keys::default();
+
+    let (into_body, from_body) = match &input.data {
+        Data::Struct(s) => {
+            if top.tag.is_some() {
+                return Err(syn::Error::new(input.span(), "`tag` only applies to enums"));
+            }
+            let fields = fields_of(&s.fields, |i, id| match id {
+                Some(id) => quote! { self.#id },
+                None => {
+                    let i = syn::Index::from(i);
+                    quote! { self.#i }
+                }
+            })?;
+            let writes = write_fields(&fields, &mut keys);
+            let reads = read_fields(&fields, &reader, &mut keys);
+            let built = construct(quote! { #name }, &s.fields, reads);
+            (
+                quote! {
+                    ::core::result::Result::Ok(::paravita::vm::RecordBuilder::new()? #writes .finish())
+                },
+                quote! {
+                    let #reader = ::paravita::vm::RecordReader::new(v)?;
+                    ::core::result::Result::Ok(#built)
+                },
+            )
+        }
+        Data::Enum(e) => {
+            let tag = top.tag.unwrap_or_else(|| "tag".to_string());
+            let tag_key = keys.get(&tag);
+            let mut into_arms = Vec::new();
+            let mut from_arms = Vec::new();
+            for variant in &e.variants {
+                let attrs = pv_attrs(&variant.attrs)?;
+                if attrs.default || attrs.tag.is_some() {
+                    return Err(syn::Error::new(
+                        variant.span(),
+                        "only `rename` applies to variants",
+                    ));
+                }
+                let vname = &variant.ident;
+                let vkey = attrs.rename.unwrap_or_else(|| vname.to_string());
+
+                let fields = fields_of(&variant.fields, |i, id| match id {
+                    Some(id) => quote! { #id },
+                    None => {
+                        let b = format_ident!("__f{}", i);
+                        quote! { #b }
+                    }
+                })?;
+                if let Some(f) = fields.iter().find(|f| f.key == tag) {
+                    return Err(syn::Error::new(
+                        f.span,
+                        format!("field key `{tag}` is the tag of this enum, rename the field or the tag"),
+                    ));
+                }
+                let bindings = fields.iter().map(|f| &f.binding);
+                let pattern = match &variant.fields {
+                    Fields::Named(_) => quote! { #name::#vname { #(#bindings),* } },
+                    Fields::Unnamed(_) => quote! { #name::#vname ( #(#bindings),* ) },
+                    Fields::Unit => quote! { #name::#vname },
+                };
+                let variant_key = keys.get(&vkey);
+                let writes = write_fields(&fields, &mut keys);
+                into_arms.push(quote! {
+                    #pattern => ::paravita::vm::RecordBuilder::new()?
+                        .field(#tag_key, #tag, #variant_key)?
+                        #writes
+                        .finish(),
+                });
+
+                let reads = read_fields(&fields, &reader, &mut keys);
+                let built = construct(quote! { #name::#vname }, &variant.fields, reads);
+                from_arms.push(quote! {
+                    if __tag == #variant_key {
+                        return ::core::result::Result::Ok(#built);
+                    }
+                });
+            }
+            (
+                quote! {
+                    ::core::result::Result::Ok(match self { #(#into_arms)* })
+                },
+                quote! {
+                    let #reader = ::paravita::vm::RecordReader::new(v)?;
+                    let __tag: ::paravita::vm::Atom = #reader.field(#tag_key, #tag)?;
+                    #(#from_arms)*
+                    ::core::result::Result::Err(::paravita::vm::VmError::UnknownVariant(__tag))
+                },
+            )
+        }
+        Data::Union(u) => {
+            return Err(syn::Error::new(
+                u.union_token.span(),
+                "PvObject can't be derived for unions",
+            ));
+        }
+    };
+
+    // Every type parameter has to convert as well.
+    let mut into_generics = input.generics.clone();
+    for p in into_generics.params.iter_mut() {
+        if let GenericParam::Type(t) = p {
+            t.bounds.push(parse_quote!(::paravita::vm::IntoValue));
+        }
+    }
+    for p in input.generics.params.iter_mut() {
+        if let GenericParam::Type(t) = p {
+            t.bounds.push(parse_quote!(::paravita::vm::FromValue));
+        }
+    }
+    let (into_impl, ty_generics, into_where) = into_generics.split_for_impl();
+    let (from_impl, _, from_where) = input.generics.split_for_impl();
+
+    let table = keys.table();
+
+    Ok(quote! {
+        const _: () = {
+            #table
+
+            impl #into_impl ::paravita::vm::IntoValue for #name #ty_generics #into_where {
+                fn into_value(self) -> ::paravita::vm::VmResult<::paravita::vm::Value> {
+                    #into_body
+                }
+            }
+
+            impl #from_impl ::paravita::vm::FromValue for #name #ty_generics #from_where {
+                fn from_value(v: &::paravita::vm::Value) -> ::paravita::vm::VmResult<Self> {
+                    #from_body
+                }
+            }
+        };
+    })
+}